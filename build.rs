@@ -32,6 +32,32 @@ fn main() -> Result<()> {
     // `memory.x` is changed.
     println!("cargo:rerun-if-changed=memory.x");
 
+    // USB device identity (src/usb/id.rs), from the environment
+    for var in &["USB_VID", "USB_PID", "USB_MANUFACTURER", "USB_PRODUCT"] {
+        println!("cargo:rerun-if-env-changed={}", var);
+    }
+    let mut f = File::create(out.join("usb_id.rs"))?;
+    writeln!(
+        f,
+        "pub const VID: u16 = {:#06x};",
+        hex_var("USB_VID", 0xc410)
+    )?;
+    writeln!(
+        f,
+        "pub const PID: u16 = {:#06x};",
+        hex_var("USB_PID", 0x0000)
+    )?;
+    writeln!(
+        f,
+        "pub const MANUFACTURER: &str = {:?};",
+        string_var("USB_MANUFACTURER", "Fake company")
+    )?;
+    writeln!(
+        f,
+        "pub const PRODUCT: &str = {:?};",
+        string_var("USB_PRODUCT", "mouse")
+    )?;
+
     // generate a sine table
     println!("cargo:rerun-if-changed=build.rs");
     let out_dir = env::var("OUT_DIR").unwrap();
@@ -39,7 +65,7 @@ fn main() -> Result<()> {
     let mut f = File::create(&dest_path).unwrap();
 
     const SINE_BUF_SIZE: usize = 65536;
-    write!(f, "const SINE_BUF_SIZE: usize = {};\n", SINE_BUF_SIZE)?;
+    writeln!(f, "const SINE_BUF_SIZE: usize = {};", SINE_BUF_SIZE)?;
     write!(f, "const SINE_BUF: [u8; SINE_BUF_SIZE] = [")?;
 
     for i in 0..SINE_BUF_SIZE {
@@ -48,7 +74,26 @@ fn main() -> Result<()> {
 
         write!(f, " {},", v)?;
     }
-    write!(f, "];\n")?;
+    writeln!(f, "];")?;
 
     Ok(())
 }
+
+// a 16-bit hex number (e.g., `c410` or `0xc410`) from the environment
+fn hex_var(var: &str, default: u16) -> u16 {
+    match env::var(var) {
+        Ok(v) => u16::from_str_radix(v.trim_start_matches("0x"), 16)
+            .unwrap_or_else(|_| panic!("{}={} is not a 16-bit hex number", var, v)),
+        Err(_) => default,
+    }
+}
+
+// a USB string descriptor from the environment
+fn string_var(var: &str, default: &str) -> String {
+    match env::var(var) {
+        // string descriptors hold at most 126 UTF-16 characters
+        Ok(v) if v.encode_utf16().count() > 126 => panic!("{} is too long", var),
+        Ok(v) => v,
+        Err(_) => default.to_string(),
+    }
+}
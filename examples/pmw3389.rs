@@ -6,7 +6,7 @@
 use embedded_hal::spi::MODE_3;
 use panic_rtt_target as _;
 
-use rtic::cyccnt::{Instant, U32Ext as _};
+use rtic::cyccnt::U32Ext as _;
 use stm32f4xx_hal::{
     dwt::Dwt,
     gpio::Speed,
@@ -22,8 +22,9 @@ use stm32f4xx_hal::{
 };
 
 use app::{
+    odometry::{Odometer, Velocity},
     pmw3389::{self, Register},
-    DwtDelay,
+    time, DwtDelay,
 };
 use rtt_target::{rprintln, rtt_init_print};
 
@@ -37,6 +38,7 @@ type PMW3389T = pmw3389::Pmw3389<
         ),
     >,
     PB4<Output<PushPull>>,
+    DwtDelay,
 >;
 
 #[rtic::app(device = stm32f4xx_hal::stm32, monotonic = rtic::cyccnt::CYCCNT, peripherals = true)]
@@ -92,6 +94,8 @@ const APP: () = {
 
         let mut delay = DwtDelay::new(&mut core.DWT, clocks);
         let mut pmw3389 = pmw3389::Pmw3389::new(spi, cs, delay).unwrap();
+        // the resolution the odometer converts by
+        pmw3389.set_cpi(CPI).unwrap();
 
         // set in burst mode
         pmw3389.write_register(Register::MotionBurst, 0x00);
@@ -109,17 +113,18 @@ const APP: () = {
     #[task(priority = 2, resources = [pmw3389], schedule = [poll], spawn = [trace])]
     fn poll(cx: poll::Context) {
         static mut COUNTER: u32 = 0;
-        static mut POS_X: i64 = 0;
+        static mut ODOMETER: Odometer = Odometer::new(CPI);
+
+        let (x, y) = cx.resources.pmw3389.read_status().unwrap();
+        ODOMETER.update(x, y, time::now());
 
         *COUNTER += 1;
         if *COUNTER == 1000 / RATIO {
-            cx.spawn.trace(*POS_X).unwrap();
+            let (x, _y) = ODOMETER.position_um();
+            cx.spawn.trace(x, ODOMETER.velocity()).unwrap();
             *COUNTER = 0;
         }
 
-        let (x, _y) = cx.resources.pmw3389.read_status().unwrap();
-        *POS_X += x as i64;
-
         // task should run each second N ms (16_000 cycles at 16MHz)
         cx.schedule
             .poll(cx.scheduled + (RATIO * 16_000).cycles())
@@ -127,15 +132,16 @@ const APP: () = {
     }
 
     #[task(priority = 1)]
-    fn trace(_cx: trace::Context, pos: i64) {
+    fn trace(_cx: trace::Context, pos_um: i64, velocity: Velocity) {
         static mut OLD_POS: i64 = 0;
         rprintln!(
-            "pos_x {:010}, diff {:010} @{:?}",
-            pos,
-            pos - *OLD_POS,
-            Instant::now()
+            "pos_x {:010} um, diff {:010} um, vel_x {:.1} mm/s @{}",
+            pos_um,
+            pos_um - *OLD_POS,
+            velocity.x,
+            velocity.at.as_cycles()
         );
-        *OLD_POS = pos;
+        *OLD_POS = pos_um;
     }
 
     #[idle]
@@ -152,3 +158,5 @@ const APP: () = {
 };
 
 const RATIO: u32 = 5;
+// resolution of the sensor, set in init
+const CPI: u16 = 16000;
@@ -1,5 +1,8 @@
 // cargo run --example rtt_rtic_usb_mouse --release
 //
+// Moves the cursor back and forth while blinking the LED, paused (LED off)
+// while the host suspends the bus.
+//
 // Notice, release build required
 
 #![no_std]
@@ -18,187 +21,14 @@ use stm32f4xx_hal::{
 use usb_device::bus;
 use usb_device::prelude::*;
 
-#[allow(unused)]
-pub mod hid {
-    use usb_device::class_prelude::*;
-    use usb_device::Result;
-
-    pub const USB_CLASS_HID: u8 = 0x03;
-
-    const USB_SUBCLASS_NONE: u8 = 0x00;
-    const USB_SUBCLASS_BOOT: u8 = 0x01;
-
-    const USB_INTERFACE_NONE: u8 = 0x00;
-    const USB_INTERFACE_KEYBOARD: u8 = 0x01;
-    const USB_INTERFACE_MOUSE: u8 = 0x02;
-
-    const REQ_GET_REPORT: u8 = 0x01;
-    const REQ_GET_IDLE: u8 = 0x02;
-    const REQ_GET_PROTOCOL: u8 = 0x03;
-    const REQ_SET_REPORT: u8 = 0x09;
-    const REQ_SET_IDLE: u8 = 0x0a;
-    const REQ_SET_PROTOCOL: u8 = 0x0b;
-
-    // https://docs.microsoft.com/en-us/windows-hardware/design/component-guidelines/mouse-collection-report-descriptor
-    const REPORT_DESCR: &[u8] = &[
-        0x05, 0x01, // USAGE_PAGE (Generic Desktop)
-        0x09, 0x02, // USAGE (Mouse)
-        0xa1, 0x01, // COLLECTION (Application)
-        0x09, 0x01, //   USAGE (Pointer)
-        0xa1, 0x00, //   COLLECTION (Physical)
-        0x05, 0x09, //     USAGE_PAGE (Button)
-        0x19, 0x01, //     USAGE_MINIMUM (Button 1)
-        0x29, 0x03, //     USAGE_MAXIMUM (Button 3)
-        0x15, 0x00, //     LOGICAL_MINIMUM (0)
-        0x25, 0x01, //     LOGICAL_MAXIMUM (1)
-        0x95, 0x03, //     REPORT_COUNT (3)
-        0x75, 0x01, //     REPORT_SIZE (1)
-        0x81, 0x02, //     INPUT (Data,Var,Abs)
-        0x95, 0x01, //     REPORT_COUNT (1)
-        0x75, 0x05, //     REPORT_SIZE (5)
-        0x81, 0x03, //     INPUT (Cnst,Var,Abs)
-        0x05, 0x01, //     USAGE_PAGE (Generic Desktop)
-        0x09, 0x30, //     USAGE (X)
-        0x09, 0x31, //     USAGE (Y)
-        0x15, 0x81, //     LOGICAL_MINIMUM (-127)
-        0x25, 0x7f, //     LOGICAL_MAXIMUM (127)
-        0x75, 0x08, //     REPORT_SIZE (8)
-        0x95, 0x02, //     REPORT_COUNT (2)
-        0x81, 0x06, //     INPUT (Data,Var,Rel)
-        0xc0, //   END_COLLECTION
-        0xc0, // END_COLLECTION
-    ];
-
-    pub fn report(x: i8, y: i8) -> [u8; 3] {
-        [
-            0x00,    // button: none
-            x as u8, // x-axis
-            y as u8, // y-axis
-        ]
-    }
-
-    pub struct HIDClass<'a, B: UsbBus> {
-        report_if: InterfaceNumber,
-        report_ep: EndpointIn<'a, B>,
-    }
-
-    impl<B: UsbBus> HIDClass<'_, B> {
-        /// Creates a new HIDClass with the provided UsbBus and max_packet_size in bytes. For
-        /// full-speed devices, max_packet_size has to be one of 8, 16, 32 or 64.
-        pub fn new(alloc: &UsbBusAllocator<B>) -> HIDClass<'_, B> {
-            HIDClass {
-                report_if: alloc.interface(),
-                report_ep: alloc.interrupt(8, 10),
-            }
-        }
-
-        pub fn write(&mut self, data: &[u8]) {
-            self.report_ep.write(data).ok();
-        }
-    }
-
-    impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
-        fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
-            writer.interface(
-                self.report_if,
-                USB_CLASS_HID,
-                USB_SUBCLASS_NONE,
-                USB_INTERFACE_MOUSE,
-            )?;
-
-            let descr_len: u16 = REPORT_DESCR.len() as u16;
-            writer.write(
-                0x21,
-                &[
-                    0x01,                   // bcdHID
-                    0x01,                   // bcdHID
-                    0x00,                   // bCountryCode
-                    0x01,                   // bNumDescriptors
-                    0x22,                   // bDescriptorType
-                    descr_len as u8,        // wDescriptorLength
-                    (descr_len >> 8) as u8, // wDescriptorLength
-                ],
-            )?;
-
-            writer.endpoint(&self.report_ep)?;
-
-            Ok(())
-        }
-
-        fn control_in(&mut self, xfer: ControlIn<B>) {
-            let req = xfer.request();
-
-            if req.request_type == control::RequestType::Standard {
-                match (req.recipient, req.request) {
-                    (control::Recipient::Interface, control::Request::GET_DESCRIPTOR) => {
-                        let (dtype, _index) = req.descriptor_type_index();
-                        if dtype == 0x21 {
-                            // HID descriptor
-                            cortex_m::asm::bkpt();
-                            let descr_len: u16 = REPORT_DESCR.len() as u16;
-
-                            // HID descriptor
-                            let descr = &[
-                                0x09,                   // length
-                                0x21,                   // descriptor type
-                                0x01,                   // bcdHID
-                                0x01,                   // bcdHID
-                                0x00,                   // bCountryCode
-                                0x01,                   // bNumDescriptors
-                                0x22,                   // bDescriptorType
-                                descr_len as u8,        // wDescriptorLength
-                                (descr_len >> 8) as u8, // wDescriptorLength
-                            ];
-
-                            xfer.accept_with(descr).ok();
-                            return;
-                        } else if dtype == 0x22 {
-                            // Report descriptor
-                            xfer.accept_with(REPORT_DESCR).ok();
-                            return;
-                        }
-                    }
-                    _ => {
-                        return;
-                    }
-                };
-            }
-
-            if !(req.request_type == control::RequestType::Class
-                && req.recipient == control::Recipient::Interface
-                && req.index == u8::from(self.report_if) as u16)
-            {
-                return;
-            }
-
-            match req.request {
-                REQ_GET_REPORT => {
-                    // USB host requests for report
-                    // I'm not sure what should we do here, so just send empty report
-                    xfer.accept_with(&report(0, 0)).ok();
-                }
-                _ => {
-                    xfer.reject().ok();
-                }
-            }
-        }
-
-        fn control_out(&mut self, xfer: ControlOut<B>) {
-            let req = xfer.request();
-
-            if !(req.request_type == control::RequestType::Class
-                && req.recipient == control::Recipient::Interface
-                && req.index == u8::from(self.report_if) as u16)
-            {
-                return;
-            }
-
-            xfer.reject().ok();
-        }
-    }
-}
-
-use hid::HIDClass;
+use app::{
+    power::UsbPower,
+    uid,
+    usb::{
+        hid::{HIDClass, USB_INTERFACE_MOUSE, USB_SUBCLASS_NONE},
+        id, mouse,
+    },
+};
 
 type LED = gpio::gpioa::PA5<gpio::Output<gpio::PushPull>>;
 
@@ -211,6 +41,7 @@ const APP: () = {
         led: LED,
 
         usb_dev: UsbDevice<'static, UsbBusType>,
+        power: UsbPower,
         hid: HIDClass<'static, UsbBusType>,
     }
 
@@ -218,6 +49,7 @@ const APP: () = {
     fn init(mut cx: init::Context) -> init::LateResources {
         static mut USB_BUS: Option<bus::UsbBusAllocator<UsbBusType>> = None;
         static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+        static mut SERIAL: [u8; id::SERIAL_SIZE] = [0; id::SERIAL_SIZE];
         cx.core.DCB.enable_trace();
         DWT::unlock();
         cx.core.DWT.enable_cycle_counter();
@@ -254,12 +86,17 @@ const APP: () = {
 
         *USB_BUS = Some(UsbBus::new(usb, EP_MEMORY));
 
-        let hid = HIDClass::new(USB_BUS.as_ref().unwrap());
+        let hid = HIDClass::new(
+            USB_BUS.as_ref().unwrap(),
+            mouse::REPORT_DESCR,
+            USB_SUBCLASS_NONE,
+            USB_INTERFACE_MOUSE,
+        );
 
-        let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(0xc410, 0x0000))
-            .manufacturer("Fake company")
-            .product("mouse")
-            .serial_number("TEST")
+        let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(id::VID, id::PID))
+            .manufacturer(id::MANUFACTURER)
+            .product(id::PRODUCT)
+            .serial_number(id::serial_number(&uid::read(), SERIAL))
             .device_class(0)
             .build();
 
@@ -270,6 +107,7 @@ const APP: () = {
             led,
 
             usb_dev,
+            power: UsbPower::new(),
             hid,
         }
     }
@@ -282,7 +120,7 @@ const APP: () = {
         }
     }
 
-    #[task(schedule = [on_tick], resources = [counter, led, hid])]
+    #[task(schedule = [on_tick], resources = [counter, led, hid, power])]
     fn on_tick(mut cx: on_tick::Context) {
         cx.schedule.on_tick(Instant::now() + PERIOD.cycles()).ok();
 
@@ -290,27 +128,38 @@ const APP: () = {
         let led = &mut cx.resources.led;
         let hid = &mut cx.resources.hid;
 
+        if cx.resources.power.is_suspended() {
+            led.set_low().ok();
+            return;
+        }
+
         const P: u8 = 2;
         *counter = (*counter + 1) % P;
 
         // move mouse cursor horizontally (x-axis) while blinking LED
         if *counter < P / 2 {
             led.set_high().ok();
-            hid.write(&hid::report(10, 0));
+            hid.write(&mouse::report(10, 0)).ok();
         } else {
             led.set_low().ok();
-            hid.write(&hid::report(-10, 0));
+            hid.write(&mouse::report(-10, 0)).ok();
         }
     }
 
-    #[task(binds=OTG_FS, resources = [counter, led, usb_dev, hid])]
+    #[task(binds=OTG_FS, resources = [counter, led, usb_dev, hid, power])]
     fn usb_fs(mut cx: usb_fs::Context) {
+        // the interrupt may be the resume (or a reset) of the suspended bus
+        cx.resources.power.interrupt();
         usb_poll(
             &mut cx.resources.counter,
             &mut cx.resources.led,
             &mut cx.resources.usb_dev,
             &mut cx.resources.hid,
         );
+        let usb_dev = &cx.resources.usb_dev;
+        cx.resources
+            .power
+            .update(usb_dev.state(), usb_dev.remote_wakeup_enabled());
     }
 
     extern "C" {
@@ -0,0 +1,772 @@
+// cargo run --example rtt_rtic_usb_pmw3389 --release
+//
+// USB mouse, motion from the PMW3389 sensor (wired as in examples/pmw3389.rs)
+//
+// The sensor is polled each ms, and the motion accumulated until the host
+// collects the next HID report (at the USB polling rate, 1 kHz). The next
+// report is written as soon as the last one is collected, so each USB frame
+// carries the motion accumulated since the previous one. Motion exceeding
+// the report range is carried over to the following reports. The motion
+// passes the filter (dropping motion while lifted or on a poor surface) and
+// the sensitivity and acceleration pipeline (`app::motion`) first, without
+// acceleration by default.
+//
+// The sensor resolution, lift-off distance, angle snapping, rest mode and
+// report rate are configured from the host through a vendor-defined HID
+// feature report, see `host/src/bin/mouse-config.rs`, and kept in flash
+// (sectors 6 and 7) over resets.
+//
+// Five buttons (left, right, middle, back and forward) are read from PC6 to
+// PC10, active low (closing to ground). The back and forward buttons can be
+// bound to key combinations, key sequences or media keys, sent as keyboard
+// and consumer control reports of the same HID interface (e.g., `bind back
+// paste` on the console).
+//
+// When the host suspends the bus (e.g., the PC sleeps), the sensor is put in
+// rest mode and the MCU clock lowered (`app::power`). If the host enabled
+// remote wakeup, the sensor is still polled (every 2 ms, the schedules below
+// are in cycles of the lowered clock), and moving the mouse or pressing a
+// button wakes the host.
+//
+// Besides the mouse, the device has a CDC-ACM serial port with a text console
+// (log output, sensor diagnostics and settings), e.g.:
+//
+// > screen /dev/ttyACM0
+//
+// Each sensor sample is streamed as a binary motion record (`app::trace`) on
+// RTT channel 1, and on the serial port after `trace on` on the console,
+// e.g., captured by `host/src/bin/trace-capture.rs`.
+//
+// The sensor task and the USB interrupt are profiled (`app::profile`), the
+// cycles spent are dumped every second on RTT channel 2, e.g., rendered by
+// `host/src/bin/profile-view.rs`.
+//
+// The latency (start after the scheduled time) and run time of the sensor
+// task, and of the other priority 1 tasks holding it up, are monitored
+// (`app::latency`), a summary with the deadline misses is printed on RTT
+// every 10 s, e.g., to check the 1 kHz polling under USB load.
+//
+// Notice, release build required
+
+#![no_std]
+#![no_main]
+
+use panic_rtt_target as _;
+
+use core::fmt::Write as _;
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::spi::MODE_3;
+use rtic::cyccnt::{Instant, U32Ext as _};
+use rtt_target::{rprintln, rtt_init, set_print_channel, UpChannel};
+use stm32f4xx_hal::{
+    gpio::{
+        gpiob::{PB10, PB4},
+        gpioc::{PC, PC2, PC3},
+        Alternate, Input, Output, PullUp, PushPull, Speed, AF5,
+    },
+    otg_fs::{UsbBus, UsbBusType, USB},
+    prelude::*,
+    spi::Spi,
+    stm32,
+};
+use usb_device::bus;
+use usb_device::device::UsbDeviceState;
+use usb_device::prelude::*;
+
+use app::{
+    console::{self, Command, LineBuffer},
+    flash::SettingsSectors,
+    latency::TaskMonitor,
+    motion::{Accumulator, Curve, Filter, FilterConfig, Pipeline},
+    pmw3389::{self, Register},
+    power::UsbPower,
+    profile,
+    settings::{self, Binding, Settings},
+    store::{self, Store},
+    time::{self, Duration},
+    trace::{self, Record},
+    uid,
+    usb::{
+        cdc::CdcAcmClass,
+        hid::{HIDClass, Protocol, USB_INTERFACE_MOUSE, USB_SUBCLASS_BOOT},
+        id,
+        keyboard::{self, Queue},
+        mouse::{self, MouseReport, SETTINGS_ID},
+    },
+    DwtDelay,
+};
+
+type PMW3389T = pmw3389::Pmw3389<
+    Spi<
+        stm32::SPI2,
+        (
+            PB10<Alternate<AF5>>,
+            PC2<Alternate<AF5>>,
+            PC3<Alternate<AF5>>,
+        ),
+    >,
+    PB4<Output<PushPull>>,
+    DwtDelay,
+>;
+
+// The periods are in cycles (RTIC cyccnt), and stretch 2x while the clocks
+// are lowered (`app::power`). The remote wakeup signalling is scheduled
+// after the clocks are restored.
+
+// sensor polling period, 1ms at 48MHz
+const PERIOD: u32 = 48_000;
+const PERIOD_US: u32 = 1_000;
+
+// profile dump period, 1s at 48MHz
+const PROFILE: u32 = 48_000_000;
+
+// latency report period, 10s at 48MHz
+const LATENCY: u32 = 480_000_000;
+
+// remote wakeup signalling (1 to 15 ms), 10ms at 48MHz
+const WAKEUP: u32 = 480_000;
+
+// ms a button must be stable to register a change
+const DEBOUNCE_MS: u8 = 5;
+
+// the state of the mouse, not yet reported to the host
+pub struct Mouse {
+    motion: Accumulator,
+    // mouse buttons pressed, and last reported
+    buttons: u8,
+    reported: u8,
+    // keyboard and consumer control reports of bound buttons
+    keys: Queue,
+}
+
+impl Mouse {
+    const fn new() -> Self {
+        Mouse {
+            motion: Accumulator::new(),
+            buttons: 0,
+            reported: 0,
+            keys: Queue::new(),
+        }
+    }
+
+    fn pending(&self) -> bool {
+        self.motion.pending() || self.buttons != self.reported || !self.keys.is_empty()
+    }
+}
+
+#[rtic::app(device = stm32f4xx_hal::stm32, peripherals = true, monotonic = rtic::cyccnt::CYCCNT)]
+const APP: () = {
+    struct Resources {
+        pmw3389: PMW3389T,
+        buttons: [PC<Input<PullUp>>; 5],
+        mouse: Mouse,
+        settings: Settings,
+        store: Store<SettingsSectors>,
+        // motion records, on RTT and (if on) the serial port
+        rtt_trace: UpChannel,
+        #[init(false)]
+        tracing: bool,
+        // profile dumps, on RTT
+        rtt_profile: UpChannel,
+        // the sensor task must complete within its period, and the other
+        // priority 1 tasks, delaying it, too
+        #[init(TaskMonitor::new("poll", PERIOD))]
+        poll_latency: TaskMonitor,
+        #[init(TaskMonitor::new("profile_dump", PERIOD))]
+        dump_latency: TaskMonitor,
+        #[init(TaskMonitor::new("latency_report", PERIOD))]
+        report_latency: TaskMonitor,
+        #[init(TaskMonitor::new("sensor_power", PERIOD))]
+        power_latency: TaskMonitor,
+
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        power: UsbPower,
+        hid: HIDClass<'static, UsbBusType>,
+        serial: CdcAcmClass<'static, UsbBusType>,
+    }
+
+    #[init(schedule = [poll, profile_dump, latency_report])]
+    fn init(cx: init::Context) -> init::LateResources {
+        static mut USB_BUS: Option<bus::UsbBusAllocator<UsbBusType>> = None;
+        static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+        static mut SERIAL: [u8; id::SERIAL_SIZE] = [0; id::SERIAL_SIZE];
+
+        let channels = rtt_init! {
+            up: {
+                0: {
+                    size: 1024
+                    name: "Terminal"
+                }
+                1: {
+                    size: 1024
+                    name: "Trace"
+                }
+                2: {
+                    size: 1024
+                    name: "Profile"
+                }
+            }
+        };
+        set_print_channel(channels.up.0);
+        rprintln!("init");
+
+        let mut core = cx.core;
+        let device = cx.device;
+
+        // Initialize (enable) the monotonic timer (CYCCNT)
+        core.DCB.enable_trace();
+        core.DWT.enable_cycle_counter();
+
+        // 48MHz required for the USB
+        let rcc = device.RCC.constrain();
+        let clocks = rcc.cfgr.sysclk(48.mhz()).pclk1(24.mhz()).freeze();
+
+        let gpioa = device.GPIOA.split();
+        let gpiob = device.GPIOB.split();
+        let gpioc = device.GPIOC.split();
+
+        // Configure SPI, see examples/pmw3389.rs for the wiring
+        let sck = gpiob.pb10.into_alternate_af5();
+        let miso = gpioc.pc2.into_alternate_af5();
+        let mosi = gpioc.pc3.into_alternate_af5();
+        let cs = gpiob.pb4.into_push_pull_output().set_speed(Speed::High);
+
+        // left, right, middle, back and forward, the bits of the button field
+        let buttons = [
+            gpioc.pc6.into_pull_up_input().downgrade(),
+            gpioc.pc7.into_pull_up_input().downgrade(),
+            gpioc.pc8.into_pull_up_input().downgrade(),
+            gpioc.pc9.into_pull_up_input().downgrade(),
+            gpioc.pc10.into_pull_up_input().downgrade(),
+        ];
+
+        let spi = Spi::spi2(
+            device.SPI2,
+            (sck, miso, mosi),
+            MODE_3,
+            stm32f4xx_hal::time::KiloHertz(2000).into(),
+            clocks,
+        );
+
+        let delay = DwtDelay::new(&mut core.DWT, clocks);
+        let mut pmw3389 = pmw3389::Pmw3389::new(spi, cs, delay).unwrap();
+
+        let mut store = Store::new(SettingsSectors::new(device.FLASH)).unwrap();
+        let settings = Settings::load(&mut store);
+        // the erase stalls the CPU for seconds, done before USB is enabled,
+        // the tasks only append
+        if let Err(e) = store.erase_spare() {
+            rprintln!("failed to erase the spare settings sector {:?}", e);
+        }
+        rprintln!("settings {:?}", settings);
+        pmw3389.apply(&settings).ok();
+
+        // set in burst mode
+        pmw3389.write_register(Register::MotionBurst, 0x00).ok();
+
+        // Pull the D+ pin down to send a RESET condition to the USB bus.
+        let mut usb_dp = gpioa.pa12.into_push_pull_output();
+        usb_dp.set_low().ok();
+        time::delay(Duration::from_millis(10));
+        let usb_dp = usb_dp.into_floating_input();
+
+        let usb_dm = gpioa.pa11;
+
+        let usb = USB {
+            usb_global: device.OTG_FS_GLOBAL,
+            usb_device: device.OTG_FS_DEVICE,
+            usb_pwrclk: device.OTG_FS_PWRCLK,
+            pin_dm: usb_dm.into_alternate_af10(),
+            pin_dp: usb_dp.into_alternate_af10(),
+        };
+
+        *USB_BUS = Some(UsbBus::new(usb, EP_MEMORY));
+
+        let mut hid = HIDClass::new(
+            USB_BUS.as_ref().unwrap(),
+            mouse::REPORT_DESCR,
+            USB_SUBCLASS_BOOT,
+            USB_INTERFACE_MOUSE,
+        );
+
+        hid.set_feature(&feature(&settings));
+
+        let serial = CdcAcmClass::new(USB_BUS.as_ref().unwrap());
+
+        let usb_dev = UsbDeviceBuilder::new(USB_BUS.as_ref().unwrap(), UsbVidPid(id::VID, id::PID))
+            .manufacturer(id::MANUFACTURER)
+            .product(id::PRODUCT)
+            .serial_number(id::serial_number(&uid::read(), SERIAL))
+            .composite_with_iads()
+            .supports_remote_wakeup(true)
+            .build();
+
+        cx.schedule.poll(cx.start + PERIOD.cycles()).ok();
+        cx.schedule.profile_dump(cx.start + PROFILE.cycles()).ok();
+        cx.schedule.latency_report(cx.start + LATENCY.cycles()).ok();
+
+        init::LateResources {
+            pmw3389,
+            buttons,
+            mouse: Mouse::new(),
+            settings,
+            store,
+            rtt_trace: channels.up.1,
+            rtt_profile: channels.up.2,
+            usb_dev,
+            power: UsbPower::new(),
+            hid,
+            serial,
+        }
+    }
+
+    #[idle]
+    fn idle(_cx: idle::Context) -> ! {
+        loop {
+            continue;
+        }
+    }
+
+    #[task(
+        priority = 1,
+        resources = [
+            pmw3389,
+            buttons,
+            mouse,
+            hid,
+            settings,
+            power,
+            rtt_trace,
+            tracing,
+            serial,
+            poll_latency
+        ],
+        schedule = [poll, end_wakeup]
+    )]
+    fn poll(mut cx: poll::Context) {
+        // ms since the last report
+        static mut ELAPSED: u8 = 0;
+        // debouncing, the last sample and for how long (ms) it is stable
+        static mut SAMPLE: u8 = 0;
+        static mut STABLE: u8 = 0;
+        // debounced buttons
+        static mut PRESSED: u8 = 0;
+        // the bindings of the back and forward buttons when pressed, so a
+        // binding changed while pressed is released as it was pressed
+        static mut HELD: [Binding; 2] = [Binding::Button, Binding::Button];
+        // lift and surface quality gating, calibrated by the settings
+        static mut FILTER: Filter = Filter::new(FilterConfig::DEFAULT);
+        // sensitivity and acceleration, no acceleration
+        static mut PIPELINE: Pipeline = Pipeline::new(Curve::NONE, 1.0, 16000);
+        // motion record timestamps, and records lost on RTT and the serial
+        // port
+        static mut TIME_US: u32 = 0;
+        static mut DROPPED: [bool; 2] = [false; 2];
+
+        let _run = cx.resources.poll_latency.start(cx.scheduled);
+        let _scope = profile::scope("poll");
+        cx.schedule.poll(cx.scheduled + PERIOD.cycles()).ok();
+        *ELAPSED = ELAPSED.saturating_add(1);
+        *TIME_US = TIME_US.wrapping_add(PERIOD_US);
+
+        // while suspended, the sensor is only read if it may wake the host
+        let (low_power, can_wakeup) = cx.resources.power.lock(|power| {
+            if power.wakeup_expired() {
+                rprintln!("remote wakeup, no resume");
+            }
+            (power.is_low_power(), power.can_wakeup())
+        });
+        if low_power && !can_wakeup {
+            return;
+        }
+
+        let pmw3389 = &mut cx.resources.pmw3389;
+        // a failed read skips the sample, the next poll is already scheduled
+        let burst = match profile::measure("read_burst", || pmw3389.read_burst()) {
+            Ok(burst) => burst,
+            Err(e) => {
+                rprintln!("read_burst failed {:?}", e);
+                return;
+            }
+        };
+        let (orientation, min_squal, max_shutter) = cx.resources.settings.lock(|settings| {
+            (
+                settings.orientation,
+                settings.lift_squal,
+                settings.lift_shutter,
+            )
+        });
+        // the lift calibration, a change resets the filter
+        let config = FilterConfig {
+            min_squal,
+            max_shutter,
+            ..FilterConfig::DEFAULT
+        };
+        if *FILTER.config() != config {
+            FILTER.set_config(config);
+        }
+        let (x, y) = FILTER.process(&burst, PERIOD_US);
+        let (x, y) = orientation.apply(x, y);
+
+        let mut record = Record::from_burst(*TIME_US, &burst);
+        if FILTER.is_gated() {
+            record.flags |= trace::flags::GATED;
+        }
+        let rtt_trace = &mut cx.resources.rtt_trace;
+        DROPPED[0] = !send_record(record, DROPPED[0], |bytes| {
+            rtt_trace.write(bytes) == bytes.len()
+        });
+        if cx.resources.tracing.lock(|tracing| *tracing) {
+            let serial = &mut cx.resources.serial;
+            DROPPED[1] = !send_record(record, DROPPED[1], |bytes| {
+                serial.lock(|serial| serial.space() >= bytes.len() && serial.write(bytes) > 0)
+            });
+        }
+
+        let mut sample = 0;
+        for (i, button) in cx.resources.buttons.iter().enumerate() {
+            if button.is_low().unwrap_or(false) {
+                sample |= 1 << i;
+            }
+        }
+        let mut changed = 0;
+        if sample != *SAMPLE {
+            *SAMPLE = sample;
+            *STABLE = 0;
+        } else if *STABLE < DEBOUNCE_MS {
+            *STABLE += 1;
+            if *STABLE == DEBOUNCE_MS {
+                changed = sample ^ *PRESSED;
+                *PRESSED = sample;
+            }
+        }
+
+        if low_power {
+            // motion or a click wakes the host
+            if (x != 0 || y != 0 || sample != 0) && cx.resources.power.lock(|power| power.wakeup())
+            {
+                rprintln!("remote wakeup");
+                cx.schedule
+                    .end_wakeup(Instant::now() + WAKEUP.cycles())
+                    .ok();
+            }
+            return;
+        }
+
+        let (polling_ms, cpi, bindings) = cx.resources.settings.lock(|settings| {
+            (
+                settings.polling_ms,
+                settings.cpi,
+                [settings.back, settings.forward],
+            )
+        });
+        PIPELINE.set_cpi(cpi);
+        let (x, y) = PIPELINE.process(x, y, PERIOD_US);
+        let pressed = *PRESSED;
+        let held = &mut *HELD;
+        let hid = &mut cx.resources.hid;
+        cx.resources.mouse.lock(|mouse| {
+            mouse.motion.add(x, y);
+
+            // the back (bit 3) and forward (bit 4) buttons, if bound to keys,
+            // are not reported as mouse buttons
+            for (i, (binding, held)) in bindings.iter().zip(held.iter_mut()).enumerate() {
+                let bit = 1 << (3 + i);
+                if changed & bit != 0 {
+                    if pressed & bit != 0 {
+                        *held = *binding;
+                        keyboard::press(held, &mut mouse.keys);
+                    } else {
+                        keyboard::release(held, &mut mouse.keys);
+                    }
+                }
+            }
+            let mut buttons = pressed;
+            for (i, held) in held.iter().enumerate() {
+                if *held != Binding::Button {
+                    buttons &= !(1 << (3 + i));
+                }
+            }
+            mouse.buttons = buttons;
+
+            hid.lock(|hid| {
+                // the first report after a pause (or at a reduced report rate),
+                // otherwise reports are sent as the host collects the previous
+                // ones
+                if !hid.is_busy() && mouse.pending() && *ELAPSED >= polling_ms {
+                    send_report(hid, mouse);
+                    *ELAPSED = 0;
+                } else {
+                    // repeat the (no motion) report if the host asked for it
+                    hid.tick(1).ok();
+                }
+            });
+        });
+    }
+
+    // the profile since the last dump, dropped if the host does not read
+    // the channel
+    #[task(priority = 1, resources = [rtt_profile, dump_latency], schedule = [profile_dump])]
+    fn profile_dump(cx: profile_dump::Context) {
+        let _run = cx.resources.dump_latency.start(cx.scheduled);
+        cx.schedule
+            .profile_dump(cx.scheduled + PROFILE.cycles())
+            .ok();
+        let rtt_profile = cx.resources.rtt_profile;
+        profile::dump(|bytes| rtt_profile.write(bytes) == bytes.len());
+    }
+
+    // the latency of the priority 1 tasks since the last report, the report
+    // itself including this run
+    #[task(
+        priority = 1,
+        resources = [poll_latency, dump_latency, report_latency, power_latency],
+        schedule = [latency_report]
+    )]
+    fn latency_report(cx: latency_report::Context) {
+        let frequency = time::cycle_frequency();
+        let report_latency = cx.resources.report_latency;
+        {
+            let _run = report_latency.start(cx.scheduled);
+            cx.schedule
+                .latency_report(cx.scheduled + LATENCY.cycles())
+                .ok();
+            for monitor in [
+                cx.resources.poll_latency,
+                cx.resources.dump_latency,
+                cx.resources.power_latency,
+            ]
+            .iter_mut()
+            {
+                rprintln!("{}", monitor.summary(frequency));
+                monitor.reset();
+            }
+        }
+        rprintln!("{}", report_latency.summary(frequency));
+        report_latency.reset();
+    }
+
+    #[task(priority = 1, resources = [power])]
+    fn end_wakeup(mut cx: end_wakeup::Context) {
+        cx.resources.power.lock(|power| power.end_wakeup());
+    }
+
+    // the sensor in rest mode while the bus is suspended, as set by the
+    // settings otherwise
+    #[task(priority = 1, capacity = 2, resources = [pmw3389, settings, power_latency])]
+    fn sensor_power(mut cx: sensor_power::Context, suspended: bool) {
+        let _run = cx.resources.power_latency.start(cx.scheduled);
+        let rest = suspended || cx.resources.settings.lock(|settings| settings.rest);
+        let pmw3389 = cx.resources.pmw3389;
+        pmw3389.set_rest(rest).ok();
+        // back to burst mode
+        pmw3389.write_register(Register::MotionBurst, 0x00).ok();
+    }
+
+    // applies new settings to the sensor, and saves them to flash
+    #[task(priority = 1, resources = [pmw3389, settings, store, serial])]
+    fn apply(mut cx: apply::Context) {
+        let settings = cx.resources.settings.lock(|settings| *settings);
+        rprintln!("apply {:?}", settings);
+        // appends a record, never erases (the spare sector is erased at
+        // start up), so USB is served while saving
+        let saved = settings.save(cx.resources.store);
+        if let Err(e) = saved {
+            rprintln!("failed to save settings {:?}", e);
+        }
+        cx.resources.serial.lock(|serial| match saved {
+            Ok(()) => write!(serial, "settings saved\r\n").ok(),
+            Err(store::Error::Full) => write!(
+                serial,
+                "settings not saved, the store is full until restart\r\n"
+            )
+            .ok(),
+            Err(e) => write!(serial, "failed to save settings {:?}\r\n", e).ok(),
+        });
+        cx.resources.pmw3389.apply(&settings).ok();
+        // keep the sensor in burst mode
+        cx.resources
+            .pmw3389
+            .write_register(Register::MotionBurst, 0x00)
+            .ok();
+    }
+
+    // sensor diagnostics, printed on the console
+    #[task(priority = 1, resources = [pmw3389, serial])]
+    fn diag(mut cx: diag::Context) {
+        let pmw3389 = cx.resources.pmw3389;
+        let product_id = pmw3389.product_id().unwrap_or(0);
+        let srom_id = pmw3389.read_register(Register::SROMId).unwrap_or(0);
+        let squal = pmw3389.read_register(Register::SQUAL).unwrap_or(0);
+        let shutter_lower = pmw3389.read_register(Register::ShutterLower).unwrap_or(0);
+        let shutter_upper = pmw3389.read_register(Register::ShutterUpper).unwrap_or(0);
+        // back to burst mode
+        pmw3389.write_register(Register::MotionBurst, 0x00).ok();
+
+        cx.resources.serial.lock(|serial| {
+            write!(
+                serial,
+                "product id {:#04x}, srom id {:#04x}, squal {}, shutter {}\r\n> ",
+                product_id,
+                srom_id,
+                squal,
+                u16::from_le_bytes([shutter_lower, shutter_upper])
+            )
+            .ok();
+        });
+    }
+
+    #[task(
+        binds = OTG_FS,
+        priority = 2,
+        resources = [usb_dev, power, hid, serial, mouse, settings, tracing],
+        spawn = [apply, diag, sensor_power]
+    )]
+    fn usb_fs(cx: usb_fs::Context) {
+        static mut LINE: LineBuffer = LineBuffer::new();
+
+        let _scope = profile::scope("usb_fs");
+        let usb_dev = cx.resources.usb_dev;
+        let hid = cx.resources.hid;
+        let serial = cx.resources.serial;
+        let power = cx.resources.power;
+        // the interrupt may be the resume (or a reset) of the suspended bus
+        power.interrupt();
+        usb_dev.poll(&mut [&mut *hid, &mut *serial]);
+
+        if let Some(suspended) = power.update(usb_dev.state(), usb_dev.remote_wakeup_enabled()) {
+            rprintln!("{}", if suspended { "suspend" } else { "resume" });
+            cx.spawn.sensor_power(suspended).ok();
+        }
+        if usb_dev.state() == UsbDeviceState::Suspend {
+            return;
+        }
+
+        // queue the motion since the last report, for the next frame
+        if hid.collected() && cx.resources.settings.polling_ms == 1 && cx.resources.mouse.pending()
+        {
+            send_report(hid, cx.resources.mouse);
+        }
+
+        // settings written by the host (SET_FEATURE), after the report ID
+        let mut buf = [0; settings::SIZE + 1];
+        if hid.read_feature(&mut buf) == buf.len() && buf[0] == SETTINGS_ID {
+            match Settings::from_bytes(&buf[1..]) {
+                Some(settings) => {
+                    *cx.resources.settings = settings;
+                    cx.spawn.apply().ok();
+                }
+                None => rprintln!("invalid settings {:02x?}", buf),
+            }
+            // the settings in effect, read back by GET_FEATURE
+            hid.set_feature(&feature(cx.resources.settings));
+        }
+
+        // console input, echoed back
+        let mut buf = [0; 64];
+        let n = serial.read(&mut buf).unwrap_or(0);
+        for c in &buf[..n] {
+            match *c {
+                b'\r' | b'\n' => serial.write(b"\r\n"),
+                c => serial.write(&[c]),
+            };
+
+            let line = match LINE.push(*c) {
+                Some(line) => line,
+                None => continue,
+            };
+            match console::parse(line) {
+                Ok(None) => {}
+                Ok(Some(Command::Help)) => {
+                    for l in console::HELP.lines() {
+                        write!(serial, "{}\r\n", l).ok();
+                    }
+                }
+                Ok(Some(Command::Show)) => {
+                    write!(serial, "{:?}\r\n", cx.resources.settings).ok();
+                }
+                Ok(Some(Command::Diag)) => {
+                    // the prompt is written by the diag task
+                    cx.spawn.diag().ok();
+                    continue;
+                }
+                Ok(Some(Command::Trace(on))) => {
+                    *cx.resources.tracing = on;
+                }
+                Ok(Some(Command::Set(setting))) => match setting.apply(cx.resources.settings) {
+                    Some(settings) => {
+                        *cx.resources.settings = settings;
+                        hid.set_feature(&feature(&settings));
+                        cx.spawn.apply().ok();
+                    }
+                    None => {
+                        write!(serial, "out of range\r\n").ok();
+                    }
+                },
+                Err(e) => {
+                    write!(serial, "{}\r\n", e).ok();
+                }
+            }
+            serial.write(b"> ");
+        }
+    }
+
+    extern "C" {
+        fn EXTI0();
+        fn EXTI1();
+        fn EXTI2();
+    }
+};
+
+// the settings feature report, prefixed by the report ID
+fn feature(settings: &Settings) -> [u8; settings::SIZE + 1] {
+    let mut report = [SETTINGS_ID; settings::SIZE + 1];
+    report[1..].copy_from_slice(&settings.to_bytes());
+    report
+}
+
+// sends a motion record, flagged if the last was lost, true if sent
+fn send_record(mut record: Record, dropped: bool, send: impl FnOnce(&[u8]) -> bool) -> bool {
+    if dropped {
+        record.flags |= trace::flags::DROPPED;
+    }
+    send(&record.to_bytes())
+}
+
+fn send_report<B: bus::UsbBus>(hid: &mut HIDClass<'static, B>, mouse: &mut Mouse) {
+    let protocol = hid.protocol();
+    let mut buf = [0; mouse::REPORT_SIZE];
+
+    // key reports first, in order, the boot protocol has no room for them
+    if protocol == Protocol::Boot {
+        mouse.keys.clear();
+    }
+    if let Some(report) = mouse.keys.peek() {
+        // kept as the current keyboard (or consumer) report, the mouse
+        // report is kept apart
+        if hid.write(report).is_ok() {
+            mouse.keys.pop();
+        }
+        return;
+    }
+
+    // the boot protocol (BIOS/UEFI) only has room for 8-bit motion
+    let motion = &mut mouse.motion;
+    let (x, y) = match protocol {
+        Protocol::Boot => {
+            let (x, y) = motion.peek_i8();
+            (x as i16, y as i16)
+        }
+        Protocol::Report => motion.peek_i16(),
+    };
+
+    let report = MouseReport::new().buttons(mouse.buttons).motion(x, y);
+    // the report is only accepted once the previous one is collected
+    if hid.write(report.serialize(protocol, &mut buf)).is_ok() {
+        motion.consume(x, y);
+        mouse.reported = mouse.buttons;
+        // the current state, buttons but no motion
+        hid.set_report(report.motion(0, 0).serialize(protocol, &mut buf));
+    }
+}
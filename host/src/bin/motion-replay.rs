@@ -0,0 +1,224 @@
+//! Replays recorded motion through the motion filter and pipeline (`app::motion`)
+//!
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin pmw3389 -- loopback -n 1000 > trace.csv
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin motion-replay -- --curve linear:0.002,50,4 < trace.csv
+//!
+//! Reads the CSV of the `pmw3389` tool (time_us,dx,dy,squal,lifted,shutter,
+//! or only time_us,dx,dy for a sensor on a good surface) from stdin, or a
+//! binary trace of the mouse (`--trace`, see `trace-capture`), and writes the speed and the pointer motion of each sample to stdout as CSV,
+//! and the totals to stderr.
+use std::env;
+use std::fs;
+use std::io::{self, BufRead};
+use std::iter;
+use std::process;
+
+use app::motion::{Curve, Filter, FilterConfig, Lut, Pipeline, Smoothing};
+use app::pmw3389::Burst;
+use app::trace::Replay;
+
+const USAGE: &str = "\
+usage: motion-replay [options] < trace.csv
+
+options:
+  --trace file  read a binary trace (trace-capture) instead of CSV
+  --cpi n       resolution of the recorded sensor (default 16000)
+  --sens f      sensitivity, a multiplier (default 1)
+  --curve curve acceleration curve (default none), speeds in mm/s:
+                none
+                linear:accel,offset,cap
+                power:accel,exponent,offset,cap
+                lut:speed:gain,speed:gain,...
+  --min-squal n   drop motion below this surface quality (default 16)
+  --max-shutter n drop motion above this shutter, 0 disables (default 0)
+  --settle ms     drop motion this long after a lift (default 10)
+  --jitter n      hold back motion from rest up to n counts (default 0)
+  --smooth s      smoothing of small movements (default none):
+                  none
+                  lowpass:cutoff_hz
+                  euro:min_cutoff_hz,beta,d_cutoff_hz";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+fn numbers(s: &str, sep: char) -> Option<Vec<f32>> {
+    s.split(sep).map(|n| n.parse().ok()).collect()
+}
+
+fn curve(s: &str) -> Option<Curve> {
+    let (kind, params) = match s.find(':') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    match kind {
+        "none" => Some(Curve::NONE),
+        "linear" => match numbers(params, ',')?.as_slice() {
+            [accel, offset, cap] => Some(Curve::Linear {
+                accel: *accel,
+                offset: *offset,
+                cap: *cap,
+            }),
+            _ => None,
+        },
+        "power" => match numbers(params, ',')?.as_slice() {
+            [accel, exponent, offset, cap] => Some(Curve::Power {
+                accel: *accel,
+                exponent: *exponent,
+                offset: *offset,
+                cap: *cap,
+            }),
+            _ => None,
+        },
+        "lut" => {
+            let mut points = Vec::new();
+            for point in params.split(',') {
+                match numbers(point, ':')?.as_slice() {
+                    [speed, gain] => points.push((*speed, *gain)),
+                    _ => return None,
+                }
+            }
+            Lut::new(&points).map(Curve::Table)
+        }
+        _ => None,
+    }
+}
+
+fn smoothing(s: &str) -> Option<Smoothing> {
+    let (kind, params) = match s.find(':') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    match (kind, numbers(params, ',').as_deref()) {
+        ("none", _) => Some(Smoothing::None),
+        ("lowpass", Some([cutoff])) => Some(Smoothing::LowPass { cutoff: *cutoff }),
+        ("euro", Some([min_cutoff, beta, d_cutoff])) => Some(Smoothing::OneEuro {
+            min_cutoff: *min_cutoff,
+            beta: *beta,
+            d_cutoff: *d_cutoff,
+        }),
+        _ => None,
+    }
+}
+
+fn arg<T: std::str::FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut cpi = 16000;
+    let mut sensitivity = 1.0;
+    let mut accel = Curve::NONE;
+    let mut config = FilterConfig::DEFAULT;
+    let mut trace = None;
+
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--trace" => trace = Some(args.next().unwrap_or_else(|| usage())),
+            "--cpi" => cpi = arg(args.next()),
+            "--sens" => sensitivity = arg(args.next()),
+            "--curve" => {
+                accel = args
+                    .next()
+                    .and_then(|s| curve(&s))
+                    .unwrap_or_else(|| usage())
+            }
+            "--min-squal" => config.min_squal = arg(args.next()),
+            "--max-shutter" => config.max_shutter = arg(args.next()),
+            "--settle" => config.settle_us = arg::<u32>(args.next()) * 1000,
+            "--jitter" => config.jitter = arg(args.next()),
+            "--smooth" => {
+                config.smoothing = args
+                    .next()
+                    .and_then(|s| smoothing(&s))
+                    .unwrap_or_else(|| usage())
+            }
+            _ => usage(),
+        }
+    }
+
+    let mut filter = Filter::new(config);
+    let mut pipeline = Pipeline::new(accel, sensitivity, cpi);
+    let mut last_us = None;
+    let (mut input, mut output) = ((0i64, 0i64), (0i64, 0i64));
+    let mut gated = 0;
+
+    let data;
+    let samples: Box<dyn Iterator<Item = (u64, Burst)>> = match trace {
+        Some(path) => {
+            data = fs::read(&path).unwrap_or_else(|e| {
+                eprintln!("failed to read {}: {}", path, e);
+                process::exit(1)
+            });
+            // timestamps wrap, unwrapped from the first record
+            let mut replay = Replay::new(&data);
+            let mut time_us = 0;
+            Box::new(iter::from_fn(move || {
+                let (burst, dt_us) = replay.next_burst(0)?;
+                time_us += dt_us as u64;
+                Some((time_us, burst))
+            }))
+        }
+        None => Box::new(io::stdin().lock().lines().filter_map(|line| {
+            let line = line.expect("failed to read stdin");
+            let fields: Vec<&str> = line.split(',').collect();
+            // none for the header
+            parse(&fields)
+        })),
+    };
+
+    println!("time_us,dx,dy,gated,speed_mm_s,x,y");
+    for (time_us, burst) in samples {
+        // the first sample, assume the default 1 ms period
+        let dt_us = last_us.map_or(1000, |last| time_us.saturating_sub(last)) as u32;
+        last_us = Some(time_us);
+
+        let (dx, dy) = filter.process(&burst, dt_us);
+        let speed = pipeline.speed(dx, dy, dt_us);
+        let (x, y) = pipeline.process(dx, dy, dt_us);
+        println!(
+            "{},{},{},{},{:.1},{},{}",
+            time_us,
+            burst.dx,
+            burst.dy,
+            filter.is_gated() as u8,
+            speed,
+            x,
+            y
+        );
+
+        gated += filter.is_gated() as u32;
+        input = (input.0 + burst.dx as i64, input.1 + burst.dy as i64);
+        output = (output.0 + x as i64, output.1 + y as i64);
+    }
+    eprintln!("input  {} {}", input.0, input.1);
+    eprintln!("output {} {}", output.0, output.1);
+    eprintln!("gated  {} samples", gated);
+}
+
+// a sample, time_us,dx,dy[,squal,lifted,shutter]
+fn parse(fields: &[&str]) -> Option<(u64, Burst)> {
+    let time_us = fields.first()?.parse().ok()?;
+    let mut burst = Burst {
+        motion: true,
+        squal: u8::MAX,
+        ..Burst::default()
+    };
+    match fields {
+        [_, dx, dy] => {
+            burst.dx = dx.parse().ok()?;
+            burst.dy = dy.parse().ok()?;
+        }
+        [_, dx, dy, squal, lifted, shutter] => {
+            burst.dx = dx.parse().ok()?;
+            burst.dy = dy.parse().ok()?;
+            burst.squal = squal.parse().ok()?;
+            burst.lifted = lifted.parse::<u8>().ok()? != 0;
+            burst.shutter = shutter.parse().ok()?;
+        }
+        _ => return None,
+    }
+    Some((time_us, burst))
+}
@@ -0,0 +1,251 @@
+//! Reads and writes the settings of the USB mouse (examples/rtt_rtic_usb_pmw3389.rs)
+//!
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin mouse-config -- [options]
+//!
+//! The settings are a vendor-defined HID feature report, accessed through
+//! `hidraw`. The device needs to be readable and writable by the user, e.g.,
+//! by a udev rule:
+//!
+//! SUBSYSTEM=="hidraw", ATTRS{idVendor}=="c410", MODE="0666"
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::process;
+
+use app::console;
+use app::settings::{self, Lift, Settings};
+use app::usb::{id, mouse::SETTINGS_ID};
+
+const USAGE: &str = "\
+usage: mouse-config [-d /dev/hidrawN] [options]
+
+Without options the current settings are printed.
+
+options:
+  -d device         hidraw device (default, the first mouse found)
+  --cpi n           resolution, 50..16000 cpi in steps of 50
+  --lift mm         lift-off distance, 2 or 3 mm
+  --angle-snap b    angle snapping, on or off
+  --rest b          sensor rest mode, on or off
+  --polling ms      report interval, 1..255 ms
+  --swap b          swap the sensor x and y axes, on or off
+  --invert-x b      invert the sensor x axis (after swapping), on or off
+  --invert-y b      invert the sensor y axis (after swapping), on or off
+  --lift-squal n    lift calibration, drop motion below this SQUAL
+  --lift-shutter n  lift calibration, drop motion above this shutter, 0 off
+  --back binding    back button binding, e.g., paste or 'keys 01:06'
+  --forward binding forward button binding (see help in the mouse console)";
+
+// the vendor usage page of the settings feature report, identifying the
+// mouse interface (together with the USB VID, `app::usb::id::VID`)
+const VENDOR_PAGE: [u8; 3] = [0x06, 0x00, 0xff];
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+// _IOC(_IOC_READ | _IOC_WRITE, 'H', nr, len), see linux/hidraw.h
+fn hidioc(nr: u64, len: usize) -> u64 {
+    (3 << 30) | ((len as u64) << 16) | ((b'H' as u64) << 8) | nr
+}
+
+fn get_feature(dev: &File) -> io::Result<[u8; settings::SIZE]> {
+    // the report ID, followed by the report
+    let mut buf = [0u8; settings::SIZE + 1];
+    buf[0] = SETTINGS_ID;
+    let r = unsafe { libc::ioctl(dev.as_raw_fd(), hidioc(0x07, buf.len()), buf.as_mut_ptr()) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut report = [0; settings::SIZE];
+    report.copy_from_slice(&buf[1..]);
+    Ok(report)
+}
+
+fn set_feature(dev: &File, report: &[u8; settings::SIZE]) -> io::Result<()> {
+    let mut buf = [0u8; settings::SIZE + 1];
+    buf[0] = SETTINGS_ID;
+    buf[1..].copy_from_slice(report);
+    let r = unsafe { libc::ioctl(dev.as_raw_fd(), hidioc(0x06, buf.len()), buf.as_mut_ptr()) };
+    if r < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// the hidraw node of the configuration interface, from sysfs
+fn find() -> Option<String> {
+    // e.g., HID_ID=0003:0000C410:00000000
+    let vid = format!(":{:08X}:", id::VID);
+    for entry in fs::read_dir("/sys/class/hidraw").ok()?.flatten() {
+        let path = entry.path();
+        let uevent = fs::read_to_string(path.join("device/uevent")).unwrap_or_default();
+        let descr = fs::read(path.join("device/report_descriptor")).unwrap_or_default();
+        if uevent.contains(&vid) && descr.windows(VENDOR_PAGE.len()).any(|w| w == VENDOR_PAGE) {
+            return Some(format!("/dev/{}", entry.file_name().to_string_lossy()));
+        }
+    }
+    None
+}
+
+fn on_off(s: Option<String>) -> bool {
+    match s.as_deref() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => usage(),
+    }
+}
+
+fn binding(s: Option<String>) -> settings::Binding {
+    let s = s.unwrap_or_else(|| usage());
+    console::parse_binding(s.split_whitespace()).unwrap_or_else(|e| {
+        eprintln!("{}: {}", s, e);
+        usage()
+    })
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut device = None;
+    let mut cpi = None;
+    let mut lift = None;
+    let mut angle_snap = None;
+    let mut rest = None;
+    let mut polling_ms = None;
+    let mut swap = None;
+    let mut invert_x = None;
+    let mut invert_y = None;
+    let mut lift_squal = None;
+    let mut lift_shutter = None;
+    let mut back = None;
+    let mut forward = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-d" => device = Some(args.next().unwrap_or_else(|| usage())),
+            "--cpi" => {
+                cpi = Some(
+                    args.next()
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--lift" => {
+                lift = match args.next().as_deref() {
+                    Some("2") => Some(Lift::Mm2),
+                    Some("3") => Some(Lift::Mm3),
+                    _ => usage(),
+                }
+            }
+            "--angle-snap" => angle_snap = Some(on_off(args.next())),
+            "--rest" => rest = Some(on_off(args.next())),
+            "--polling" => {
+                polling_ms = Some(
+                    args.next()
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--swap" => swap = Some(on_off(args.next())),
+            "--invert-x" => invert_x = Some(on_off(args.next())),
+            "--invert-y" => invert_y = Some(on_off(args.next())),
+            "--lift-squal" => {
+                lift_squal = Some(
+                    args.next()
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--lift-shutter" => {
+                lift_shutter = Some(
+                    args.next()
+                        .and_then(|s| s.parse().ok())
+                        .unwrap_or_else(|| usage()),
+                )
+            }
+            "--back" => back = Some(binding(args.next())),
+            "--forward" => forward = Some(binding(args.next())),
+            _ => usage(),
+        }
+    }
+    let changed = cpi.is_some()
+        || lift.is_some()
+        || angle_snap.is_some()
+        || rest.is_some()
+        || polling_ms.is_some()
+        || swap.is_some()
+        || invert_x.is_some()
+        || invert_y.is_some()
+        || lift_squal.is_some()
+        || lift_shutter.is_some()
+        || back.is_some()
+        || forward.is_some();
+
+    let device = device.or_else(find).unwrap_or_else(|| {
+        eprintln!("no mouse found, try -d /dev/hidrawN");
+        process::exit(1)
+    });
+    let dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&device)
+        .unwrap_or_else(|e| {
+            eprintln!("failed to open {}: {}", device, e);
+            process::exit(1)
+        });
+
+    let report = get_feature(&dev).expect("failed to read settings");
+    let mut settings = Settings::from_bytes(&report).unwrap_or_else(|| {
+        eprintln!("unsupported settings {:02x?}", report);
+        process::exit(1)
+    });
+
+    if changed {
+        settings.cpi = cpi.unwrap_or(settings.cpi);
+        settings.lift = lift.unwrap_or(settings.lift);
+        settings.angle_snap = angle_snap.unwrap_or(settings.angle_snap);
+        settings.rest = rest.unwrap_or(settings.rest);
+        settings.polling_ms = polling_ms.unwrap_or(settings.polling_ms);
+        let orientation = &mut settings.orientation;
+        orientation.swap = swap.unwrap_or(orientation.swap);
+        orientation.invert_x = invert_x.unwrap_or(orientation.invert_x);
+        orientation.invert_y = invert_y.unwrap_or(orientation.invert_y);
+        settings.lift_squal = lift_squal.unwrap_or(settings.lift_squal);
+        settings.lift_shutter = lift_shutter.unwrap_or(settings.lift_shutter);
+        settings.back = back.unwrap_or(settings.back);
+        settings.forward = forward.unwrap_or(settings.forward);
+        // validate before sending, the mouse ignores invalid settings
+        let report = settings.to_bytes();
+        if Settings::from_bytes(&report).is_none() {
+            eprintln!("invalid settings {:?}", settings);
+            process::exit(1)
+        }
+        set_feature(&dev, &report).expect("failed to write settings");
+        let report = get_feature(&dev).expect("failed to read settings");
+        settings = Settings::from_bytes(&report).expect("unsupported settings");
+    }
+
+    println!("device      {}", device);
+    println!("cpi         {}", settings.cpi);
+    println!("lift        {} mm", settings.lift as u8);
+    println!(
+        "angle snap  {}",
+        if settings.angle_snap { "on" } else { "off" }
+    );
+    println!("rest        {}", if settings.rest { "on" } else { "off" });
+    println!("polling     {} ms", settings.polling_ms);
+    let o = settings.orientation;
+    let state = |on| if on { "on" } else { "off" };
+    println!(
+        "orientation swap {}, invert x {}, invert y {}",
+        state(o.swap),
+        state(o.invert_x),
+        state(o.invert_y)
+    );
+    println!("lift squal  {}", settings.lift_squal);
+    println!("lift shut.  {}", settings.lift_shutter);
+    println!("back        {:02x?}", settings.back);
+    println!("forward     {:02x?}", settings.forward);
+}
@@ -0,0 +1,132 @@
+//! Streams PMW3389 motion data to stdout as CSV
+//!
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin pmw3389 -- <bus> [options]
+//!
+//! Driver tracing goes to stderr, so the output can be redirected to a file.
+use std::env;
+use std::fmt::Debug;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use app::bus::sc18is602::{Order, Speed, SH18IS602};
+use app::bus::Spidev;
+use app::pmw3389e::{Pmw3389e, Register};
+use app::time::StdTimer;
+use app_host::loopback::Loopback;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+use embedded_hal::spi::MODE_3;
+use linux_embedded_hal::{Delay, I2cdev};
+
+const USAGE: &str = "\
+usage: pmw3389 <bus> [-n samples] [-p period_ms]
+
+buses:
+  spidev <device> <ncs-gpio>  sensor on spidev, NCS on a sysfs GPIO,
+                              e.g., spidev /dev/spidev0.0 25
+  i2c <device>                sensor behind an SC18IS602 bridge (address 0),
+                              e.g., i2c /dev/i2c-1
+  loopback                    simulated sensor, no hardware required
+
+options:
+  -n samples    number of samples, 0 streams forever (default 0)
+  -p period_ms  sampling period in ms (default 1)";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+fn stream<SPI, E>(pmw3389: &mut Pmw3389e<SPI, Delay, E>, samples: u64, period: Duration)
+where
+    SPI: Transfer<u8, Error = E> + OutputPin,
+    E: Debug,
+{
+    // set in burst mode
+    pmw3389.write_register(Register::MotionBurst, 0x00).unwrap();
+
+    println!("time_us,dx,dy,squal,lifted,shutter");
+    let start = Instant::now();
+    let mut next = start;
+    let mut n = 0;
+    while samples == 0 || n < samples {
+        let burst = pmw3389.read_burst().unwrap();
+        println!(
+            "{},{},{},{},{},{}",
+            start.elapsed().as_micros(),
+            burst.dx,
+            burst.dy,
+            burst.squal,
+            burst.lifted as u8,
+            burst.shutter
+        );
+        n += 1;
+
+        next += period;
+        if let Some(d) = next.checked_duration_since(Instant::now()) {
+            thread::sleep(d);
+        }
+    }
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut samples = 0;
+    let mut period_ms = 1;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => {
+                samples = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-p" => {
+                period_ms = args
+                    .next()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or_else(|| usage())
+            }
+            "-h" | "--help" => usage(),
+            _ => positional.push(arg),
+        }
+    }
+    let period = Duration::from_millis(period_ms);
+
+    match positional
+        .iter()
+        .map(|s| s.as_str())
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["spidev", dev, ncs] => {
+            let ncs = ncs.parse().unwrap_or_else(|_| usage());
+            let spi = Spidev::open(dev, ncs, 2_000_000).expect("failed to open spidev");
+            let mut pmw3389 = Pmw3389e::new(spi, Delay).unwrap();
+            stream(&mut pmw3389, samples, period);
+        }
+        ["i2c", dev] => {
+            let i2c = I2cdev::new(dev).expect("failed to open i2c-dev");
+            let spi_emu = SH18IS602::new(
+                i2c,
+                StdTimer::new(),
+                0,
+                Order::MsbFirst,
+                MODE_3,
+                Speed::Speed1843kHz,
+                true,
+            )
+            .expect("failed to configure the SC18IS602");
+            let mut pmw3389 = Pmw3389e::new(spi_emu, Delay).unwrap();
+            stream(&mut pmw3389, samples, period);
+        }
+        ["loopback"] => {
+            let mut pmw3389 = Pmw3389e::new(Loopback::new(), Delay).unwrap();
+            stream(&mut pmw3389, samples, period);
+        }
+        _ => usage(),
+    }
+}
@@ -0,0 +1,144 @@
+//! Renders the profile dumps (`app::profile`) of the firmware
+//!
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin profile-view -- localhost:8765
+//!
+//! The dumps are read from the RTT profile channel (channel 2 of
+//! examples/rtt_rtic_usb_pmw3389.rs), as served over TCP by an RTT host
+//! (e.g., OpenOCD `rtt server start 8765 2`), or saved to a file (`-` for
+//! stdin). Each dump is printed as a table of the scopes, in us at the core
+//! clock of the dump, with the share of the dump period spent in the scope
+//! (the load). On a terminal the table is redrawn in place.
+use std::env;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::process;
+
+use app::profile::{Decoder, Record};
+
+const USAGE: &str = "\
+usage: profile-view <host:port|file|-> [options]
+
+options:
+  -n n        stop after n dumps";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+fn arg<T: std::str::FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())
+}
+
+fn open(input: &str) -> io::Result<Box<dyn Read>> {
+    if input == "-" {
+        Ok(Box::new(io::stdin()))
+    } else if input.contains(':') {
+        Ok(Box::new(TcpStream::connect(input)?))
+    } else {
+        Ok(Box::new(File::open(input)?))
+    }
+}
+
+fn render(out: &mut impl Write, dump: &[Record], clear: bool) -> io::Result<()> {
+    if clear {
+        // clear the screen, cursor home
+        write!(out, "\x1b[2J\x1b[H")?;
+    }
+    let (frequency, period) = dump.first().map_or((0, 0), |r| (r.frequency, r.period));
+    writeln!(
+        out,
+        "period {:.1} ms at {:.1} MHz",
+        period as f64 * 1e3 / frequency.max(1) as f64,
+        frequency as f64 / 1e6
+    )?;
+    writeln!(
+        out,
+        "{:<12} {:>8} {:>10} {:>10} {:>10} {:>7}",
+        "scope", "count", "min us", "mean us", "max us", "load %"
+    )?;
+    for r in dump {
+        writeln!(
+            out,
+            "{:<12} {:>8} {:>10.2} {:>10.2} {:>10.2} {:>7.2}",
+            r.name(),
+            r.count,
+            r.to_us(r.min),
+            r.to_us(r.mean),
+            r.to_us(r.max),
+            r.load() * 100.0
+        )?;
+    }
+    writeln!(out)?;
+    out.flush()
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut input = None;
+    let mut count = u64::MAX;
+
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-n" => count = arg(args.next()),
+            "-" if input.is_none() => input = Some(a),
+            _ if input.is_none() && !a.starts_with('-') => input = Some(a),
+            _ => usage(),
+        }
+    }
+    let input = input.unwrap_or_else(|| usage());
+
+    let mut dev = open(&input).unwrap_or_else(|e| {
+        eprintln!("failed to open {}: {}", input, e);
+        process::exit(1)
+    });
+    let clear = unsafe { libc::isatty(libc::STDOUT_FILENO) } == 1;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    let mut decoder = Decoder::new();
+    let mut buf = [0; 512];
+    let mut dump: Vec<Record> = Vec::new();
+    let (mut dumps, mut partial) = (0u64, 0u64);
+    'view: loop {
+        let n = match dev.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("read failed: {}", e);
+                break;
+            }
+        };
+        for b in &buf[..n] {
+            let record = match decoder.push(*b) {
+                Some(record) => record,
+                None => continue,
+            };
+            // a dump starts at index 0, records of a dump cut short (the
+            // channel was full) are dropped
+            if record.index as usize != dump.len() {
+                partial += 1;
+                dump.clear();
+                if record.index != 0 {
+                    continue;
+                }
+            }
+            dump.push(record);
+            if record.is_last() {
+                if render(&mut out, &dump, clear).is_err() {
+                    break 'view;
+                }
+                dump.clear();
+                dumps += 1;
+                if dumps >= count {
+                    break 'view;
+                }
+            }
+        }
+    }
+
+    eprintln!("dumps   {}", dumps);
+    eprintln!("partial {}", partial);
+    eprintln!("skipped {} bytes", decoder.skipped());
+}
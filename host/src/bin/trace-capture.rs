@@ -0,0 +1,158 @@
+//! Captures motion records (`app::trace`) of the USB mouse (examples/rtt_rtic_usb_pmw3389.rs)
+//!
+//! cargo run -p app-host --target x86_64-unknown-linux-gnu --bin trace-capture -- /dev/ttyACM0 -o trace.trc
+//!
+//! From the serial port of the mouse, the console is switched to streaming
+//! records (`trace on`) for the duration of the capture. The input may also
+//! be a file, e.g., RTT channel 1 saved by an RTT host, or an earlier
+//! capture. The valid records are saved to the output file, and with
+//! `--csv` printed as CSV to stdout, in the format of the `pmw3389` tool
+//! (the shutter is not recorded, and printed as 0), for `motion-replay`.
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::process;
+use std::time::{Duration, Instant};
+
+use app::trace::{flags, Decoder};
+
+const USAGE: &str = "\
+usage: trace-capture <device|file> [options]
+
+options:
+  -o file     save the records to file
+  -n n        stop after n records
+  -t s        stop after s seconds, serial port only (default 10)
+  --csv       print the records as CSV";
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1)
+}
+
+fn arg<T: std::str::FromStr>(arg: Option<String>) -> T {
+    arg.and_then(|s| s.parse().ok()).unwrap_or_else(|| usage())
+}
+
+// raw mode, no echo or line editing, reads return after 100 ms without data
+fn set_raw(tty: &File) -> io::Result<()> {
+    unsafe {
+        let mut termios = std::mem::zeroed::<libc::termios>();
+        if libc::tcgetattr(tty.as_raw_fd(), &mut termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = 1;
+        if libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &termios) < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let mut input = None;
+    let mut output = None;
+    let mut count = u64::MAX;
+    let mut seconds = 10;
+    let mut csv = false;
+
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "-o" => output = Some(args.next().unwrap_or_else(|| usage())),
+            "-n" => count = arg(args.next()),
+            "-t" => seconds = arg(args.next()),
+            "--csv" => csv = true,
+            _ if input.is_none() && !a.starts_with('-') => input = Some(a),
+            _ => usage(),
+        }
+    }
+    let input = input.unwrap_or_else(|| usage());
+
+    let mut dev = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&input)
+        .or_else(|_| File::open(&input))
+        .unwrap_or_else(|e| {
+            eprintln!("failed to open {}: {}", input, e);
+            process::exit(1)
+        });
+    let tty = unsafe { libc::isatty(dev.as_raw_fd()) } == 1;
+    if tty {
+        set_raw(&dev).expect("failed to set raw mode");
+        dev.write_all(b"trace on\r")
+            .expect("failed to start tracing");
+    }
+
+    let mut out = output.map(|path| {
+        File::create(&path).unwrap_or_else(|e| {
+            eprintln!("failed to create {}: {}", path, e);
+            process::exit(1)
+        })
+    });
+    if csv {
+        println!("time_us,dx,dy,squal,lifted,shutter");
+    }
+
+    let start = Instant::now();
+    let mut decoder = Decoder::new();
+    let mut buf = [0; 512];
+    let (mut records, mut gated, mut dropped) = (0u64, 0u64, 0u64);
+    // timestamps wrap, unwrapped for the CSV
+    let (mut time_us, mut last) = (0u64, None);
+    'capture: loop {
+        if tty && start.elapsed() >= Duration::from_secs(seconds) {
+            break;
+        }
+        let n = match dev.read(&mut buf) {
+            // end of file, or no data for a while on the serial port
+            Ok(0) if tty => continue,
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) => {
+                eprintln!("read failed: {}", e);
+                break;
+            }
+        };
+        for b in &buf[..n] {
+            let record = match decoder.push(*b) {
+                Some(record) => record,
+                None => continue,
+            };
+            if let Some(out) = out.as_mut() {
+                out.write_all(&record.to_bytes())
+                    .expect("failed to write the output");
+            }
+            if csv {
+                time_us += last.map_or(0, |last: u32| record.time_us.wrapping_sub(last) as u64);
+                last = Some(record.time_us);
+                println!(
+                    "{},{},{},{},{},0",
+                    time_us,
+                    record.dx,
+                    record.dy,
+                    record.squal,
+                    (record.flags & flags::LIFTED != 0) as u8
+                );
+            }
+            records += 1;
+            gated += (record.flags & flags::GATED != 0) as u64;
+            dropped += (record.flags & flags::DROPPED != 0) as u64;
+            if records >= count {
+                break 'capture;
+            }
+        }
+    }
+
+    if tty {
+        dev.write_all(b"trace off\r").ok();
+    }
+    eprintln!("records {}", records);
+    eprintln!("gated   {}", gated);
+    eprintln!("dropped {} (records lost before)", dropped);
+    eprintln!("skipped {} bytes", decoder.skipped());
+}
@@ -0,0 +1,496 @@
+//! Parsing and validation of USB and HID descriptors
+//!
+//! Used by the USB tests (host/tests/usb.rs) to verify the descriptors
+//! returned by the device classes (`app::usb`) during enumeration. The
+//! parsers are strict, any malformed or inconsistent descriptor is an
+//! error, described by the returned `String`.
+use std::collections::BTreeMap;
+
+pub const DEVICE: u8 = 0x01;
+pub const CONFIGURATION: u8 = 0x02;
+pub const STRING: u8 = 0x03;
+pub const INTERFACE: u8 = 0x04;
+pub const ENDPOINT: u8 = 0x05;
+pub const INTERFACE_ASSOCIATION: u8 = 0x0b;
+pub const HID: u8 = 0x21;
+pub const REPORT: u8 = 0x22;
+// class-specific (e.g., CDC functional) descriptors
+const CS_INTERFACE: u8 = 0x24;
+const CS_ENDPOINT: u8 = 0x25;
+
+const CLASS_HID: u8 = 0x03;
+
+/// The device descriptor
+#[derive(Debug)]
+pub struct Device {
+    pub usb: u16,
+    pub class: u8,
+    pub max_packet_size_0: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: u8,
+    pub product: u8,
+    pub serial_number: u8,
+    pub num_configurations: u8,
+}
+
+/// An endpoint descriptor
+#[derive(Debug)]
+pub struct Endpoint {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+/// The HID descriptor, with a single (report) class descriptor
+#[derive(Debug)]
+pub struct Hid {
+    pub bytes: Vec<u8>,
+    pub report_length: u16,
+}
+
+/// An interface descriptor, with its endpoints (and HID descriptor)
+#[derive(Debug)]
+pub struct Interface {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub hid: Option<Hid>,
+    pub endpoints: Vec<Endpoint>,
+    num_endpoints: u8,
+}
+
+/// The configuration descriptor, with all interfaces
+#[derive(Debug)]
+pub struct Configuration {
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub value: u8,
+    pub attributes: u8,
+    pub max_power: u8,
+    pub interfaces: Vec<Interface>,
+}
+
+/// Parses an 18 byte device descriptor
+pub fn parse_device(d: &[u8]) -> Result<Device, String> {
+    if d.len() != 18 || d[0] != 18 || d[1] != DEVICE {
+        return Err(format!("not a device descriptor: {:02x?}", d));
+    }
+    let device = Device {
+        usb: u16::from_le_bytes([d[2], d[3]]),
+        class: d[4],
+        max_packet_size_0: d[7],
+        vendor_id: u16::from_le_bytes([d[8], d[9]]),
+        product_id: u16::from_le_bytes([d[10], d[11]]),
+        manufacturer: d[14],
+        product: d[15],
+        serial_number: d[16],
+        num_configurations: d[17],
+    };
+    if device.usb < 0x0110 {
+        return Err(format!("bcdUSB {:04x}", device.usb));
+    }
+    if ![8, 16, 32, 64].contains(&device.max_packet_size_0) {
+        return Err(format!("bMaxPacketSize0 {}", device.max_packet_size_0));
+    }
+    if device.num_configurations == 0 {
+        return Err("no configurations".to_string());
+    }
+    Ok(device)
+}
+
+/// Parses a string descriptor (other than the language IDs, index 0)
+pub fn parse_string(d: &[u8]) -> Result<String, String> {
+    if d.len() < 2 || d[0] as usize != d.len() || d[1] != STRING || d.len() & 1 != 0 {
+        return Err(format!("not a string descriptor: {:02x?}", d));
+    }
+    let utf16: Vec<u16> = d[2..]
+        .chunks(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&utf16).map_err(|e| e.to_string())
+}
+
+/// Parses a complete configuration descriptor (`wTotalLength` bytes)
+pub fn parse_configuration(data: &[u8]) -> Result<Configuration, String> {
+    let mut descriptors = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let len = rest[0] as usize;
+        if len < 2 || len > rest.len() {
+            return Err(format!(
+                "bLength {} at offset {}",
+                len,
+                data.len() - rest.len()
+            ));
+        }
+        descriptors.push(&rest[..len]);
+        rest = &rest[len..];
+    }
+
+    let (c, descriptors) = descriptors
+        .split_first()
+        .ok_or_else(|| "empty configuration".to_string())?;
+    if c.len() != 9 || c[1] != CONFIGURATION {
+        return Err(format!("not a configuration descriptor: {:02x?}", c));
+    }
+    let mut config = Configuration {
+        total_length: u16::from_le_bytes([c[2], c[3]]),
+        num_interfaces: c[4],
+        value: c[5],
+        attributes: c[7],
+        max_power: c[8],
+        interfaces: Vec::new(),
+    };
+    if config.total_length as usize != data.len() {
+        return Err(format!(
+            "wTotalLength {}, {} bytes",
+            config.total_length,
+            data.len()
+        ));
+    }
+    if config.attributes & 0x80 == 0 {
+        return Err(format!(
+            "bmAttributes {:02x}, bit 7 not set",
+            config.attributes
+        ));
+    }
+
+    // interface associations, (first interface, count)
+    let mut associations = Vec::new();
+    for d in descriptors {
+        match d[1] {
+            INTERFACE => {
+                expect_length(d, 9)?;
+                config.interfaces.push(Interface {
+                    number: d[2],
+                    alternate: d[3],
+                    num_endpoints: d[4],
+                    class: d[5],
+                    subclass: d[6],
+                    protocol: d[7],
+                    hid: None,
+                    endpoints: Vec::new(),
+                });
+            }
+            ENDPOINT => {
+                expect_length(d, 7)?;
+                let endpoint = parse_endpoint(d)?;
+                config
+                    .interfaces
+                    .last_mut()
+                    .ok_or_else(|| "endpoint before any interface".to_string())?
+                    .endpoints
+                    .push(endpoint);
+            }
+            HID => {
+                let hid = parse_hid(d)?;
+                let interface = config
+                    .interfaces
+                    .last_mut()
+                    .ok_or_else(|| "HID descriptor before any interface".to_string())?;
+                if interface.class != CLASS_HID {
+                    return Err(format!(
+                        "HID descriptor in interface {} of class {:02x}",
+                        interface.number, interface.class
+                    ));
+                }
+                if interface.hid.is_some() || !interface.endpoints.is_empty() {
+                    return Err(format!(
+                        "HID descriptor of interface {} not before the endpoints",
+                        interface.number
+                    ));
+                }
+                interface.hid = Some(hid);
+            }
+            INTERFACE_ASSOCIATION => {
+                expect_length(d, 8)?;
+                if !config.interfaces.is_empty() && d[2] <= config.interfaces.last().unwrap().number
+                {
+                    return Err(format!("association of interface {} too late", d[2]));
+                }
+                associations.push((d[2], d[3]));
+            }
+            CS_INTERFACE | CS_ENDPOINT => {
+                if config.interfaces.is_empty() {
+                    return Err("class descriptor before any interface".to_string());
+                }
+            }
+            t => return Err(format!("unexpected descriptor type {:02x}", t)),
+        }
+    }
+
+    let mut numbers: Vec<u8> = config.interfaces.iter().map(|i| i.number).collect();
+    numbers.dedup();
+    if numbers.len() != config.num_interfaces as usize
+        || numbers.iter().enumerate().any(|(i, n)| *n as usize != i)
+    {
+        return Err(format!(
+            "bNumInterfaces {}, interfaces {:?}",
+            config.num_interfaces, numbers
+        ));
+    }
+    for (first, count) in associations {
+        if count == 0 || first as usize + count as usize > numbers.len() {
+            return Err(format!("association of interfaces {}+{}", first, count));
+        }
+    }
+
+    let mut addresses = Vec::new();
+    for interface in &config.interfaces {
+        if interface.endpoints.len() != interface.num_endpoints as usize {
+            return Err(format!(
+                "interface {}, bNumEndpoints {}, {} endpoints",
+                interface.number,
+                interface.num_endpoints,
+                interface.endpoints.len()
+            ));
+        }
+        if interface.class == CLASS_HID && interface.hid.is_none() {
+            return Err(format!(
+                "HID interface {} without HID descriptor",
+                interface.number
+            ));
+        }
+        for endpoint in &interface.endpoints {
+            if addresses.contains(&endpoint.address) {
+                return Err(format!("endpoint {:02x} used twice", endpoint.address));
+            }
+            addresses.push(endpoint.address);
+        }
+    }
+    Ok(config)
+}
+
+fn expect_length(d: &[u8], len: usize) -> Result<(), String> {
+    if d.len() == len {
+        Ok(())
+    } else {
+        Err(format!("descriptor type {:02x}, bLength {}", d[1], d.len()))
+    }
+}
+
+fn parse_endpoint(d: &[u8]) -> Result<Endpoint, String> {
+    let endpoint = Endpoint {
+        address: d[2],
+        attributes: d[3],
+        max_packet_size: u16::from_le_bytes([d[4], d[5]]),
+        interval: d[6],
+    };
+    if endpoint.address & 0x0f == 0 || endpoint.address & 0x70 != 0 {
+        return Err(format!("endpoint address {:02x}", endpoint.address));
+    }
+    // full-speed limits (USB 2.0, 5.5.3 - 5.8.3)
+    let ok = match endpoint.attributes & 0x03 {
+        // isochronous
+        1 => endpoint.max_packet_size <= 1023 && endpoint.interval == 1,
+        // bulk
+        2 => [8, 16, 32, 64].contains(&endpoint.max_packet_size),
+        // interrupt
+        3 => endpoint.max_packet_size <= 64 && endpoint.interval >= 1,
+        _ => false,
+    };
+    if !ok {
+        return Err(format!("endpoint {:02x?}", d));
+    }
+    Ok(endpoint)
+}
+
+/// Parses a HID descriptor (the standalone GET_DESCRIPTOR result, or the one
+/// embedded in the configuration)
+pub fn parse_hid(d: &[u8]) -> Result<Hid, String> {
+    if d.len() != 9 || d[0] != 9 || d[1] != HID {
+        return Err(format!("not a HID descriptor: {:02x?}", d));
+    }
+    // bcdHID, bCountryCode, bNumDescriptors
+    if d[5] != 1 || d[6] != REPORT {
+        return Err(format!(
+            "HID descriptor, report descriptor not first: {:02x?}",
+            d
+        ));
+    }
+    let report_length = u16::from_le_bytes([d[7], d[8]]);
+    if report_length == 0 {
+        return Err("wDescriptorLength 0".to_string());
+    }
+    Ok(Hid {
+        bytes: d.to_vec(),
+        report_length,
+    })
+}
+
+/// Report type, of a HID main item
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ReportType {
+    Input,
+    Output,
+    Feature,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    logical_minimum: Option<i32>,
+    logical_maximum: Option<u32>,
+    report_size: Option<u32>,
+    report_id: u8,
+    report_count: Option<u32>,
+}
+
+/// Parses a HID report descriptor
+///
+/// Checks that collections and push/pop are balanced, that each main item
+/// has a report size, count and logical range, and that report IDs are
+/// either used throughout or not at all. Returns the size in bytes of each
+/// report, by type and report ID (0 if not used), including the ID.
+pub fn parse_report(d: &[u8]) -> Result<BTreeMap<(ReportType, u8), usize>, String> {
+    let mut bits: BTreeMap<(ReportType, u8), u32> = BTreeMap::new();
+    let mut globals = Globals::default();
+    let mut stack = Vec::new();
+    let mut collections = 0;
+    let mut uses_ids = None;
+
+    let mut rest = d;
+    while let Some((&prefix, tail)) = rest.split_first() {
+        let offset = d.len() - rest.len();
+        if prefix == 0xfe {
+            return Err(format!("long item at offset {}", offset));
+        }
+        let size = [0, 1, 2, 4][prefix as usize & 0x03];
+        if tail.len() < size {
+            return Err(format!("item at offset {} truncated", offset));
+        }
+        let data = &tail[..size];
+        rest = &tail[size..];
+
+        let mut value = 0u32;
+        for (i, b) in data.iter().enumerate() {
+            value |= (*b as u32) << (8 * i);
+        }
+        // sign extended
+        let signed = match size {
+            1 => value as u8 as i8 as i32,
+            2 => value as u16 as i16 as i32,
+            _ => value as i32,
+        };
+
+        let item_type = (prefix >> 2) & 0x03;
+        let tag = prefix >> 4;
+        match (item_type, tag) {
+            // main items
+            (0, 0x8) | (0, 0x9) | (0, 0xb) => {
+                let report_type = match tag {
+                    0x8 => ReportType::Input,
+                    0x9 => ReportType::Output,
+                    _ => ReportType::Feature,
+                };
+                if collections == 0 {
+                    return Err(format!(
+                        "main item outside a collection at offset {}",
+                        offset
+                    ));
+                }
+                let (size, count) = match (globals.report_size, globals.report_count) {
+                    (Some(size), Some(count)) => (size, count),
+                    _ => {
+                        return Err(format!(
+                            "report size or count undefined at offset {}",
+                            offset
+                        ))
+                    }
+                };
+                // constant (padding) fields need no logical range
+                if value & 0x01 == 0
+                    && (globals.logical_minimum.is_none() || globals.logical_maximum.is_none())
+                {
+                    return Err(format!("logical range undefined at offset {}", offset));
+                }
+                match uses_ids {
+                    None => uses_ids = Some(globals.report_id != 0),
+                    Some(ids) if ids != (globals.report_id != 0) => {
+                        return Err(format!("main item without report ID at offset {}", offset))
+                    }
+                    _ => {}
+                }
+                *bits.entry((report_type, globals.report_id)).or_default() += size * count;
+            }
+            // collection
+            (0, 0xa) => collections += 1,
+            // end collection
+            (0, 0xc) => {
+                if collections == 0 {
+                    return Err(format!("unbalanced end collection at offset {}", offset));
+                }
+                collections -= 1;
+            }
+            (0, _) => return Err(format!("unknown main item {:02x}", prefix)),
+            // global items
+            (1, 0x0) => {}
+            (1, 0x1) => globals.logical_minimum = Some(signed),
+            (1, 0x2) => {
+                // the maximum is unsigned if the minimum is not negative
+                let max = match globals.logical_minimum {
+                    Some(min) if min < 0 => signed as u32,
+                    _ => value,
+                };
+                globals.logical_maximum = Some(max);
+            }
+            (1, 0x3) | (1, 0x4) | (1, 0x5) | (1, 0x6) => {}
+            (1, 0x7) => globals.report_size = Some(value),
+            (1, 0x8) => {
+                if value == 0 || value > 0xff {
+                    return Err(format!("report ID {} at offset {}", value, offset));
+                }
+                globals.report_id = value as u8;
+            }
+            (1, 0x9) => globals.report_count = Some(value),
+            (1, 0xa) => stack.push(globals),
+            (1, 0xb) => {
+                globals = stack
+                    .pop()
+                    .ok_or_else(|| format!("unbalanced pop at offset {}", offset))?
+            }
+            (1, _) => return Err(format!("unknown global item {:02x}", prefix)),
+            // local items
+            (2, _) => {}
+            _ => return Err(format!("reserved item {:02x}", prefix)),
+        }
+
+        // signed logical ranges, the maximum (as signed) not below the minimum
+        if let (Some(min), Some(max)) = (globals.logical_minimum, globals.logical_maximum) {
+            if min < 0 && (max as i32) < min {
+                return Err(format!(
+                    "logical minimum {} above maximum {} at offset {}",
+                    min, max as i32, offset
+                ));
+            }
+            if min >= 0 && max < min as u32 {
+                return Err(format!(
+                    "logical minimum {} above maximum {} at offset {}",
+                    min, max, offset
+                ));
+            }
+        }
+    }
+    if collections != 0 {
+        return Err(format!("{} collections not ended", collections));
+    }
+    if !stack.is_empty() {
+        return Err(format!("{} pushes not popped", stack.len()));
+    }
+
+    let mut sizes = BTreeMap::new();
+    for ((report_type, id), bits) in bits {
+        if bits % 8 != 0 {
+            return Err(format!(
+                "{:?} report {} of {} bits, not a whole number of bytes",
+                report_type, id, bits
+            ));
+        }
+        let size = bits as usize / 8 + (id != 0) as usize;
+        sizes.insert((report_type, id), size);
+    }
+    Ok(sizes)
+}
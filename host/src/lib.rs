@@ -0,0 +1,11 @@
+//! Host side support for the `app` sensor stack
+//!
+//! The binaries in `src/bin` run the same drivers as the firmware, on a
+//! Linux host with the sensor on `spidev` or behind an SC18IS602 on
+//! `i2c-dev`, or against the `loopback` stand-in (no hardware required).
+//! The USB classes run against the simulated bus of `usbsim`, checked by
+//! `descriptors`.
+
+pub mod descriptors;
+pub mod loopback;
+pub mod usbsim;
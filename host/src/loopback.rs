@@ -0,0 +1,131 @@
+//! A simulated PMW3389 on an (emulated) SPI bus
+//!
+//! Decodes the register read/write and motion burst transactions issued by
+//! `Pmw3389e`, and answers with plausible register contents. The motion
+//! burst reports a deterministic square path, 4 x 100 bursts of 5 counts,
+//! so the accumulated position returns to the origin every 400 bursts.
+use core::convert::Infallible;
+
+use app::pmw3389e::Register;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    // NCS high, or waiting for the address byte
+    Idle,
+    Read(u8),
+    Write(u8),
+}
+
+pub struct Loopback {
+    state: State,
+    regs: [u8; 128],
+    bursts: u32,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        let mut regs = [0u8; 128];
+        regs[Register::ProductId as usize] = 0x47;
+        regs[Register::RevisionId as usize] = 0x01;
+        regs[Register::SROMId as usize] = 0x04;
+        regs[Register::SQUAL as usize] = 0x40;
+        regs[Register::InverseProductID as usize] = 0xb8;
+        Loopback {
+            state: State::Idle,
+            regs,
+            bursts: 0,
+        }
+    }
+
+    /// Number of motion bursts read so far
+    pub fn bursts(&self) -> u32 {
+        self.bursts
+    }
+
+    // The next step of the square path
+    fn motion(&mut self) -> (i16, i16) {
+        let step = self.bursts % 400;
+        self.bursts += 1;
+        match step / 100 {
+            0 => (5, 0),
+            1 => (0, 5),
+            2 => (-5, 0),
+            _ => (0, -5),
+        }
+    }
+
+    fn burst(&mut self, buf: &mut [u8]) {
+        let (x, y) = self.motion();
+        let data = [
+            0x80, // Motion, MOT set, on surface
+            0x00, // Observation
+            x as u8,
+            (x >> 8) as u8,
+            y as u8,
+            (y >> 8) as u8,
+            self.regs[Register::SQUAL as usize],
+            0x40, // RawDataSum
+            0x7f, // MaximumRawdata
+            0x00, // MinimumRawdata
+            0x00, // ShutterUpper
+            0x80, // ShutterLower
+        ];
+        for (b, d) in buf.iter_mut().zip(data.iter()) {
+            *b = *d;
+        }
+    }
+}
+
+impl Default for Loopback {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transfer<u8> for Loopback {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        match self.state {
+            State::Idle => {
+                let addr = words[0] & 0x7f;
+                self.state = if words[0] & 0x80 != 0 {
+                    State::Write(addr)
+                } else {
+                    State::Read(addr)
+                };
+                words[0] = 0;
+            }
+            State::Read(addr) if addr == Register::MotionBurst as u8 => self.burst(words),
+            State::Read(addr) => {
+                for w in words.iter_mut() {
+                    *w = self.regs[addr as usize];
+                }
+            }
+            // consecutive writes (e.g., the SROM download) go to the same register
+            State::Write(addr) => {
+                for w in words.iter_mut() {
+                    self.regs[addr as usize] = *w;
+                    *w = 0;
+                }
+            }
+        }
+        Ok(words)
+    }
+}
+
+impl OutputPin for Loopback {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.state = State::Idle;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.state = State::Idle;
+        Ok(())
+    }
+}
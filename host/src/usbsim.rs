@@ -0,0 +1,346 @@
+//! A simulated USB bus, for running the USB classes on the host
+//!
+//! `SimBus` implements `UsbBus` for the device side (the classes and
+//! `UsbDevice`), `Host` plays the USB host, issuing control transfers on
+//! endpoint 0 and collecting interrupt/bulk IN packets. The device is
+//! polled (`UsbDevice::poll`) by a closure passed to the host side, once
+//! per simulated bus event.
+//!
+//! ```ignore
+//! let (bus, host) = usbsim::new();
+//! let alloc = UsbBusAllocator::new(bus);
+//! let mut hid = HIDClass::new(&alloc, mouse::REPORT_DESCR, 0, 2);
+//! let mut dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(0xc410, 0)).build();
+//! let mut poll = || {
+//!     dev.poll(&mut [&mut hid]);
+//! };
+//! host.reset(&mut poll);
+//! let descr = host.control_in(&mut poll, usbsim::get_descriptor(1, 0, 18));
+//! ```
+use std::sync::{Arc, Mutex};
+
+use usb_device::bus::{PollResult, UsbBus};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+const ENDPOINTS: usize = 16;
+
+#[derive(Default)]
+struct State {
+    // allocated endpoints, max packet size (0 if free)
+    in_size: [u16; ENDPOINTS],
+    out_size: [u16; ENDPOINTS],
+    // SETUP packet waiting for the device (endpoint 0)
+    setup: Option<[u8; 8]>,
+    // OUT packets waiting for the device
+    out: [Option<Vec<u8>>; ENDPOINTS],
+    // IN packets written by the device, waiting for the host
+    in_: [Option<Vec<u8>>; ENDPOINTS],
+    // IN packets collected by the host, not yet reported by `poll`
+    in_complete: u16,
+    in_stalled: u16,
+    out_stalled: u16,
+    reset: bool,
+    address: u8,
+}
+
+/// The device side of the simulated bus
+pub struct SimBus {
+    state: Arc<Mutex<State>>,
+}
+
+/// The host side of the simulated bus
+pub struct Host {
+    state: Arc<Mutex<State>>,
+}
+
+/// Creates a bus, and the host attached to it
+pub fn new() -> (SimBus, Host) {
+    let state = Arc::new(Mutex::new(State::default()));
+    (
+        SimBus {
+            state: state.clone(),
+        },
+        Host { state },
+    )
+}
+
+/// The control transfer was stalled (rejected) by the device
+#[derive(Debug, PartialEq)]
+pub struct Stalled;
+
+/// A SETUP packet
+pub fn setup(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> [u8; 8] {
+    let value = value.to_le_bytes();
+    let index = index.to_le_bytes();
+    let length = length.to_le_bytes();
+    [
+        request_type,
+        request,
+        value[0],
+        value[1],
+        index[0],
+        index[1],
+        length[0],
+        length[1],
+    ]
+}
+
+/// A standard GET_DESCRIPTOR request to the device
+pub fn get_descriptor(dtype: u8, index: u8, length: u16) -> [u8; 8] {
+    setup(0x80, 0x06, (dtype as u16) << 8 | index as u16, 0, length)
+}
+
+impl Host {
+    /// Signals a bus reset
+    pub fn reset(&self, poll: &mut dyn FnMut()) {
+        self.state.lock().unwrap().reset = true;
+        poll();
+    }
+
+    /// The address assigned by SET_ADDRESS
+    pub fn address(&self) -> u8 {
+        self.state.lock().unwrap().address
+    }
+
+    /// A control transfer with an IN (or no) data stage, returns the data
+    pub fn control_in(
+        &self,
+        poll: &mut dyn FnMut(),
+        setup: [u8; 8],
+    ) -> core::result::Result<Vec<u8>, Stalled> {
+        let length = u16::from_le_bytes([setup[6], setup[7]]) as usize;
+        self.send_setup(poll, setup)?;
+
+        let max_packet = self.state.lock().unwrap().in_size[0] as usize;
+        let mut data = Vec::new();
+        loop {
+            let packet = self.collect(poll, 0)?;
+            let n = packet.len();
+            data.extend(packet);
+            if n < max_packet || data.len() >= length {
+                break;
+            }
+        }
+
+        // status stage, a zero length OUT packet
+        self.send_out(poll, 0, &[])?;
+        Ok(data)
+    }
+
+    /// A control transfer with an OUT (or no) data stage
+    pub fn control_out(
+        &self,
+        poll: &mut dyn FnMut(),
+        setup: [u8; 8],
+        data: &[u8],
+    ) -> core::result::Result<(), Stalled> {
+        self.send_setup(poll, setup)?;
+        let max_packet = self.state.lock().unwrap().out_size[0] as usize;
+        for chunk in data.chunks(max_packet) {
+            self.send_out(poll, 0, chunk)?;
+        }
+
+        // status stage, a zero length IN packet
+        let status = self.collect(poll, 0)?;
+        if status.is_empty() {
+            Ok(())
+        } else {
+            Err(Stalled)
+        }
+    }
+
+    /// Collects an IN packet of an interrupt or bulk endpoint, if any
+    pub fn read(&self, poll: &mut dyn FnMut(), ep: u8) -> Option<Vec<u8>> {
+        let packet = {
+            let mut state = self.state.lock().unwrap();
+            let packet = state.in_[ep as usize].take()?;
+            state.in_complete |= 1 << ep;
+            packet
+        };
+        poll();
+        Some(packet)
+    }
+
+    fn send_setup(
+        &self,
+        poll: &mut dyn FnMut(),
+        setup: [u8; 8],
+    ) -> core::result::Result<(), Stalled> {
+        {
+            let mut state = self.state.lock().unwrap();
+            // a SETUP clears the stall of endpoint 0, and aborts the last
+            // transfer
+            state.in_stalled &= !1;
+            state.out_stalled &= !1;
+            state.in_[0] = None;
+            state.out[0] = None;
+            state.setup = Some(setup);
+        }
+        poll();
+        self.check_stall()
+    }
+
+    fn send_out(
+        &self,
+        poll: &mut dyn FnMut(),
+        ep: u8,
+        data: &[u8],
+    ) -> core::result::Result<(), Stalled> {
+        self.state.lock().unwrap().out[ep as usize] = Some(data.to_vec());
+        poll();
+        self.check_stall()
+    }
+
+    // the next IN packet of endpoint 0, polling the device until written
+    fn collect(&self, poll: &mut dyn FnMut(), ep: u8) -> core::result::Result<Vec<u8>, Stalled> {
+        // the device answers within a few polls, or never
+        for _ in 0..8 {
+            self.check_stall()?;
+            if let Some(packet) = self.read(poll, ep) {
+                return Ok(packet);
+            }
+            poll();
+        }
+        Err(Stalled)
+    }
+
+    fn check_stall(&self) -> core::result::Result<(), Stalled> {
+        let state = self.state.lock().unwrap();
+        if (state.in_stalled | state.out_stalled) & 1 != 0 {
+            Err(Stalled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl UsbBus for SimBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let mut state = self.state.lock().unwrap();
+        let sizes = match ep_dir {
+            UsbDirection::In => &mut state.in_size,
+            UsbDirection::Out => &mut state.out_size,
+        };
+        let index = match ep_addr {
+            Some(addr) => addr.index(),
+            None if ep_type == EndpointType::Control => 0,
+            None => (1..ENDPOINTS)
+                .find(|i| sizes[*i] == 0)
+                .ok_or(UsbError::EndpointOverflow)?,
+        };
+        if sizes[index] != 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        sizes[index] = max_packet_size;
+        Ok(EndpointAddress::from_parts(index, ep_dir))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.setup = None;
+        state.out = Default::default();
+        state.in_ = Default::default();
+        state.in_complete = 0;
+        state.in_stalled = 0;
+        state.out_stalled = 0;
+        state.address = 0;
+    }
+
+    fn set_device_address(&self, addr: u8) {
+        self.state.lock().unwrap().address = addr;
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let index = ep_addr.index();
+        if buf.len() > state.in_size[index] as usize {
+            return Err(UsbError::BufferOverflow);
+        }
+        if state.in_[index].is_some() {
+            return Err(UsbError::WouldBlock);
+        }
+        state.in_[index] = Some(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let index = ep_addr.index();
+        let packet = match (index, state.setup.take()) {
+            (0, Some(setup)) => setup.to_vec(),
+            (_, setup) => {
+                state.setup = setup;
+                state.out[index].take().ok_or(UsbError::WouldBlock)?
+            }
+        };
+        if packet.len() > buf.len() {
+            return Err(UsbError::BufferOverflow);
+        }
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        let mut state = self.state.lock().unwrap();
+        let bits = match ep_addr.direction() {
+            UsbDirection::In => &mut state.in_stalled,
+            UsbDirection::Out => &mut state.out_stalled,
+        };
+        if stalled {
+            *bits |= 1 << ep_addr.index();
+        } else {
+            *bits &= !(1 << ep_addr.index());
+        }
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        let state = self.state.lock().unwrap();
+        let bits = match ep_addr.direction() {
+            UsbDirection::In => state.in_stalled,
+            UsbDirection::Out => state.out_stalled,
+        };
+        bits & (1 << ep_addr.index()) != 0
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        let mut state = self.state.lock().unwrap();
+        if state.reset {
+            state.reset = false;
+            return PollResult::Reset;
+        }
+
+        let ep_setup = state.setup.is_some() as u16;
+        let mut ep_out = 0;
+        for (i, out) in state.out.iter().enumerate() {
+            if out.is_some() {
+                ep_out |= 1 << i;
+            }
+        }
+        let ep_in_complete = state.in_complete;
+        state.in_complete = 0;
+
+        if ep_setup | ep_out | ep_in_complete == 0 {
+            PollResult::None
+        } else {
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        }
+    }
+}
@@ -0,0 +1,66 @@
+//! The PMW3389 driver against the loopback sensor
+use core::convert::Infallible;
+
+use app::pmw3389e::{Pmw3389e, Register};
+use app_host::loopback::Loopback;
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
+
+// the loopback answers at once, the driver delays are skipped
+struct NoDelay;
+
+impl DelayUs<u32> for NoDelay {
+    fn delay_us(&mut self, _: u32) {}
+}
+
+impl DelayMs<u32> for NoDelay {
+    fn delay_ms(&mut self, _: u32) {}
+}
+
+fn sensor() -> Pmw3389e<Loopback, NoDelay, Infallible> {
+    Pmw3389e::new(Loopback::new(), NoDelay).unwrap()
+}
+
+#[test]
+fn init_uploads_the_firmware() {
+    let mut pmw3389 = sensor();
+    assert_eq!(pmw3389.product_id().unwrap(), 0x47);
+    assert_eq!(
+        pmw3389.read_register(Register::InverseProductID).unwrap(),
+        0xb8
+    );
+    // set after the upload, 5 * 50 = 250 cpi
+    assert_eq!(pmw3389.read_register(Register::ResolutionL).unwrap(), 0x05);
+    assert_eq!(pmw3389.read_register(Register::ResolutionH).unwrap(), 0x00);
+}
+
+#[test]
+fn register_round_trip() {
+    let mut pmw3389 = sensor();
+    pmw3389.write_register(Register::ResolutionL, 0x10).unwrap();
+    pmw3389.write_register(Register::Config2, 0x20).unwrap();
+    assert_eq!(pmw3389.read_register(Register::ResolutionL).unwrap(), 0x10);
+    assert_eq!(pmw3389.read_register(Register::Config2).unwrap(), 0x20);
+}
+
+#[test]
+fn bursts_trace_the_square() {
+    let mut pmw3389 = sensor();
+    pmw3389.write_register(Register::MotionBurst, 0x00).unwrap();
+
+    let (mut x, mut y) = (0i32, 0i32);
+    for i in 0..400 {
+        let burst = pmw3389.read_burst().unwrap();
+        assert!(burst.motion && !burst.lifted);
+        assert_eq!(burst.squal, 0x40);
+        assert_eq!(burst.shutter, 0x80);
+        x += burst.dx as i32;
+        y += burst.dy as i32;
+        if i == 99 {
+            assert_eq!((x, y), (500, 0));
+        } else if i == 199 {
+            assert_eq!((x, y), (500, 500));
+        }
+    }
+    assert_eq!((x, y), (0, 0));
+    assert_eq!(pmw3389.read_status().unwrap(), (5, 0));
+}
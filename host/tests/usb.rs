@@ -0,0 +1,537 @@
+//! The USB descriptors and HID requests of the mouse, on the simulated bus
+//!
+//! Builds the device as the firmware does (examples/rtt_rtic_usb_pmw3389.rs),
+//! a boot mouse HID interface and a CDC-ACM serial port, on a simulated USB
+//! bus. Enumerates it like a host, and checks the returned descriptors (see
+//! `app_host::descriptors`), the HID class requests and the input reports,
+//! and replays a motion trace (`app::trace`) through the HID path.
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+
+use app::motion::{Accumulator, Curve, Filter, FilterConfig, Pipeline};
+use app::pmw3389::Burst;
+use app::settings::{self, Settings};
+use app::trace::{Record, Replay};
+use app::usb::{
+    cdc::CdcAcmClass,
+    hid::{HIDClass, Protocol, USB_INTERFACE_MOUSE, USB_SUBCLASS_BOOT},
+    id, keyboard,
+    mouse::{self, MouseReport, CONSUMER_ID, KEYBOARD_ID, MOUSE_ID, SETTINGS_ID},
+    report::{self, desktop, page},
+};
+use app_host::descriptors::{self, Configuration, ReportType};
+use app_host::usbsim::{self, Host, SimBus, Stalled};
+
+// HID class requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+const SET_PROTOCOL: u8 = 0x0b;
+// report types, upper byte of wValue
+const INPUT: u16 = 0x0100;
+const FEATURE: u16 = 0x0300;
+
+const LANG_ID_EN_US: u16 = 0x0409;
+
+const CHIP_ID: [u32; 3] = [0x0012_3456, 0x789a_bcde, 0xf011_2233];
+
+// the device under test, polled by the simulated host
+struct Device<'a> {
+    usb_dev: UsbDevice<'a, SimBus>,
+    hid: HIDClass<'a, SimBus>,
+    serial: CdcAcmClass<'a, SimBus>,
+}
+
+impl Device<'_> {
+    fn poll(&mut self) {
+        self.usb_dev.poll(&mut [&mut self.hid, &mut self.serial]);
+    }
+}
+
+// builds the device, and runs `test` on it after a bus reset
+fn with_device(test: impl FnOnce(&Host, &mut Device)) {
+    let (bus, host) = usbsim::new();
+    let alloc = UsbBusAllocator::new(bus);
+
+    let mut hid = HIDClass::new(
+        &alloc,
+        mouse::REPORT_DESCR,
+        USB_SUBCLASS_BOOT,
+        USB_INTERFACE_MOUSE,
+    );
+    hid.set_feature(&feature(&Settings::default()));
+    let serial = CdcAcmClass::new(&alloc);
+
+    let mut serial_buf = [0; id::SERIAL_SIZE];
+    let serial_number = id::serial_number(&CHIP_ID, &mut serial_buf);
+    let usb_dev = UsbDeviceBuilder::new(&alloc, UsbVidPid(id::VID, id::PID))
+        .manufacturer(id::MANUFACTURER)
+        .product(id::PRODUCT)
+        .serial_number(serial_number)
+        .composite_with_iads()
+        .supports_remote_wakeup(true)
+        .build();
+
+    let mut dev = Device {
+        usb_dev,
+        hid,
+        serial,
+    };
+    host.reset(&mut || dev.poll());
+    test(&host, &mut dev);
+}
+
+// the settings feature report, prefixed by the report ID
+fn feature(settings: &Settings) -> [u8; settings::SIZE + 1] {
+    let mut report = [SETTINGS_ID; settings::SIZE + 1];
+    report[1..].copy_from_slice(&settings.to_bytes());
+    report
+}
+
+fn device_descriptor(host: &Host, dev: &mut Device) -> descriptors::Device {
+    let d = host
+        .control_in(
+            &mut || dev.poll(),
+            usbsim::get_descriptor(descriptors::DEVICE, 0, 18),
+        )
+        .expect("GET_DESCRIPTOR device");
+    descriptors::parse_device(&d).unwrap()
+}
+
+// the standard requests of enumeration, returns the configuration
+fn enumerate(host: &Host, dev: &mut Device) -> Configuration {
+    device_descriptor(host, dev);
+
+    let set_address = usbsim::setup(0x00, 0x05, 7, 0, 0);
+    host.control_out(&mut || dev.poll(), set_address, &[])
+        .expect("SET_ADDRESS");
+    assert_eq!(host.address(), 7);
+
+    // the header first, then all wTotalLength bytes
+    let header = host
+        .control_in(
+            &mut || dev.poll(),
+            usbsim::get_descriptor(descriptors::CONFIGURATION, 0, 9),
+        )
+        .expect("GET_DESCRIPTOR configuration header");
+    assert_eq!(header.len(), 9);
+    let total = u16::from_le_bytes([header[2], header[3]]);
+    let data = host
+        .control_in(
+            &mut || dev.poll(),
+            usbsim::get_descriptor(descriptors::CONFIGURATION, 0, total),
+        )
+        .expect("GET_DESCRIPTOR configuration");
+    let config = descriptors::parse_configuration(&data).unwrap();
+
+    let set_configuration = usbsim::setup(0x00, 0x09, config.value as u16, 0, 0);
+    host.control_out(&mut || dev.poll(), set_configuration, &[])
+        .expect("SET_CONFIGURATION");
+    assert_eq!(dev.usb_dev.state(), UsbDeviceState::Configured);
+    config
+}
+
+fn hid_interface(config: &Configuration) -> &descriptors::Interface {
+    config
+        .interfaces
+        .iter()
+        .find(|i| i.hid.is_some())
+        .expect("no HID interface")
+}
+
+// the interrupt IN endpoint of the HID interface
+fn hid_endpoint(config: &Configuration) -> &descriptors::Endpoint {
+    hid_interface(config)
+        .endpoints
+        .iter()
+        .find(|e| e.address & 0x80 != 0 && e.attributes & 0x03 == 3)
+        .expect("no interrupt IN endpoint")
+}
+
+fn class_in(request: u8, value: u16, length: u16) -> [u8; 8] {
+    usbsim::setup(0xa1, request, value, 0, length)
+}
+
+fn class_out(request: u8, value: u16, length: u16) -> [u8; 8] {
+    usbsim::setup(0x21, request, value, 0, length)
+}
+
+fn set_protocol(host: &Host, dev: &mut Device, protocol: Protocol) {
+    host.control_out(
+        &mut || dev.poll(),
+        class_out(SET_PROTOCOL, protocol as u16, 0),
+        &[],
+    )
+    .expect("SET_PROTOCOL");
+    assert_eq!(dev.hid.protocol(), protocol);
+}
+
+#[test]
+fn enumeration() {
+    with_device(|host, dev| {
+        let device = device_descriptor(host, dev);
+        assert_eq!((device.vendor_id, device.product_id), (id::VID, id::PID));
+        // composite device with interface associations
+        assert_eq!(device.class, 0xef);
+
+        let config = enumerate(host, dev);
+        assert_eq!(config.attributes & 0x20, 0x20, "remote wakeup");
+        let interface = hid_interface(&config);
+        assert_eq!(interface.number, 0);
+        assert_eq!(
+            (interface.subclass, interface.protocol),
+            (USB_SUBCLASS_BOOT, USB_INTERFACE_MOUSE)
+        );
+    });
+}
+
+#[test]
+fn string_descriptors() {
+    with_device(|host, dev| {
+        let device = device_descriptor(host, dev);
+        let lang_ids = host
+            .control_in(
+                &mut || dev.poll(),
+                usbsim::get_descriptor(descriptors::STRING, 0, 255),
+            )
+            .expect("GET_DESCRIPTOR language IDs");
+        assert_eq!(lang_ids, [4, descriptors::STRING, 0x09, 0x04]);
+
+        let mut serial_buf = [0; id::SERIAL_SIZE];
+        let serial_number = id::serial_number(&CHIP_ID, &mut serial_buf);
+        let strings = [
+            (device.manufacturer, id::MANUFACTURER),
+            (device.product, id::PRODUCT),
+            (device.serial_number, serial_number),
+        ];
+        for (index, expected) in strings.iter() {
+            let mut setup = usbsim::get_descriptor(descriptors::STRING, *index, 255);
+            setup[4..6].copy_from_slice(&LANG_ID_EN_US.to_le_bytes());
+            let d = host
+                .control_in(&mut || dev.poll(), setup)
+                .expect("GET_DESCRIPTOR string");
+            assert_eq!(descriptors::parse_string(&d).unwrap(), *expected);
+        }
+    });
+}
+
+#[test]
+fn hid_descriptor_matches_the_configuration() {
+    with_device(|host, dev| {
+        let config = enumerate(host, dev);
+        let interface = hid_interface(&config);
+        let get_hid = usbsim::setup(
+            0x81,
+            0x06,
+            (descriptors::HID as u16) << 8,
+            interface.number as u16,
+            9,
+        );
+        let d = host
+            .control_in(&mut || dev.poll(), get_hid)
+            .expect("GET_DESCRIPTOR HID");
+        descriptors::parse_hid(&d).unwrap();
+        assert_eq!(d, interface.hid.as_ref().unwrap().bytes);
+    });
+}
+
+#[test]
+fn report_descriptor() {
+    with_device(|host, dev| {
+        let config = enumerate(host, dev);
+        let interface = hid_interface(&config);
+        let report_length = interface.hid.as_ref().unwrap().report_length;
+        assert_eq!(report_length as usize, mouse::REPORT_DESCR.len());
+
+        let get_report_descr = usbsim::setup(
+            0x81,
+            0x06,
+            (descriptors::REPORT as u16) << 8,
+            interface.number as u16,
+            report_length,
+        );
+        let d = host
+            .control_in(&mut || dev.poll(), get_report_descr)
+            .expect("GET_DESCRIPTOR report");
+        assert_eq!(d, mouse::REPORT_DESCR);
+
+        let sizes = descriptors::parse_report(&d).unwrap();
+        let expected = [
+            ((ReportType::Input, MOUSE_ID), mouse::REPORT_SIZE),
+            ((ReportType::Input, KEYBOARD_ID), keyboard::REPORT_SIZE),
+            (
+                (ReportType::Input, CONSUMER_ID),
+                keyboard::CONSUMER_REPORT_SIZE,
+            ),
+            ((ReportType::Feature, SETTINGS_ID), settings::SIZE + 1),
+        ];
+        for (report, size) in expected.iter() {
+            assert_eq!(sizes.get(report), Some(size), "{:?}", report);
+        }
+        assert_eq!(sizes.len(), expected.len(), "no other reports");
+
+        // the settings in a vendor application collection, after the mouse,
+        // keyboard and consumer collections
+        let settings = [0x06, 0x00, 0xff, 0x09, 0x01, 0xa1, 0x01, 0x85, SETTINGS_ID];
+        let at = d.windows(settings.len()).position(|w| w == settings);
+        let last = d.windows(2).rposition(|w| w == [0xa1, 0x01]);
+        assert!(at.is_some() && at.map(|i| i + 5) == last, "{:02x?}", d);
+
+        let ep = hid_endpoint(&config);
+        assert!(mouse::REPORT_SIZE <= ep.max_packet_size as usize);
+    });
+}
+
+#[test]
+fn idle_and_protocol_requests() {
+    with_device(|host, dev| {
+        let config = enumerate(host, dev);
+        let ep = hid_endpoint(&config).address & 0x0f;
+
+        let get_idle = |dev: &mut Device, id: u8| {
+            host.control_in(&mut || dev.poll(), class_in(GET_IDLE, id as u16, 1))
+        };
+        let set_idle = |dev: &mut Device, idle: u8, id: u8| {
+            let value = (idle as u16) << 8 | id as u16;
+            host.control_out(&mut || dev.poll(), class_out(SET_IDLE, value, 0), &[])
+        };
+
+        // idle rate 0 (infinite) after reset, then 500 ms (125 * 4 ms) for
+        // all reports
+        assert_eq!(get_idle(dev, 0), Ok(vec![0]));
+        set_idle(dev, 125, 0).expect("SET_IDLE");
+        for id in [0, MOUSE_ID, KEYBOARD_ID, CONSUMER_ID].iter() {
+            assert_eq!(get_idle(dev, *id), Ok(vec![125]), "report {}", id);
+        }
+
+        // the keyboard alone (e.g., Windows sets 0 for keyboards)
+        set_idle(dev, 0, KEYBOARD_ID).expect("SET_IDLE keyboard");
+        assert_eq!(get_idle(dev, KEYBOARD_ID), Ok(vec![0]));
+        assert_eq!(get_idle(dev, MOUSE_ID), Ok(vec![125]));
+        assert_eq!(dev.hid.idle(CONSUMER_ID), Some(125));
+
+        // no input report of the ID
+        assert_eq!(set_idle(dev, 1, SETTINGS_ID), Err(Stalled));
+        assert_eq!(get_idle(dev, 0x7f), Err(Stalled));
+
+        // only the report of an expired idle period is repeated
+        set_idle(dev, 0, 0).expect("SET_IDLE");
+        set_idle(dev, 2, KEYBOARD_ID).expect("SET_IDLE keyboard");
+        let mut buf = [0; mouse::REPORT_SIZE];
+        dev.hid
+            .set_report(MouseReport::new().serialize(Protocol::Report, &mut buf));
+        let mut key = [0; keyboard::REPORT_SIZE];
+        key[0] = KEYBOARD_ID;
+        dev.hid.set_report(&key);
+        assert!(!dev.hid.tick(7).unwrap());
+        assert!(dev.hid.tick(1).unwrap());
+        let packet = host.read(&mut || dev.poll(), ep).expect("no report");
+        assert_eq!(packet, key);
+
+        let protocol = host.control_in(&mut || dev.poll(), class_in(GET_PROTOCOL, 0, 1));
+        assert_eq!(protocol, Ok(vec![Protocol::Report as u8]));
+        set_protocol(host, dev, Protocol::Boot);
+        set_protocol(host, dev, Protocol::Report);
+    });
+}
+
+#[test]
+fn get_and_set_report() {
+    with_device(|host, dev| {
+        enumerate(host, dev);
+
+        // before any report, all zero but the report ID, of the length in
+        // the descriptor whatever the host asks for
+        let mut zero = [0; mouse::REPORT_SIZE];
+        zero[0] = MOUSE_ID;
+        for length in [mouse::REPORT_SIZE as u16, 64].iter() {
+            let report = host.control_in(
+                &mut || dev.poll(),
+                class_in(GET_REPORT, INPUT | MOUSE_ID as u16, *length),
+            );
+            assert_eq!(report, Ok(zero.to_vec()));
+        }
+        // report ID 0, the boot report
+        let report = host.control_in(&mut || dev.poll(), class_in(GET_REPORT, INPUT, 64));
+        assert_eq!(report, Ok(vec![0; mouse::BOOT_REPORT_SIZE]));
+
+        // the last report of each ID
+        let mut buf = [0; mouse::REPORT_SIZE];
+        let mouse_report = MouseReport::new().buttons(0x02);
+        let mouse_report = mouse_report.serialize(Protocol::Report, &mut buf);
+        dev.hid.set_report(mouse_report);
+        let mut key = [0; keyboard::REPORT_SIZE];
+        key[0] = KEYBOARD_ID;
+        key[3] = 0x04;
+        dev.hid.set_report(&key);
+        let inputs = [
+            (MOUSE_ID, mouse_report.to_vec()),
+            (KEYBOARD_ID, key.to_vec()),
+            (CONSUMER_ID, {
+                let mut zero = vec![0; keyboard::CONSUMER_REPORT_SIZE];
+                zero[0] = CONSUMER_ID;
+                zero
+            }),
+        ];
+        for (id, expected) in inputs.iter() {
+            let report = host.control_in(
+                &mut || dev.poll(),
+                class_in(GET_REPORT, INPUT | *id as u16, 64),
+            );
+            assert_eq!(report.as_ref(), Ok(expected), "report {}", id);
+        }
+        let report = host.control_in(
+            &mut || dev.poll(),
+            class_in(GET_REPORT, INPUT | 0x7f, mouse::REPORT_SIZE as u16),
+        );
+        assert_eq!(report, Err(Stalled));
+
+        let settings = feature(&Settings::default());
+        let report = host.control_in(
+            &mut || dev.poll(),
+            class_in(
+                GET_REPORT,
+                FEATURE | SETTINGS_ID as u16,
+                settings.len() as u16,
+            ),
+        );
+        assert_eq!(report, Ok(settings.to_vec()));
+
+        // a feature report written by the host, read by the application
+        let written = feature(&Settings {
+            cpi: 800,
+            ..Settings::default()
+        });
+        host.control_out(
+            &mut || dev.poll(),
+            class_out(
+                SET_REPORT,
+                FEATURE | SETTINGS_ID as u16,
+                written.len() as u16,
+            ),
+            &written,
+        )
+        .expect("SET_REPORT");
+        let mut buf = [0; settings::SIZE + 1];
+        let n = dev.hid.read_feature(&mut buf);
+        assert_eq!(buf[..n], written[..]);
+
+        // unknown report IDs are rejected
+        let report = host.control_in(
+            &mut || dev.poll(),
+            class_in(GET_REPORT, FEATURE | 0x7f, settings.len() as u16),
+        );
+        assert_eq!(report, Err(Stalled));
+    });
+}
+
+#[test]
+fn input_reports() {
+    with_device(|host, dev| {
+        let config = enumerate(host, dev);
+        let ep = hid_endpoint(&config).address & 0x0f;
+        let report = MouseReport::new().buttons(0x01).motion(-300, 5).wheel(1);
+
+        let mut buf = [0; mouse::REPORT_SIZE];
+        for p in [Protocol::Report, Protocol::Boot].iter() {
+            set_protocol(host, dev, *p);
+            let data = report.serialize(*p, &mut buf);
+            dev.hid.write(data).unwrap();
+            let packet = host.read(&mut || dev.poll(), ep).expect("no report");
+            assert_eq!(packet, data, "{:?} protocol", p);
+            assert!(dev.hid.collected());
+        }
+        assert_eq!(
+            report.serialize(Protocol::Boot, &mut buf).len(),
+            mouse::BOOT_REPORT_SIZE
+        );
+    });
+}
+
+// a motion trace as streamed on the serial port, after the echo of the
+// `trace on` command, with a record torn in the middle: motion, lifted off
+// the surface, and motion again
+fn trace() -> (Vec<u8>, usize) {
+    let mut data = b"trace on\r\n> ".to_vec();
+    let mut records = 0;
+    for i in 0..300u32 {
+        let burst = Burst {
+            motion: true,
+            lifted: (100..150).contains(&i),
+            dx: 3000 - 20 * i as i16,
+            dy: -7,
+            squal: 40,
+            ..Burst::default()
+        };
+        let mut bytes =
+            Record::from_burst(i.wrapping_mul(1000).wrapping_sub(150_000), &burst).to_bytes();
+        if i == 200 {
+            bytes[6] ^= 0x10;
+            data.extend_from_slice(&bytes[..7]);
+            continue;
+        }
+        data.extend_from_slice(&bytes);
+        records += 1;
+    }
+    (data, records)
+}
+
+// replays a trace through the motion filter and pipeline, and the HID
+// class, the host receives the motion not dropped by the filter
+#[test]
+fn trace_replay() {
+    let (data, records) = trace();
+    assert_eq!(Replay::new(&data).count(), records);
+
+    with_device(|host, dev| {
+        let config = enumerate(host, dev);
+        let ep = hid_endpoint(&config).address & 0x0f;
+        set_protocol(host, dev, Protocol::Report);
+
+        let x = mouse::DESCRIPTOR.field(
+            report::ReportType::Input,
+            MOUSE_ID,
+            page::GENERIC_DESKTOP,
+            desktop::X,
+        );
+        let y = mouse::DESCRIPTOR.field(
+            report::ReportType::Input,
+            MOUSE_ID,
+            page::GENERIC_DESKTOP,
+            desktop::Y,
+        );
+        let mut filter = Filter::new(FilterConfig::DEFAULT);
+        let mut pipeline = Pipeline::new(Curve::NONE, 1.0, 16000);
+        let mut motion = Accumulator::new();
+        let mut replay = Replay::new(&data);
+        let (mut expected, mut received) = ((0i64, 0i64), (0i64, 0i64));
+        let mut buf = [0; mouse::REPORT_SIZE];
+        while let Some((burst, dt_us)) = replay.next_burst(1000) {
+            let (dx, dy) = filter.process(&burst, dt_us);
+            if !filter.is_gated() {
+                expected = (expected.0 + burst.dx as i64, expected.1 + burst.dy as i64);
+            }
+            let (dx, dy) = pipeline.process(dx, dy, dt_us);
+            motion.add(dx, dy);
+            if !motion.pending() {
+                continue;
+            }
+
+            let (dx, dy) = motion.peek_i16();
+            let report = MouseReport::new().motion(dx, dy);
+            dev.hid
+                .write(report.serialize(Protocol::Report, &mut buf))
+                .unwrap();
+            motion.consume(dx, dy);
+            let packet = host.read(&mut || dev.poll(), ep).expect("no report");
+            let field = |f: report::Field| {
+                let i = f.offset as usize / 8;
+                i16::from_le_bytes([packet[i], packet[i + 1]]) as i64
+            };
+            received = (received.0 + field(x), received.1 + field(y));
+        }
+        assert_eq!(received, expected);
+    });
+}
@@ -3,7 +3,10 @@ MEMORY
   /* NOTE 1 K = 1 KiBi = 1024 bytes */
   /* TODO Adjust these memory regions to match your device memory layout */
   /* These values correspond to the STM32F411 */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
+  /* Sectors 0 to 5 */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  /* Sectors 6 and 7, reserved for the settings store (src/flash.rs) */
+  SETTINGS : ORIGIN = 0x08040000, LENGTH = 256K
   RAM : ORIGIN = 0x20000000, LENGTH = 64K
 }
 
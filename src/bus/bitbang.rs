@@ -0,0 +1,158 @@
+//! Bit-banged (software) SPI master over general purpose IOs
+//!
+//! Useful for bring-up on boards where no SPI peripheral is free. The clock
+//! rate is set by the half period in microseconds, so with `DwtDelay` the
+//! highest rate is 500 kHz (the PMW3389 accepts anything up to 2 MHz).
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::spi::{Mode, Phase, Polarity};
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// Failed to drive or sample one of the pins
+    Pin,
+}
+
+pub struct BitBang<SCK, MOSI, MISO, NCS, D> {
+    sck: SCK,
+    mosi: MOSI,
+    miso: MISO,
+    ncs: NCS,
+    delay: D,
+    mode: Mode,
+    half_period_us: u32,
+}
+
+impl<SCK, MOSI, MISO, NCS, D> BitBang<SCK, MOSI, MISO, NCS, D>
+where
+    SCK: OutputPin,
+    MOSI: OutputPin,
+    MISO: InputPin,
+    NCS: OutputPin,
+    D: DelayUs<u32>,
+{
+    /// Creates a new software SPI, MSB first
+    ///
+    /// `half_period_us` is the time SCK spends in each level, i.e., the
+    /// clock rate is `1 / (2 * half_period_us)` MHz.
+    pub fn new(
+        sck: SCK,
+        mosi: MOSI,
+        miso: MISO,
+        ncs: NCS,
+        delay: D,
+        mode: Mode,
+        half_period_us: u32,
+    ) -> Self {
+        let mut spi = BitBang {
+            sck,
+            mosi,
+            miso,
+            ncs,
+            delay,
+            mode,
+            half_period_us,
+        };
+
+        // idle levels, no transaction in progress
+        spi.sck_idle().ok();
+        spi.ncs.set_high().ok();
+        spi
+    }
+
+    /// Releases the pins and the delay provider
+    pub fn free(self) -> (SCK, MOSI, MISO, NCS, D) {
+        (self.sck, self.mosi, self.miso, self.ncs, self.delay)
+    }
+
+    fn sck_idle(&mut self) -> Result<(), Error> {
+        match self.mode.polarity {
+            Polarity::IdleLow => self.sck.set_low(),
+            Polarity::IdleHigh => self.sck.set_high(),
+        }
+        .map_err(|_| Error::Pin)
+    }
+
+    fn sck_active(&mut self) -> Result<(), Error> {
+        match self.mode.polarity {
+            Polarity::IdleLow => self.sck.set_high(),
+            Polarity::IdleHigh => self.sck.set_low(),
+        }
+        .map_err(|_| Error::Pin)
+    }
+
+    fn write_bit(&mut self, bit: bool) -> Result<(), Error> {
+        if bit {
+            self.mosi.set_high()
+        } else {
+            self.mosi.set_low()
+        }
+        .map_err(|_| Error::Pin)
+    }
+
+    fn read_bit(&mut self) -> Result<bool, Error> {
+        self.miso.is_high().map_err(|_| Error::Pin)
+    }
+
+    fn transfer_byte(&mut self, out: u8) -> Result<u8, Error> {
+        let mut data = 0u8;
+        for bit in (0..8).rev() {
+            let b = out & (1 << bit) != 0;
+            match self.mode.phase {
+                // data is sampled on the leading (first) clock edge
+                Phase::CaptureOnFirstTransition => {
+                    self.write_bit(b)?;
+                    self.delay.delay_us(self.half_period_us);
+                    self.sck_active()?;
+                    data = data << 1 | self.read_bit()? as u8;
+                    self.delay.delay_us(self.half_period_us);
+                    self.sck_idle()?;
+                }
+                // data is shifted out on the leading, and sampled on the trailing edge
+                Phase::CaptureOnSecondTransition => {
+                    self.sck_active()?;
+                    self.write_bit(b)?;
+                    self.delay.delay_us(self.half_period_us);
+                    self.sck_idle()?;
+                    data = data << 1 | self.read_bit()? as u8;
+                    self.delay.delay_us(self.half_period_us);
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+impl<SCK, MOSI, MISO, NCS, D> Transfer<u8> for BitBang<SCK, MOSI, MISO, NCS, D>
+where
+    SCK: OutputPin,
+    MOSI: OutputPin,
+    MISO: InputPin,
+    NCS: OutputPin,
+    D: DelayUs<u32>,
+{
+    type Error = Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        for word in words.iter_mut() {
+            *word = self.transfer_byte(*word)?;
+        }
+        Ok(words)
+    }
+}
+
+impl<SCK, MOSI, MISO, NCS, D> OutputPin for BitBang<SCK, MOSI, MISO, NCS, D>
+where
+    NCS: OutputPin,
+{
+    type Error = NCS::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.ncs.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.ncs.set_high()
+    }
+}
@@ -0,0 +1,115 @@
+//! SPI bus adapters
+//!
+//! The `Pmw3389e` driver talks to a single bus type implementing both
+//! `Transfer<u8>` (the data) and `OutputPin` (the NCS line). The adapters
+//! in this module present different ways of reaching the sensor through
+//! that same interface:
+//!
+//! - `SpiCs`, any SPI peripheral combined with any output pin as NCS,
+//!   e.g., the STM32 SPI2 and PB4, or a Linux `spidev` and a sysfs GPIO.
+//! - `bitbang::BitBang`, a software SPI over any GPIOs, timed by a delay
+//!   provider such as `DwtDelay`.
+//! - `sc18is602::SH18IS602`, the SC18IS602 I2C to SPI bridge.
+use embedded_hal::blocking::spi::Transfer;
+use embedded_hal::digital::v2::OutputPin;
+
+pub mod bitbang;
+pub mod sc18is602;
+
+/// Power management of the bus itself (e.g., an I2C-to-SPI bridge)
+///
+/// Implemented by bus bridges that can be put in a low power mode together
+/// with the sensor. The bus is expected to wake up on its next access, so
+/// `wake` only needs to be called explicitly to avoid the wake-up latency
+/// on the first transfer.
+pub trait Sleep {
+    type Error;
+
+    fn sleep(&mut self) -> Result<(), Self::Error>;
+    fn wake(&mut self) -> Result<(), Self::Error>;
+}
+
+/// An SPI bus with a dedicated chip select (NCS) pin
+pub struct SpiCs<SPI, CS> {
+    spi: SPI,
+    cs: CS,
+}
+
+impl<SPI, CS> SpiCs<SPI, CS>
+where
+    CS: OutputPin,
+{
+    /// Combines the `spi` bus and the `cs` pin, the pin is driven high (inactive)
+    pub fn new(spi: SPI, mut cs: CS) -> Self {
+        cs.set_high().ok();
+        SpiCs { spi, cs }
+    }
+
+    /// Releases the bus and pin
+    pub fn free(self) -> (SPI, CS) {
+        (self.spi, self.cs)
+    }
+}
+
+impl<SPI, CS> Transfer<u8> for SpiCs<SPI, CS>
+where
+    SPI: Transfer<u8>,
+{
+    type Error = SPI::Error;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        self.spi.transfer(words)
+    }
+}
+
+impl<SPI, CS> OutputPin for SpiCs<SPI, CS>
+where
+    CS: OutputPin,
+{
+    type Error = CS::Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.cs.set_low()
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.cs.set_high()
+    }
+}
+
+/// A Linux `spidev` device, with a sysfs GPIO as NCS
+///
+/// The `spidev` chip select toggles on each transfer, which breaks the split
+/// (address, delay, data) transactions of the sensor. Open the device with
+/// `SPI_NO_CS` (or leave its CS unconnected) and wire NCS to a GPIO instead.
+#[cfg(feature = "linux")]
+pub type Spidev = SpiCs<linux_embedded_hal::Spidev, linux_embedded_hal::Pin>;
+
+#[cfg(feature = "linux")]
+impl Spidev {
+    /// Opens `path` (e.g., `/dev/spidev0.0`) in SPI mode 3, using the sysfs
+    /// GPIO number `ncs` as chip select
+    pub fn open(path: &str, ncs: u64, speed_hz: u32) -> std::io::Result<Self> {
+        use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+        use linux_embedded_hal::sysfs_gpio::Direction;
+
+        let mut spi = linux_embedded_hal::Spidev::open(path)?;
+        let options = SpidevOptions::new()
+            .bits_per_word(8)
+            .max_speed_hz(speed_hz)
+            .mode(SpiModeFlags::SPI_MODE_3 | SpiModeFlags::SPI_NO_CS)
+            .build();
+        spi.configure(&options)?;
+
+        let pin = linux_embedded_hal::Pin::new(ncs);
+        pin.export().map_err(to_io)?;
+        pin.set_direction(Direction::High).map_err(to_io)?;
+
+        Ok(SpiCs::new(spi, pin))
+    }
+}
+
+#[cfg(feature = "linux")]
+fn to_io(e: linux_embedded_hal::sysfs_gpio::Error) -> std::io::Error {
+    std::io::Error::other(e)
+}
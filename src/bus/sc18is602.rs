@@ -0,0 +1,319 @@
+//! SC18IS602 I2C to SPI bridge
+//!
+//! The SS0 slave select can be managed by the bridge itself, or used as a
+//! general purpose output (`gpio = true`). In the latter case the bridge
+//! implements both `Transfer<u8>` and `OutputPin`, allowing split
+//! transactions as needed by the PMW3389.
+//!
+//! The bridge does not answer its address while a SPI transfer is in
+//! progress, so rather than waiting a fixed time the result is polled
+//! (after the expected transfer time), with a timeout in case the bridge
+//! does not answer at all. The delay provider must thus also be a
+//! `CountDown` (`DwtDelay` on the target, `time::StdTimer` on a host).
+
+pub enum Function {
+    SpiReadWrite = 0x00, // 0F..01, where lowest 4 bits are the CSs
+    SpiConfigure = 0xF0,
+    ClearInterrupt = 0xF1,
+    IdleMode = 0xF2,
+    GpioWrite = 0xF4,
+    GpioRead = 0xF5,
+    GpioEnable = 0xF6,
+    GpioConfigure = 0xF7,
+}
+
+impl Function {
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+}
+
+pub enum Speed {
+    Speed1843kHz = 0b00,
+    Speed461kHz = 0b01,
+    Speed115kHz = 0b10,
+    Speed58kHz = 0b11,
+}
+
+impl Speed {
+    // the time to shift a byte, in us (rounded up)
+    fn byte_us(&self) -> u64 {
+        match self {
+            Speed::Speed1843kHz => 5,
+            Speed::Speed461kHz => 18,
+            Speed::Speed115kHz => 70,
+            Speed::Speed58kHz => 138,
+        }
+    }
+}
+
+pub enum Order {
+    MsbFirst = 0b0,
+    MsbLast = 0b1,
+}
+
+#[allow(dead_code)]
+enum GpioMode {
+    QuasiBiDirectional = 0b00,
+    PushPull = 0b01,
+    InputOnly = 0b10,
+    OpenDrain = 0b11,
+}
+
+impl GpioMode {
+    fn val(self) -> u8 {
+        self as u8
+    }
+}
+
+use embedded_hal::{
+    blocking::{delay::DelayUs, i2c, spi::Transfer},
+    digital::v2::OutputPin,
+    spi::Mode,
+    timer::CountDown,
+};
+
+use super::Sleep;
+use crate::time::{self, Duration, TimeoutError};
+
+// the bridge overhead (I2C to SPI) of a transfer, in us
+const OVERHEAD_US: u64 = 20;
+// the time the bridge may take to answer beyond the expected, in us
+const TIMEOUT_US: u64 = 10_000;
+// the data buffer of the bridge, the function ID and the SPI data
+const BUFFER_SIZE: usize = 200;
+
+/// The longest transfer, in bytes
+pub const MAX_TRANSFER: usize = BUFFER_SIZE - 1;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    NotConfigured,
+    /// The I2C bus failed
+    I2c,
+    /// The bridge did not answer in time
+    Timeout,
+    /// The transfer is longer than `MAX_TRANSFER`
+    TooLong,
+}
+
+pub struct SH18IS602<I2C, D>
+where
+    I2C: i2c::Write + i2c::Read,
+{
+    addr: u8,
+    gpio: bool,
+    idle: bool,
+    i2c: I2C,
+    delay: D,
+    byte_us: u64,
+    // a backing buffer for shadowing SPI transfers
+    buff: [u8; BUFFER_SIZE],
+}
+
+use Function::*;
+
+impl<I2C, D> SH18IS602<I2C, D>
+where
+    I2C: i2c::Write + i2c::Read,
+    D: DelayUs<u32> + CountDown<Time = Duration>,
+{
+    pub fn new(
+        i2c: I2C,
+        delay: D,
+        addr: u8,
+        order: Order,
+        mode: Mode,
+        speed: Speed,
+        gpio: bool,
+    ) -> Result<SH18IS602<I2C, D>, Error> {
+        let addr = (0x50 + addr) >> 1;
+        // set configuration
+        let mut device = SH18IS602 {
+            addr,
+            gpio,
+            idle: false,
+            i2c,
+            delay,
+            byte_us: speed.byte_us(),
+            buff: [0; BUFFER_SIZE],
+        };
+
+        // configure
+        // 7:6 -     reserved
+        // 5   ORDER logic 0, the MSB of the data word is transmitted first.
+        //           logic 1, the LSB of the data word is transmitted first.
+        // 4   -     reserved
+        // 3:3 M1:M0 Mode selection
+        //           00 - SPICLK LOW when idle; data clocked in on leading edge (CPOL = 0, CPHA = 0)
+        //           01 - SPICLK LOW when idle; data clocked in on trailing edge (CPOL = 0, CPHA = 1)
+        //           10 - SPICLK HIGH when idle; data clocked in on trailing edge (CPOL = 1, CPHA = 0)
+        //           11 - SPICLK HIGH when idle; data clocked in on leading edge (CPOL = 1, CPHA = 1)
+        // 1:0 F1:F0 SPI clock rate
+        //           00 - 1843 kHz
+        //           01 - 461 kHz
+        //           10 - 115 kHz
+        //           11 - 58 kHz
+
+        let cfg =
+            (order as u8) << 5 | (mode.polarity as u8) << 3 | (mode.phase as u8) << 2 | speed as u8;
+
+        device
+            .i2c
+            .write(addr, &[SpiConfigure.id(), cfg])
+            .map_err(|_| Error::I2c)?;
+
+        if gpio {
+            device.set_ss0_gpio()?;
+        } else {
+            device.set_ss0_hw()?;
+        }
+
+        Ok(device)
+    }
+
+    pub fn set_ss0_gpio(&mut self) -> Result<(), Error> {
+        // Configure SS0 as GPIO
+        self.i2c
+            .write(self.addr, &[GpioEnable.id(), 0x1])
+            .map_err(|_| Error::I2c)?;
+
+        // Configure GPIO SS0 as a PushPull Output
+        self.i2c
+            .write(self.addr, &[GpioConfigure.id(), GpioMode::PushPull.val()])
+            .map_err(|_| Error::I2c)?;
+
+        // Set the SS0 to high out of transaction (idle)
+        self.gpio = true;
+        self.set_high()
+    }
+
+    pub fn set_ss0_hw(&mut self) -> Result<(), Error> {
+        // Configure SS0 as managed by HW
+        self.i2c
+            .write(self.addr, &[GpioEnable.id(), 0x0])
+            .map_err(|_| Error::I2c)?;
+        self.gpio = false;
+        Ok(())
+    }
+
+    /// Puts the bridge in Idle (power-down) mode
+    ///
+    /// SPI configuration and GPIO state are retained.
+    pub fn sleep(&mut self) -> Result<(), Error> {
+        if !self.idle {
+            self.i2c
+                .write(self.addr, &[IdleMode.id()])
+                .map_err(|_| Error::I2c)?;
+            self.idle = true;
+        }
+        Ok(())
+    }
+
+    /// Wakes the bridge from Idle mode
+    ///
+    /// Any access to the slave address wakes the device, we use the
+    /// (harmless) clear interrupt function for that purpose. Called
+    /// implicitly before each transfer and GPIO access.
+    pub fn wake(&mut self) -> Result<(), Error> {
+        if self.idle {
+            self.i2c
+                .write(self.addr, &[ClearInterrupt.id()])
+                .map_err(|_| Error::I2c)?;
+            // Allow the internal oscillator to start
+            self.delay.delay_us(60);
+            self.idle = false;
+        }
+        Ok(())
+    }
+}
+
+impl<I2C, D> Sleep for SH18IS602<I2C, D>
+where
+    I2C: i2c::Write + i2c::Read,
+    D: DelayUs<u32> + CountDown<Time = Duration>,
+{
+    type Error = Error;
+
+    fn sleep(&mut self) -> Result<(), Self::Error> {
+        SH18IS602::sleep(self)
+    }
+
+    fn wake(&mut self) -> Result<(), Self::Error> {
+        SH18IS602::wake(self)
+    }
+}
+
+impl<I2C, D> Transfer<u8> for SH18IS602<I2C, D>
+where
+    I2C: i2c::Write + i2c::Read,
+    D: DelayUs<u32> + CountDown<Time = Duration>,
+{
+    type Error = Error;
+
+    // at most `MAX_TRANSFER` bytes, `Error::TooLong` otherwise
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Self::Error> {
+        if words.len() > MAX_TRANSFER {
+            return Err(Error::TooLong);
+        }
+        self.wake()?;
+
+        // initiate a transfer (function ID 0x02, SS1), the SPI is disabled
+        // on a slave select configured as GPIO, as SS0 is for the split
+        // transactions
+        self.buff[0] = 0x02;
+        self.buff[1..words.len() + 1].clone_from_slice(words);
+
+        self.i2c
+            .write(self.addr, &self.buff[0..words.len() + 1])
+            .map_err(|_| Error::I2c)?;
+
+        // Wait for the SPI transfer, then poll for the result, the bridge
+        // does not acknowledge its address until the transfer is done
+        // For improved performance use write if result is not needed
+        let expected = Duration::from_micros(OVERHEAD_US + words.len() as u64 * self.byte_us);
+        self.delay.start(expected);
+        nb::block!(self.delay.wait()).ok();
+
+        let (i2c, addr) = (&mut self.i2c, self.addr);
+        time::poll_until(&mut self.delay, Duration::from_micros(TIMEOUT_US), || {
+            i2c.read(addr, words).map_err(|_| nb::Error::WouldBlock)
+        })
+        .map_err(|_: TimeoutError<()>| Error::Timeout)?;
+
+        Ok(words)
+    }
+}
+
+impl<I2C, D> OutputPin for SH18IS602<I2C, D>
+where
+    I2C: i2c::Write + i2c::Read,
+    D: DelayUs<u32> + CountDown<Time = Duration>,
+{
+    type Error = Error;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        if !self.gpio {
+            Err(Error::NotConfigured)
+        } else {
+            self.wake()?;
+            self.i2c
+                .write(self.addr, &[Function::GpioWrite.id(), 0x0])
+                .map_err(|_| Error::I2c)?;
+            self.delay.delay_us(6_000);
+            Ok(())
+        }
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        if !self.gpio {
+            Err(Error::NotConfigured)
+        } else {
+            self.wake()?;
+            self.i2c
+                .write(self.addr, &[Function::GpioWrite.id(), 0x1])
+                .map_err(|_| Error::I2c)?;
+            Ok(())
+        }
+    }
+}
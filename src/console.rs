@@ -0,0 +1,243 @@
+//! Text console commands
+//!
+//! The command language of the serial console (e.g., the CDC-ACM port of
+//! the USB mouse), one command per line.
+use crate::settings::{Binding, Lift, Settings, SEQUENCE};
+use crate::usb::keyboard::{consumer, key, modifier};
+
+pub const HELP: &str = "\
+commands:
+  help            this text
+  settings        show the settings
+  diag            sensor diagnostics
+  cpi <n>         resolution, 50..16000
+  lift <2|3>      lift-off distance in mm
+  snap <on|off>   angle snapping
+  rest <on|off>   sensor rest mode
+  polling <ms>    report interval, 1..255
+  swap <on|off>   swap the sensor x and y axes
+  invertx <on|off>, inverty <on|off>
+                  invert a sensor axis (after swapping)
+  liftsqual <n>   lift calibration, drop motion below this SQUAL
+  liftshutter <n> lift calibration, drop motion above this shutter, 0 off
+  trace <on|off>  stream motion records (binary, see app::trace)
+  bind <back|forward> <binding>
+                  side button binding, one of
+                    button        the mouse button
+                    copy, cut, paste, undo, redo
+                    play, next, prev, stop, mute, volup, voldown
+                    keys <mm:kk>..  up to 3 key combinations (hex
+                                  modifiers:usage), e.g., keys 01:06
+                    media <usage>   consumer usage (hex), e.g., media cd
+";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    Help,
+    Show,
+    Diag,
+    /// Streaming of motion records on the console
+    Trace(bool),
+    Set(Setting),
+}
+
+/// A change to a single setting
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    Cpi(u16),
+    Lift(Lift),
+    AngleSnap(bool),
+    Rest(bool),
+    Polling(u8),
+    Swap(bool),
+    InvertX(bool),
+    InvertY(bool),
+    LiftSqual(u8),
+    LiftShutter(u16),
+    Back(Binding),
+    Forward(Binding),
+}
+
+impl Setting {
+    /// The settings with the change applied, `None` if out of range
+    pub fn apply(self, settings: &Settings) -> Option<Settings> {
+        let mut new = *settings;
+        match self {
+            Setting::Cpi(cpi) => new.cpi = cpi,
+            Setting::Lift(lift) => new.lift = lift,
+            Setting::AngleSnap(on) => new.angle_snap = on,
+            Setting::Rest(on) => new.rest = on,
+            Setting::Polling(ms) => new.polling_ms = ms,
+            Setting::Swap(on) => new.orientation.swap = on,
+            Setting::InvertX(on) => new.orientation.invert_x = on,
+            Setting::InvertY(on) => new.orientation.invert_y = on,
+            Setting::LiftSqual(squal) => new.lift_squal = squal,
+            Setting::LiftShutter(shutter) => new.lift_shutter = shutter,
+            Setting::Back(binding) => new.back = binding,
+            Setting::Forward(binding) => new.forward = binding,
+        }
+        // validated by the round trip through the settings format
+        Settings::from_bytes(&new.to_bytes())
+    }
+}
+
+/// Parses a command line, an empty line is `Ok(None)`
+pub fn parse(line: &str) -> Result<Option<Command>, &'static str> {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        None => return Ok(None),
+        Some(command) => command,
+    };
+    if command == "bind" {
+        let setting = match words.next() {
+            Some("back") => Setting::Back(parse_binding(words)?),
+            Some("forward") => Setting::Forward(parse_binding(words)?),
+            _ => return Err("expected back or forward"),
+        };
+        return Ok(Some(Command::Set(setting)));
+    }
+    let arg = words.next();
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+
+    let command = match (command, arg) {
+        ("help", None) => Command::Help,
+        ("settings", None) => Command::Show,
+        ("diag", None) => Command::Diag,
+        ("trace", Some(b)) => Command::Trace(on_off(b)?),
+        ("cpi", Some(n)) => Command::Set(Setting::Cpi(n.parse().map_err(|_| "invalid cpi")?)),
+        ("lift", Some("2")) => Command::Set(Setting::Lift(Lift::Mm2)),
+        ("lift", Some("3")) => Command::Set(Setting::Lift(Lift::Mm3)),
+        ("snap", Some(b)) => Command::Set(Setting::AngleSnap(on_off(b)?)),
+        ("rest", Some(b)) => Command::Set(Setting::Rest(on_off(b)?)),
+        ("polling", Some(ms)) => Command::Set(Setting::Polling(
+            ms.parse().map_err(|_| "invalid polling interval")?,
+        )),
+        ("swap", Some(b)) => Command::Set(Setting::Swap(on_off(b)?)),
+        ("invertx", Some(b)) => Command::Set(Setting::InvertX(on_off(b)?)),
+        ("inverty", Some(b)) => Command::Set(Setting::InvertY(on_off(b)?)),
+        ("liftsqual", Some(n)) => {
+            Command::Set(Setting::LiftSqual(n.parse().map_err(|_| "invalid SQUAL")?))
+        }
+        ("liftshutter", Some(n)) => Command::Set(Setting::LiftShutter(
+            n.parse().map_err(|_| "invalid shutter")?,
+        )),
+        _ => return Err("unknown command, try help"),
+    };
+    Ok(Some(command))
+}
+
+/// Parses a button binding, the words following `bind <button>`
+pub fn parse_binding<'a>(
+    mut words: impl Iterator<Item = &'a str>,
+) -> Result<Binding, &'static str> {
+    let ctrl = |k| Binding::Keys([(modifier::LEFT_CTRL, k), (0, 0), (0, 0)]);
+    let binding = match words.next() {
+        Some("button") => Binding::Button,
+        Some("copy") => ctrl(key::C),
+        Some("cut") => ctrl(key::X),
+        Some("paste") => ctrl(key::V),
+        Some("undo") => ctrl(key::Z),
+        Some("redo") => ctrl(key::Y),
+        Some("play") => Binding::Consumer(consumer::PLAY_PAUSE),
+        Some("next") => Binding::Consumer(consumer::NEXT_TRACK),
+        Some("prev") => Binding::Consumer(consumer::PREVIOUS_TRACK),
+        Some("stop") => Binding::Consumer(consumer::STOP),
+        Some("mute") => Binding::Consumer(consumer::MUTE),
+        Some("volup") => Binding::Consumer(consumer::VOLUME_UP),
+        Some("voldown") => Binding::Consumer(consumer::VOLUME_DOWN),
+        Some("media") => {
+            let usage = words
+                .next()
+                .and_then(|u| u16::from_str_radix(u, 16).ok())
+                .filter(|u| *u != 0)
+                .ok_or("invalid usage")?;
+            Binding::Consumer(usage)
+        }
+        Some("keys") => {
+            let mut keys = [(0, 0); SEQUENCE];
+            for k in keys.iter_mut() {
+                let combination = match words.next() {
+                    Some(c) => c,
+                    None => break,
+                };
+                *k = parse_combination(combination).ok_or("invalid keys, expected mm:kk")?;
+            }
+            if keys[0].1 == 0 {
+                return Err("invalid keys, expected mm:kk");
+            }
+            Binding::Keys(keys)
+        }
+        _ => return Err("unknown binding, try help"),
+    };
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+    Ok(binding)
+}
+
+// modifiers and key usage in hex, `mm:kk`
+fn parse_combination(s: &str) -> Option<(u8, u8)> {
+    let mut parts = s.splitn(2, ':');
+    let modifiers = u8::from_str_radix(parts.next()?, 16).ok()?;
+    let key = u8::from_str_radix(parts.next()?, 16).ok()?;
+    if key == 0 {
+        return None;
+    }
+    Some((modifiers, key))
+}
+
+fn on_off(s: &str) -> Result<bool, &'static str> {
+    match s {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err("expected on or off"),
+    }
+}
+
+/// Line editing, collects characters until end of line
+pub struct LineBuffer {
+    buf: [u8; 64],
+    len: usize,
+}
+
+impl LineBuffer {
+    pub const fn new() -> Self {
+        LineBuffer {
+            buf: [0; 64],
+            len: 0,
+        }
+    }
+
+    /// Adds a received character, returns the line at end of line
+    ///
+    /// Backspace removes the last character, characters beyond the line
+    /// length are dropped.
+    pub fn push(&mut self, c: u8) -> Option<&str> {
+        match c {
+            b'\r' | b'\n' => {
+                let len = self.len;
+                self.len = 0;
+                Some(core::str::from_utf8(&self.buf[..len]).unwrap_or(""))
+            }
+            0x08 | 0x7f => {
+                self.len = self.len.saturating_sub(1);
+                None
+            }
+            _ => {
+                if self.len < self.buf.len() {
+                    self.buf[self.len] = c;
+                    self.len += 1;
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Default for LineBuffer {
+    fn default() -> Self {
+        LineBuffer::new()
+    }
+}
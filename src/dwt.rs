@@ -0,0 +1,54 @@
+//! Delays and count downs based on the DWT cycle counter
+//!
+//! See `time`, the delays are computed in 64 bits, so any `u32` number of
+//! us or ms is exact. `DwtDelay` is also a periodic `CountDown` (a
+//! `time::Timer`), so drivers can wait on hardware with a timeout (see
+//! `time::poll_until`) through the same delay provider.
+use crate::time::{self, Duration, Timer};
+use embedded_hal::timer::{CountDown, Periodic};
+use stm32f4xx_hal::{prelude::*, rcc::Clocks, stm32};
+use void::Void;
+
+#[derive(Clone, Copy)]
+pub struct DwtDelay {
+    timer: Timer,
+}
+
+impl DwtDelay {
+    /// Enables the cycle counter, timed by HCLK (see `time::init`)
+    pub fn new(dwt: &mut stm32::DWT, clocks: Clocks) -> DwtDelay {
+        time::init(dwt, &clocks);
+        Self {
+            timer: Timer::new(),
+        }
+    }
+}
+
+impl _embedded_hal_blocking_delay_DelayUs<u32> for DwtDelay {
+    fn delay_us(&mut self, us: u32) {
+        time::delay(Duration::from_micros(us as u64));
+    }
+}
+
+impl _embedded_hal_blocking_delay_DelayMs<u32> for DwtDelay {
+    fn delay_ms(&mut self, ms: u32) {
+        time::delay(Duration::from_millis(ms as u64));
+    }
+}
+
+impl CountDown for DwtDelay {
+    type Time = Duration;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Duration>,
+    {
+        self.timer.start(count);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        self.timer.wait()
+    }
+}
+
+impl Periodic for DwtDelay {}
@@ -0,0 +1,121 @@
+//! The settings sectors of the STM32F411 flash
+//!
+//! Sectors 6 and 7 (128K each at 0x0804_0000 and 0x0806_0000), reserved as
+//! `SETTINGS` in `memory.x`. Programming is done byte by byte (PSIZE x8),
+//! valid over the whole supply voltage range.
+//!
+//! Notice, the CPU stalls on flash reads while a sector is being erased (up
+//! to some seconds), so interrupts are served late. The store only erases
+//! in `Store::erase_spare`, which should not run while latency matters
+//! (e.g., before USB is enabled).
+use crate::store::{Flash, SECTORS};
+use stm32f4xx_hal::stm32::FLASH;
+
+/// Start of the sectors, must match `memory.x`
+pub const START: [u32; SECTORS] = [0x0804_0000, 0x0806_0000];
+/// Size of a sector, the sectors are `SECTORS * SIZE` in `memory.x`
+pub const SIZE: u32 = 128 * 1024;
+// the sector numbers (SNB)
+const SECTOR: [u8; SECTORS] = [6, 7];
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xcdef_89ab;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Error {
+    /// Programming or erase failed (the SR error flags)
+    Program(u32),
+}
+
+pub struct SettingsSectors {
+    flash: FLASH,
+}
+
+impl SettingsSectors {
+    pub fn new(flash: FLASH) -> Self {
+        SettingsSectors { flash }
+    }
+
+    /// Releases the flash peripheral
+    pub fn free(self) -> FLASH {
+        self.flash
+    }
+
+    fn unlock(&mut self) {
+        if self.flash.cr.read().lock().bit_is_set() {
+            self.flash.keyr.write(|w| unsafe { w.key().bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.key().bits(KEY2) });
+        }
+    }
+
+    fn lock(&mut self) {
+        self.flash.cr.modify(|_, w| w.lock().set_bit());
+    }
+
+    // waits for the operation to complete, returning (and clearing) errors
+    fn wait(&mut self) -> Result<(), Error> {
+        while self.flash.sr.read().bsy().bit_is_set() {}
+        // PGSERR, PGPERR, PGAERR, WRPERR, OPERR
+        let errors = self.flash.sr.read().bits() & 0xf2;
+        self.flash.sr.write(|w| unsafe { w.bits(errors) });
+        if errors != 0 {
+            Err(Error::Program(errors))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Flash for SettingsSectors {
+    type Error = Error;
+
+    fn size(&self) -> u32 {
+        SIZE
+    }
+
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) -> Result<(), Error> {
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = unsafe {
+                core::ptr::read_volatile((START[sector] + offset + i as u32) as *const u8)
+            };
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), Error> {
+        self.unlock();
+        self.flash
+            .cr
+            .modify(|_, w| unsafe { w.psize().bits(0b00).pg().set_bit() });
+        let mut result = Ok(());
+        for (i, b) in data.iter().enumerate() {
+            unsafe {
+                core::ptr::write_volatile((START[sector] + offset + i as u32) as *mut u8, *b)
+            };
+            result = self.wait();
+            if result.is_err() {
+                break;
+            }
+        }
+        self.flash.cr.modify(|_, w| w.pg().clear_bit());
+        self.lock();
+        result
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        self.unlock();
+        self.flash.cr.modify(|_, w| unsafe {
+            w.psize()
+                .bits(0b00)
+                .ser()
+                .set_bit()
+                .snb()
+                .bits(SECTOR[sector])
+        });
+        self.flash.cr.modify(|_, w| w.strt().set_bit());
+        let result = self.wait();
+        self.flash.cr.modify(|_, w| w.ser().clear_bit());
+        self.lock();
+        result
+    }
+}
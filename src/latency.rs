@@ -0,0 +1,378 @@
+//! Latency and jitter of periodic (RTIC) tasks
+//!
+//! A periodic task reschedules itself at `cx.scheduled + PERIOD`, so it
+//! should start at `cx.scheduled`. It starts late when higher priority
+//! tasks or interrupts (e.g., USB) run, or other tasks of the same priority
+//! run to completion first. `TaskMonitor` records, per run, how late the
+//! task started (the lateness, its spread being the jitter) and how long it
+//! ran, in histograms, and counts the deadline misses, runs that did not
+//! complete within the period (`late + run > deadline`).
+//!
+//! Times are in cycles of the cycle counter (the RTIC monotonic), as the
+//! task period, and converted to us by the frequency for display. The
+//! histogram buckets are powers of two, bucket 0 is below `1 << SHIFT`
+//! cycles, bucket `i` from `1 << (SHIFT + i - 1)` up to `1 << (SHIFT + i)`,
+//! the last open ended.
+//!
+//! On the target, `TaskMonitor::start(cx.scheduled)` first thing in the
+//! task returns a guard that records the run when dropped.
+use core::fmt;
+
+#[cfg(feature = "stm32")]
+use rtic::cyccnt::Instant;
+
+/// The number of histogram buckets
+pub const BUCKETS: usize = 16;
+
+/// Bucket 0 is below `1 << SHIFT` cycles
+pub const SHIFT: u32 = 6;
+
+/// A histogram of times in cycles
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Histogram {
+    buckets: [u32; BUCKETS],
+    count: u32,
+    total: u64,
+    max: u32,
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Histogram {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total: 0,
+            max: 0,
+        }
+    }
+
+    /// The bucket of `cycles`
+    pub fn bucket(cycles: u32) -> usize {
+        ((32 - (cycles >> SHIFT).leading_zeros()) as usize).min(BUCKETS - 1)
+    }
+
+    /// The (exclusive) upper limit of bucket `i` in cycles, `None` for the
+    /// last
+    pub fn limit(i: usize) -> Option<u32> {
+        if i + 1 < BUCKETS {
+            Some(1 << (SHIFT as usize + i))
+        } else {
+            None
+        }
+    }
+
+    pub fn add(&mut self, cycles: u32) {
+        self.buckets[Self::bucket(cycles)] += 1;
+        self.count = self.count.saturating_add(1);
+        self.total += cycles as u64;
+        self.max = self.max.max(cycles);
+    }
+
+    /// The counts per bucket
+    pub fn buckets(&self) -> &[u32; BUCKETS] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    /// The mean cycles, 0 if none
+    pub fn mean(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total / self.count as u64) as u32
+        }
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    /// The upper limit of the bucket holding the `percent` percentile, the
+    /// max if in the last bucket
+    pub fn percentile(&self, percent: u32) -> u32 {
+        let rank = (self.count as u64 * percent.min(100) as u64).div_ceil(100);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += *n as u64;
+            if seen >= rank {
+                return Self::limit(i).map_or(self.max, |limit| limit.min(self.max));
+            }
+        }
+        self.max
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new()
+    }
+}
+
+/// The latency, run time and deadline misses of a periodic task
+#[derive(Clone, Copy, Debug)]
+pub struct TaskMonitor {
+    name: &'static str,
+    deadline: u32,
+    late: Histogram,
+    run: Histogram,
+    misses: u32,
+}
+
+impl TaskMonitor {
+    /// A monitor of the task `name`, that must complete within `deadline`
+    /// cycles of its scheduled time (usually the period)
+    pub const fn new(name: &'static str, deadline: u32) -> Self {
+        TaskMonitor {
+            name,
+            deadline,
+            late: Histogram::new(),
+            run: Histogram::new(),
+            misses: 0,
+        }
+    }
+
+    /// Records a run, started `late` cycles after the scheduled time and
+    /// running for `run` cycles
+    pub fn record(&mut self, late: u32, run: u32) {
+        self.late.add(late);
+        self.run.add(run);
+        if late.saturating_add(run) > self.deadline {
+            self.misses = self.misses.saturating_add(1);
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The time from the scheduled time to the start of the runs
+    pub fn late(&self) -> &Histogram {
+        &self.late
+    }
+
+    /// The run times
+    pub fn run(&self) -> &Histogram {
+        &self.run
+    }
+
+    /// The runs that did not complete by the deadline
+    pub fn misses(&self) -> u32 {
+        self.misses
+    }
+
+    /// Clears the records
+    pub fn reset(&mut self) {
+        *self = TaskMonitor::new(self.name, self.deadline);
+    }
+
+    /// The records, with times in us at `frequency` Hz
+    pub fn summary(&self, frequency: u32) -> Summary<'_> {
+        Summary {
+            monitor: self,
+            frequency,
+        }
+    }
+
+    /// Starts recording a run scheduled at `scheduled`, the run ends when
+    /// the guard is dropped
+    #[cfg(feature = "stm32")]
+    pub fn start(&mut self, scheduled: Instant) -> Run<'_> {
+        let started = Instant::now();
+        Run {
+            monitor: self,
+            late: (started - scheduled).as_cycles(),
+            started,
+        }
+    }
+}
+
+/// A run of a monitored task
+#[cfg(feature = "stm32")]
+#[must_use = "the run ends when the guard is dropped"]
+pub struct Run<'a> {
+    monitor: &'a mut TaskMonitor,
+    late: u32,
+    started: Instant,
+}
+
+#[cfg(feature = "stm32")]
+impl Drop for Run<'_> {
+    fn drop(&mut self) {
+        let run = self.started.elapsed().as_cycles();
+        self.monitor.record(self.late, run);
+    }
+}
+
+/// A printable summary of a `TaskMonitor`
+pub struct Summary<'a> {
+    monitor: &'a TaskMonitor,
+    frequency: u32,
+}
+
+impl Summary<'_> {
+    fn us(&self, cycles: u32) -> f32 {
+        cycles as f32 * 1e6 / self.frequency.max(1) as f32
+    }
+
+    fn histogram(&self, f: &mut fmt::Formatter<'_>, name: &str, h: &Histogram) -> fmt::Result {
+        write!(
+            f,
+            "\n  {:<4} mean {:.1} us, p99 <= {:.1} us, max {:.1} us",
+            name,
+            self.us(h.mean()),
+            self.us(h.percentile(99)),
+            self.us(h.max())
+        )?;
+        if h.count() > 0 {
+            write!(f, "\n      ")?;
+        }
+        for (i, n) in h.buckets().iter().enumerate() {
+            if *n == 0 {
+                continue;
+            }
+            match Histogram::limit(i) {
+                Some(limit) => write!(f, " <{:.1}:{}", self.us(limit), n)?,
+                None => write!(f, " more:{}", n)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let monitor = self.monitor;
+        write!(
+            f,
+            "{}: {} runs, {} deadline misses ({:.1} us)",
+            monitor.name,
+            monitor.run.count(),
+            monitor.misses,
+            self.us(monitor.deadline)
+        )?;
+        self.histogram(f, "late", &monitor.late)?;
+        self.histogram(f, "run", &monitor.run)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::format;
+
+    #[test]
+    fn buckets_are_powers_of_two() {
+        assert_eq!(Histogram::bucket(0), 0);
+        assert_eq!(Histogram::bucket((1 << SHIFT) - 1), 0);
+        assert_eq!(Histogram::bucket(1 << SHIFT), 1);
+        assert_eq!(Histogram::bucket((2 << SHIFT) - 1), 1);
+        assert_eq!(Histogram::bucket(2 << SHIFT), 2);
+        assert_eq!(Histogram::limit(0), Some(1 << SHIFT));
+        assert_eq!(Histogram::limit(1), Some(2 << SHIFT));
+
+        // the last bucket is open ended
+        let top = 1 << (SHIFT as usize + BUCKETS - 2);
+        assert_eq!(Histogram::bucket(top - 1), BUCKETS - 2);
+        assert_eq!(Histogram::limit(BUCKETS - 2), Some(top));
+        assert_eq!(Histogram::bucket(top), BUCKETS - 1);
+        assert_eq!(Histogram::bucket(u32::MAX), BUCKETS - 1);
+        assert_eq!(Histogram::limit(BUCKETS - 1), None);
+
+        let mut h = Histogram::new();
+        h.add(u32::MAX);
+        h.add(u32::MAX);
+        assert_eq!(h.buckets()[BUCKETS - 1], 2);
+        assert_eq!(h.mean(), u32::MAX);
+        assert_eq!(h.max(), u32::MAX);
+        assert_eq!(h.percentile(50), u32::MAX);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut h = Histogram::new();
+        assert_eq!(h.percentile(99), 0);
+        assert_eq!(h.mean(), 0);
+
+        for _ in 0..90 {
+            h.add(10);
+        }
+        for _ in 0..10 {
+            h.add(100);
+        }
+        assert_eq!(h.count(), 100);
+        assert_eq!(h.mean(), 19);
+        assert_eq!(h.buckets()[0], 90);
+        assert_eq!(h.buckets()[1], 10);
+        // the upper limit of the bucket
+        assert_eq!(h.percentile(50), 1 << SHIFT);
+        assert_eq!(h.percentile(90), 1 << SHIFT);
+        // but not above the max
+        assert_eq!(h.percentile(91), 100);
+        assert_eq!(h.percentile(100), 100);
+        assert_eq!(h.percentile(200), 100);
+    }
+
+    #[test]
+    fn lateness_and_misses() {
+        let mut monitor = TaskMonitor::new("poll", 1000);
+        monitor.record(0, 1000);
+        assert_eq!(monitor.misses(), 0);
+        monitor.record(1, 1000);
+        assert_eq!(monitor.misses(), 1);
+        monitor.record(u32::MAX, 5);
+        assert_eq!(monitor.misses(), 2);
+        monitor.record(2000, 0);
+        assert_eq!(monitor.misses(), 3);
+
+        assert_eq!(monitor.late().count(), 4);
+        assert_eq!(monitor.late().max(), u32::MAX);
+        assert_eq!(monitor.late().buckets()[0], 2);
+        assert_eq!(monitor.run().count(), 4);
+        assert_eq!(monitor.run().max(), 1000);
+
+        monitor.reset();
+        assert_eq!(monitor.name(), "poll");
+        assert_eq!(monitor.misses(), 0);
+        assert_eq!(monitor.late().count(), 0);
+        monitor.record(1, 1000);
+        assert_eq!(monitor.misses(), 1);
+    }
+
+    #[test]
+    fn summary() {
+        let mut monitor = TaskMonitor::new("poll", 1000);
+        // 1 cycle per us
+        assert_eq!(
+            format!("{}", monitor.summary(1_000_000)),
+            "poll: 0 runs, 0 deadline misses (1000.0 us)\
+             \n  late mean 0.0 us, p99 <= 0.0 us, max 0.0 us\
+             \n  run  mean 0.0 us, p99 <= 0.0 us, max 0.0 us"
+        );
+
+        monitor.record(10, 100);
+        monitor.record(100, 950);
+        assert_eq!(
+            format!("{}", monitor.summary(1_000_000)),
+            "poll: 2 runs, 1 deadline misses (1000.0 us)\
+             \n  late mean 55.0 us, p99 <= 100.0 us, max 100.0 us\
+             \n       <64.0:1 <128.0:1\
+             \n  run  mean 525.0 us, p99 <= 950.0 us, max 950.0 us\
+             \n       <128.0:1 <1024.0:1"
+        );
+
+        // 2 cycles per us
+        monitor.reset();
+        monitor.record(1 << 21, 0);
+        assert_eq!(
+            format!("{}", monitor.summary(2_000_000)),
+            "poll: 1 runs, 1 deadline misses (500.0 us)\
+             \n  late mean 1048576.0 us, p99 <= 1048576.0 us, max 1048576.0 us\
+             \n       more:1\
+             \n  run  mean 0.0 us, p99 <= 0.0 us, max 0.0 us\
+             \n       <32.0:1"
+        );
+    }
+}
@@ -1,38 +1,54 @@
 #![no_std]
 
-pub mod pmw3389;
-pub mod pmw3389e;
-
-use stm32f4xx_hal::{prelude::*, rcc::Clocks, stm32};
-
-pub struct DwtDelay {
-    clocks: Clocks,
-}
-
-impl DwtDelay {
-    pub fn new(dwt: &mut stm32::DWT, clocks: Clocks) -> DwtDelay {
-        // required on Cortex-M7 devices that software lock the DWT (e.g. STM32F7)
-        stm32::DWT::unlock();
-        dwt.enable_cycle_counter();
-        Self { clocks }
+#[cfg(feature = "std")]
+extern crate std;
+
+// Driver tracing, over RTT on the target, and to stderr on a (std) host build
+// (leaving stdout to the application).
+mod log {
+    #[cfg(feature = "stm32")]
+    pub(crate) use rtt_target::rprintln;
+
+    #[cfg(all(not(feature = "stm32"), feature = "std"))]
+    macro_rules! rprintln {
+        ($($arg:tt)*) => {
+            std::eprintln!($($arg)*)
+        };
     }
-}
 
-impl _embedded_hal_blocking_delay_DelayUs<u32> for DwtDelay {
-    fn delay_us(&mut self, us: u32) {
-        let freq_m_hertz = self.clocks.hclk().0 / 1_000_000;
-
-        let start = stm32::DWT::get_cycle_count() as i32;
-        let end = start.wrapping_add((us * freq_m_hertz) as i32);
-
-        while (stm32::DWT::get_cycle_count() as i32).wrapping_sub(end) < 0 {
-            // this nop should be ok as the `DWT::get_cycle_count() provides side effects
-        }
+    #[cfg(all(not(feature = "stm32"), not(feature = "std")))]
+    macro_rules! rprintln {
+        ($($arg:tt)*) => {{
+            let _ = core::format_args!($($arg)*);
+        }};
     }
-}
 
-impl _embedded_hal_blocking_delay_DelayMs<u32> for DwtDelay {
-    fn delay_ms(&mut self, ms: u32) {
-        self.delay_us(ms * 1000)
-    }
+    #[cfg(not(feature = "stm32"))]
+    pub(crate) use rprintln;
 }
+pub(crate) use log::rprintln;
+
+pub mod bus;
+pub mod console;
+pub mod latency;
+pub mod motion;
+pub mod odometry;
+pub mod pmw3389;
+pub mod pmw3389e;
+pub mod profile;
+pub mod settings;
+pub mod store;
+pub mod time;
+pub mod trace;
+pub mod usb;
+
+#[cfg(feature = "stm32")]
+mod dwt;
+#[cfg(feature = "stm32")]
+pub mod flash;
+#[cfg(feature = "stm32")]
+pub mod power;
+#[cfg(feature = "stm32")]
+pub mod uid;
+#[cfg(feature = "stm32")]
+pub use dwt::DwtDelay;
@@ -0,0 +1,653 @@
+//! Motion processing between the sensor and the HID reports
+//!
+//! The `Filter` drops the motion of frames the sensor can not be trusted
+//! with, lifted off the surface or on a poor surface (by SQUAL and
+//! shutter), as well as jitter at rest, and optionally smooths small
+//! movements.
+//!
+//! Each sensor delta then passes the `Pipeline`, scaling it by a
+//! sensitivity and the gain of an acceleration `Curve`. The curves take the
+//! speed in mm/s, computed from the resolution (cpi) of the sensor, so the
+//! same curve feels the same at any resolution. Fractions of counts are
+//! carried over to the next delta, so slow motion is not lost to rounding.
+//!
+//! The sensor is polled faster than reports are sent, and a single sensor
+//! delta may exceed the report range. The `Accumulator` keeps the motion
+//! that has not yet been reported, so no counts are lost.
+//!
+//! All are plain computations (`no_std`, floating point by `libm`), and run
+//! on the host as well, e.g., replaying recorded motion (`motion-replay`).
+use crate::pmw3389::Burst;
+
+/// Accumulated (not yet reported) motion
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Accumulator {
+    x: i32,
+    y: i32,
+}
+
+impl Accumulator {
+    pub const fn new() -> Self {
+        Accumulator { x: 0, y: 0 }
+    }
+
+    /// Adds a sensor delta
+    pub fn add(&mut self, dx: i16, dy: i16) {
+        self.x = self.x.saturating_add(dx as i32);
+        self.y = self.y.saturating_add(dy as i32);
+    }
+
+    /// True if there is motion left to report
+    pub fn pending(&self) -> bool {
+        self.x != 0 || self.y != 0
+    }
+
+    /// The next report, as much of the accumulated motion as fits the
+    /// `i8` report range (-127..=127)
+    ///
+    /// The motion is not removed until `consume` is called, so a report
+    /// that could not be sent is simply retried.
+    pub fn peek_i8(&self) -> (i8, i8) {
+        (clamp_i8(self.x), clamp_i8(self.y))
+    }
+
+    /// The next report, as much of the accumulated motion as fits the
+    /// `i16` report range (-32767..=32767)
+    pub fn peek_i16(&self) -> (i16, i16) {
+        (clamp_i16(self.x), clamp_i16(self.y))
+    }
+
+    /// Removes reported motion, keeping the remainder for the next report
+    pub fn consume(&mut self, x: i16, y: i16) {
+        self.x -= x as i32;
+        self.y -= y as i32;
+    }
+
+    /// Takes the next report, `peek_i8` followed by `consume`
+    pub fn take_i8(&mut self) -> (i8, i8) {
+        let (x, y) = self.peek_i8();
+        self.consume(x as i16, y as i16);
+        (x, y)
+    }
+
+    /// Takes the next report, `peek_i16` followed by `consume`
+    pub fn take_i16(&mut self) -> (i16, i16) {
+        let (x, y) = self.peek_i16();
+        self.consume(x, y);
+        (x, y)
+    }
+}
+
+fn clamp_i8(v: i32) -> i8 {
+    if v > 127 {
+        127
+    } else if v < -127 {
+        -127
+    } else {
+        v as i8
+    }
+}
+
+fn clamp_i16(v: i32) -> i16 {
+    if v > 32767 {
+        32767
+    } else if v < -32767 {
+        -32767
+    } else {
+        v as i16
+    }
+}
+
+/// Points of a lookup table curve
+pub const LUT_SIZE: usize = 8;
+
+/// Gain by speed, linearly interpolated between up to `LUT_SIZE` points
+///
+/// Below the first point the gain of the first point is used, above the
+/// last the gain of the last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lut {
+    // (speed in mm/s, gain), by increasing speed
+    points: [(f32, f32); LUT_SIZE],
+    len: usize,
+}
+
+impl Lut {
+    /// A table of (speed in mm/s, gain) points, `None` if empty, longer
+    /// than `LUT_SIZE` or not by strictly increasing speed
+    pub fn new(points: &[(f32, f32)]) -> Option<Self> {
+        if points.is_empty() || points.len() > LUT_SIZE {
+            return None;
+        }
+        if points.windows(2).any(|w| w[1].0 <= w[0].0) {
+            return None;
+        }
+        let mut lut = Lut {
+            points: [(0.0, 0.0); LUT_SIZE],
+            len: points.len(),
+        };
+        lut.points[..points.len()].copy_from_slice(points);
+        Some(lut)
+    }
+
+    /// The points of the table
+    pub fn points(&self) -> &[(f32, f32)] {
+        &self.points[..self.len]
+    }
+
+    fn gain(&self, speed: f32) -> f32 {
+        let points = self.points();
+        let (first, last) = (points[0], points[self.len - 1]);
+        if speed <= first.0 {
+            return first.1;
+        }
+        if speed >= last.0 {
+            return last.1;
+        }
+        let i = points
+            .iter()
+            .position(|p| p.0 > speed)
+            .unwrap_or(self.len - 1);
+        let ((s0, g0), (s1, g1)) = (points[i - 1], points[i]);
+        g0 + (g1 - g0) * (speed - s0) / (s1 - s0)
+    }
+}
+
+/// Acceleration curve, the gain applied to the motion by speed (mm/s)
+///
+/// Below `offset` the gain is 1, above the gain grows with the speed, up to
+/// `cap`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    /// gain = 1 + accel * (speed - offset)
+    Linear { accel: f32, offset: f32, cap: f32 },
+    /// gain = 1 + (accel * (speed - offset)) ^ exponent
+    Power {
+        accel: f32,
+        exponent: f32,
+        offset: f32,
+        cap: f32,
+    },
+    /// gain from a lookup table
+    Table(Lut),
+}
+
+impl Curve {
+    /// No acceleration, a gain of 1 at any speed
+    pub const NONE: Curve = Curve::Linear {
+        accel: 0.0,
+        offset: 0.0,
+        cap: 1.0,
+    };
+
+    /// The gain at `speed` mm/s
+    pub fn gain(&self, speed: f32) -> f32 {
+        match *self {
+            Curve::Linear { accel, offset, cap } => {
+                (1.0 + accel * (speed - offset).max(0.0)).min(cap)
+            }
+            Curve::Power {
+                accel,
+                exponent,
+                offset,
+                cap,
+            } => (1.0 + libm::powf(accel * (speed - offset).max(0.0), exponent)).min(cap),
+            Curve::Table(ref lut) => lut.gain(speed),
+        }
+    }
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Curve::NONE
+    }
+}
+
+// 1 inch in mm
+const MM_PER_INCH: f32 = 25.4;
+
+/// Sensor motion to pointer motion, sensitivity and acceleration
+///
+/// ```ignore
+/// let mut pipeline = Pipeline::new(Curve::NONE, 1.0, settings.cpi);
+/// let (x, y) = pipeline.process(dx, dy, 1000);
+/// accumulator.add(x, y);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Pipeline {
+    curve: Curve,
+    sensitivity: f32,
+    cpi: u16,
+    // fractions of counts, carried over to the next delta
+    remainder: (f32, f32),
+}
+
+impl Pipeline {
+    /// A pipeline for a sensor at `cpi` counts per inch
+    pub const fn new(curve: Curve, sensitivity: f32, cpi: u16) -> Self {
+        Pipeline {
+            curve,
+            sensitivity,
+            cpi,
+            remainder: (0.0, 0.0),
+        }
+    }
+
+    pub fn set_curve(&mut self, curve: Curve) {
+        self.curve = curve;
+    }
+
+    /// Sets the sensitivity, a multiplier of the motion at any speed
+    pub fn set_sensitivity(&mut self, sensitivity: f32) {
+        self.sensitivity = sensitivity;
+    }
+
+    /// Sets the resolution of the sensor, when changed (e.g., by the settings)
+    pub fn set_cpi(&mut self, cpi: u16) {
+        self.cpi = cpi;
+    }
+
+    /// The speed in mm/s of a sensor delta over `dt_us` microseconds
+    pub fn speed(&self, dx: i16, dy: i16, dt_us: u32) -> f32 {
+        let counts = libm::sqrtf(dx as f32 * dx as f32 + dy as f32 * dy as f32);
+        let mm = counts * MM_PER_INCH / self.cpi.max(1) as f32;
+        mm * 1_000_000.0 / dt_us.max(1) as f32
+    }
+
+    /// Processes a sensor delta measured over `dt_us` microseconds, returns
+    /// the pointer motion in counts
+    ///
+    /// The fractions are kept for the next delta, and so is the motion
+    /// beyond the `i16` range, returned with the next deltas (also without
+    /// motion).
+    pub fn process(&mut self, dx: i16, dy: i16, dt_us: u32) -> (i16, i16) {
+        if dx == 0 && dy == 0 && self.remainder.0.abs() < 1.0 && self.remainder.1.abs() < 1.0 {
+            return (0, 0);
+        }
+        let gain = self.curve.gain(self.speed(dx, dy, dt_us)) * self.sensitivity;
+        let (ix, rx) = split(dx as f32 * gain + self.remainder.0);
+        let (iy, ry) = split(dy as f32 * gain + self.remainder.1);
+        self.remainder = (rx, ry);
+        (ix, iy)
+    }
+
+    /// Drops the carried fractions
+    pub fn reset(&mut self) {
+        self.remainder = (0.0, 0.0);
+    }
+}
+
+/// Smoothing of small movements
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    None,
+    /// First order low-pass, cutoff frequency in Hz
+    LowPass {
+        cutoff: f32,
+    },
+    /// One euro filter, a low-pass with the cutoff (Hz) rising by `beta` per
+    /// count/s of speed, so slow movements are smoothed and fast ones not
+    /// delayed. `d_cutoff` (Hz) smooths the speed.
+    OneEuro {
+        min_cutoff: f32,
+        beta: f32,
+        d_cutoff: f32,
+    },
+}
+
+/// Filter configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterConfig {
+    /// Motion is dropped below this surface quality
+    pub min_squal: u8,
+    /// Motion is dropped above this shutter time, 0 disables
+    pub max_shutter: u16,
+    /// Motion is dropped for this long (us) after the sensor was lifted or
+    /// on a poor surface, until it settles
+    pub settle_us: u32,
+    /// Motion starting from rest is held back until it exceeds this many
+    /// counts (on either axis), 0 disables
+    pub jitter: u8,
+    pub smoothing: Smoothing,
+}
+
+impl FilterConfig {
+    /// Lift and surface quality gating, no jitter filter or smoothing
+    pub const DEFAULT: FilterConfig = FilterConfig {
+        min_squal: 16,
+        max_shutter: 0,
+        settle_us: 10_000,
+        jitter: 0,
+        smoothing: Smoothing::None,
+    };
+}
+
+impl Default for FilterConfig {
+    fn default() -> Self {
+        FilterConfig::DEFAULT
+    }
+}
+
+// rest after this long without motion (us), re-arming the jitter filter
+const REST_US: u32 = 100_000;
+
+/// Motion filter, between the sensor and the `Pipeline`
+///
+/// ```ignore
+/// let burst = pmw3389.read_burst()?;
+/// let (dx, dy) = filter.process(&burst, 1000);
+/// ```
+#[derive(Clone, Copy, Debug)]
+pub struct Filter {
+    config: FilterConfig,
+    // us left until settled
+    settle_us: u32,
+    // the last frame was dropped
+    gated: bool,
+    // jitter filter, moving (or at rest), us without motion, held back motion
+    moving: bool,
+    idle_us: u32,
+    held: (i32, i32),
+    // smoothing, motion not yet output (the raw minus the filtered position)
+    lag: (f32, f32),
+    // smoothed speed (count/s), of the one euro filter
+    rate: (f32, f32),
+    // fractions of counts, carried over to the next frame
+    remainder: (f32, f32),
+}
+
+impl Filter {
+    pub const fn new(config: FilterConfig) -> Self {
+        Filter {
+            config,
+            settle_us: 0,
+            gated: false,
+            moving: false,
+            idle_us: 0,
+            held: (0, 0),
+            lag: (0.0, 0.0),
+            rate: (0.0, 0.0),
+            remainder: (0.0, 0.0),
+        }
+    }
+
+    pub fn config(&self) -> &FilterConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: FilterConfig) {
+        self.config = config;
+        self.reset();
+    }
+
+    /// True if the motion of the last frame was dropped (lifted, poor
+    /// surface or settling)
+    pub fn is_gated(&self) -> bool {
+        self.gated
+    }
+
+    /// Filters the motion of a burst, `dt_us` microseconds after the last
+    ///
+    /// Call for every burst, with or without motion, smoothed motion is
+    /// output over the following frames.
+    pub fn process(&mut self, burst: &Burst, dt_us: u32) -> (i16, i16) {
+        let config = &self.config;
+        let poor = burst.lifted
+            || burst.squal < config.min_squal
+            || (config.max_shutter != 0 && burst.shutter > config.max_shutter);
+        if poor {
+            self.settle_us = config.settle_us;
+        } else {
+            self.settle_us = self.settle_us.saturating_sub(dt_us);
+        }
+        // motion held back or being smoothed is dropped as well, it may
+        // already be from the lift
+        self.gated = poor || self.settle_us > 0;
+        if self.gated {
+            self.reset();
+            return (0, 0);
+        }
+
+        let (dx, dy) = self.jitter(burst.dx as i32, burst.dy as i32, dt_us);
+        self.smooth(dx, dy, dt_us)
+    }
+
+    /// Drops held back and smoothed motion
+    pub fn reset(&mut self) {
+        self.moving = false;
+        self.idle_us = 0;
+        self.held = (0, 0);
+        self.lag = (0.0, 0.0);
+        self.rate = (0.0, 0.0);
+        self.remainder = (0.0, 0.0);
+    }
+
+    // holds back motion from rest, until it exceeds the jitter threshold
+    fn jitter(&mut self, dx: i32, dy: i32, dt_us: u32) -> (i32, i32) {
+        let jitter = self.config.jitter as i32;
+        if jitter == 0 {
+            return (dx, dy);
+        }
+        if dx == 0 && dy == 0 {
+            self.idle_us = self.idle_us.saturating_add(dt_us);
+            if self.idle_us >= REST_US {
+                self.moving = false;
+                self.held = (0, 0);
+            }
+            return (0, 0);
+        }
+        self.idle_us = 0;
+        if self.moving {
+            return (dx, dy);
+        }
+
+        self.held = (self.held.0 + dx, self.held.1 + dy);
+        if self.held.0.abs() > jitter || self.held.1.abs() > jitter {
+            self.moving = true;
+            core::mem::take(&mut self.held)
+        } else {
+            (0, 0)
+        }
+    }
+
+    fn smooth(&mut self, dx: i32, dy: i32, dt_us: u32) -> (i16, i16) {
+        let dt = dt_us.max(1) as f32 / 1_000_000.0;
+        let (x, y) = match self.config.smoothing {
+            Smoothing::None => {
+                // the held back jitter may take a delta beyond the i16 range
+                let clamp = |v: i32| v.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+                return (clamp(dx), clamp(dy));
+            }
+            Smoothing::LowPass { cutoff } => {
+                let a = alpha(cutoff, dt);
+                (
+                    low_pass(&mut self.lag.0, dx as f32, a),
+                    low_pass(&mut self.lag.1, dy as f32, a),
+                )
+            }
+            Smoothing::OneEuro {
+                min_cutoff,
+                beta,
+                d_cutoff,
+            } => {
+                let d = alpha(d_cutoff, dt);
+                let one_euro = |lag: &mut f32, rate: &mut f32, delta: f32| {
+                    // the speed towards the raw position, smoothed
+                    *rate += d * ((*lag + delta) / dt - *rate);
+                    low_pass(lag, delta, alpha(min_cutoff + beta * rate.abs(), dt))
+                };
+                (
+                    one_euro(&mut self.lag.0, &mut self.rate.0, dx as f32),
+                    one_euro(&mut self.lag.1, &mut self.rate.1, dy as f32),
+                )
+            }
+        };
+
+        let (ix, rx) = split(x + self.remainder.0);
+        let (iy, ry) = split(y + self.remainder.1);
+        self.remainder = (rx, ry);
+        (ix, iy)
+    }
+}
+
+// the whole counts of `v` within the i16 range, and the remainder, towards
+// zero, so the remainder keeps the sign of the motion
+fn split(v: f32) -> (i16, f32) {
+    let i = libm::truncf(v).clamp(i16::MIN as f32, i16::MAX as f32);
+    (i as i16, v - i)
+}
+
+// smoothing factor of a first order low-pass at `cutoff` Hz, `dt` s
+fn alpha(cutoff: f32, dt: f32) -> f32 {
+    let tau = 1.0 / (2.0 * core::f32::consts::PI * cutoff);
+    1.0 / (1.0 + tau / dt)
+}
+
+// low-pass filters the position, returns the motion of the filtered
+// position, `lag` is the raw minus the filtered position
+fn low_pass(lag: &mut f32, delta: f32, alpha: f32) -> f32 {
+    let d = *lag + delta;
+    let out = alpha * d;
+    *lag = d - out;
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn burst(dx: i16, dy: i16) -> Burst {
+        Burst {
+            motion: dx != 0 || dy != 0,
+            dx,
+            dy,
+            squal: 64,
+            ..Burst::default()
+        }
+    }
+
+    #[test]
+    fn held_jitter_does_not_wrap_at_the_limits() {
+        let mut filter = Filter::new(FilterConfig {
+            jitter: 10,
+            ..FilterConfig::DEFAULT
+        });
+        assert_eq!(filter.process(&burst(5, -5), 1000), (0, 0));
+        assert_eq!(
+            filter.process(&burst(i16::MAX, i16::MIN), 1000),
+            (i16::MAX, i16::MIN)
+        );
+        // moving, no longer held back
+        assert_eq!(
+            filter.process(&burst(i16::MAX, i16::MIN), 1000),
+            (i16::MAX, i16::MIN)
+        );
+    }
+
+    // a recorded trace, 1 ms apart: moving, lifted for 5 ms, moving again
+    fn trace() -> ([u8; 40 * crate::trace::RECORD_LEN], i32) {
+        let mut data = [0; 40 * crate::trace::RECORD_LEN];
+        let mut moved = 0;
+        for (i, chunk) in data.chunks_mut(crate::trace::RECORD_LEN).enumerate() {
+            let mut b = burst(3, -1);
+            if (10..15).contains(&i) {
+                b.lifted = true;
+                b.dx = 40;
+            }
+            // before the lift, or settled (10 ms) after it
+            if !(10..24).contains(&i) {
+                moved += 3;
+            }
+            let record = crate::trace::Record::from_burst(i as u32 * 1000, &b);
+            chunk.copy_from_slice(&record.to_bytes());
+        }
+        (data, moved)
+    }
+
+    #[test]
+    fn replayed_trace_drops_lift_and_settling() {
+        let (data, moved) = trace();
+        let mut replay = crate::trace::Replay::new(&data);
+        let mut filter = Filter::new(FilterConfig::DEFAULT);
+        let (mut x, mut gated) = (0, 0);
+        while let Some((burst, dt_us)) = replay.next_burst(1000) {
+            x += filter.process(&burst, dt_us).0 as i32;
+            gated += filter.is_gated() as u32;
+        }
+        assert_eq!(x, moved);
+        assert_eq!(gated, 14);
+        assert_eq!(replay.skipped(), 0);
+    }
+
+    #[test]
+    fn replayed_trace_through_the_pipeline_keeps_fractions() {
+        let (data, moved) = trace();
+        let mut replay = crate::trace::Replay::new(&data);
+        let mut filter = Filter::new(FilterConfig::DEFAULT);
+        let mut pipeline = Pipeline::new(Curve::NONE, 0.5, 1600);
+        let mut x = 0;
+        while let Some((burst, dt_us)) = replay.next_burst(1000) {
+            let (dx, dy) = filter.process(&burst, dt_us);
+            x += pipeline.process(dx, dy, dt_us).0 as i32;
+        }
+        // 3 * 0.5 per frame, the halves carried over
+        assert_eq!(x, moved / 2);
+    }
+
+    #[test]
+    fn motion_beyond_the_i16_range_is_carried_over() {
+        let mut pipeline = Pipeline::new(Curve::NONE, 4.0, 1600);
+        assert_eq!(
+            pipeline.process(20_000, -20_000, 1000),
+            (i16::MAX, i16::MIN)
+        );
+        assert_eq!(pipeline.process(0, 0, 1000), (i16::MAX, i16::MIN));
+        // 80000 - 2 * 32767 and -80000 + 2 * 32768
+        assert_eq!(pipeline.process(0, 0, 1000), (14_466, -14_464));
+        assert_eq!(pipeline.process(0, 0, 1000), (0, 0));
+        // the carried motion adds to the next delta
+        assert_eq!(pipeline.process(i16::MAX, 0, 1000), (i16::MAX, 0));
+        assert_eq!(pipeline.process(1, 0, 1000), (i16::MAX, 0));
+    }
+
+    #[test]
+    fn speed_is_independent_of_cpi() {
+        let low = Pipeline::new(Curve::NONE, 1.0, 800);
+        let high = Pipeline::new(Curve::NONE, 1.0, 1600);
+        assert_eq!(low.speed(8, 0, 1000), high.speed(16, 0, 1000));
+        assert!((high.speed(16, 0, 1000) - 254.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn curves() {
+        let linear = Curve::Linear {
+            accel: 0.01,
+            offset: 100.0,
+            cap: 2.0,
+        };
+        assert_eq!(linear.gain(50.0), 1.0);
+        assert!((linear.gain(150.0) - 1.5).abs() < 1e-6);
+        assert_eq!(linear.gain(1000.0), 2.0);
+
+        let lut = Lut::new(&[(0.0, 1.0), (100.0, 2.0)]).unwrap();
+        assert!((Curve::Table(lut).gain(50.0) - 1.5).abs() < 1e-6);
+        assert_eq!(Curve::Table(lut).gain(500.0), 2.0);
+        assert!(Lut::new(&[(100.0, 1.0), (100.0, 2.0)]).is_none());
+    }
+
+    #[test]
+    fn low_pass_keeps_the_total_motion() {
+        let mut filter = Filter::new(FilterConfig {
+            smoothing: Smoothing::LowPass { cutoff: 20.0 },
+            ..FilterConfig::DEFAULT
+        });
+        let mut x = 0;
+        for i in 0..500 {
+            let dx = if i < 100 { 5 } else { 0 };
+            x += filter.process(&burst(dx, 0), 1000).0 as i32;
+        }
+        // the filtered position approaches the raw one, short of less
+        // than a count
+        assert!((499..=500).contains(&x));
+    }
+}
@@ -0,0 +1,427 @@
+//! Odometry, integrating sensor motion into a position in millimetres
+//!
+//! The `Odometer` integrates the deltas of a single sensor (in counts, as
+//! read by `read_status`/`read_burst`) into a position, accumulated in
+//! `i64` counts, so a robot can run for years without losing a count. The
+//! position is converted to mm by the resolution (cpi) of the sensor, a
+//! change of resolution is folded into the position at the old resolution.
+//!
+//! Velocity is estimated over a window of samples, timestamped by the DWT
+//! cycle counter (`time::now`).
+//!
+//! The `DualOdometer` combines two sensors mounted a baseline apart, left
+//! and right of the centre of the robot, both facing forward (y forward,
+//! x to the right). Turning shows as a difference in forward motion
+//! between the two, integrated into a heading, and the forward and sideways
+//! motion of the centre is integrated into a pose in world coordinates.
+//!
+//! Plain computations (`no_std`, floating point by `libm`), the time is
+//! passed in, so they run on the host as well.
+use crate::time::{Duration, Instant};
+
+/// Length of an inch in micrometres
+const UM_PER_INCH: i64 = 25_400;
+
+/// A velocity estimate, in mm/s
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Velocity {
+    pub x: f32,
+    pub y: f32,
+    /// The end of the window
+    pub at: Instant,
+}
+
+/// Odometry of a single sensor
+#[derive(Clone, Copy, Debug)]
+pub struct Odometer {
+    cpi: u16,
+    window_us: u32,
+    // counts at the current resolution
+    x: i64,
+    y: i64,
+    // position at previous resolutions, in um
+    base_x: i64,
+    base_y: i64,
+    // counts and start of the current velocity window
+    win_x: i32,
+    win_y: i32,
+    win_start: Option<Instant>,
+    velocity: Velocity,
+}
+
+impl Odometer {
+    /// A default velocity window of 10 ms
+    pub const WINDOW_US: u32 = 10_000;
+
+    /// An odometer for a sensor at `cpi`
+    pub const fn new(cpi: u16) -> Self {
+        Odometer {
+            cpi,
+            window_us: Self::WINDOW_US,
+            x: 0,
+            y: 0,
+            base_x: 0,
+            base_y: 0,
+            win_x: 0,
+            win_y: 0,
+            win_start: None,
+            velocity: Velocity {
+                x: 0.0,
+                y: 0.0,
+                at: Instant::from_cycles(0),
+            },
+        }
+    }
+
+    /// Sets the resolution of the sensor, the motion so far is kept at the
+    /// old resolution
+    pub fn set_cpi(&mut self, cpi: u16) {
+        if cpi == self.cpi {
+            return;
+        }
+        let (x, y) = self.position_um();
+        self.base_x = x;
+        self.base_y = y;
+        self.x = 0;
+        self.y = 0;
+        // counts in the window are of the old resolution
+        self.win_x = 0;
+        self.win_y = 0;
+        self.win_start = None;
+        self.cpi = cpi;
+    }
+
+    /// Sets the window the velocity is estimated over, in us
+    pub fn set_window_us(&mut self, us: u32) {
+        self.window_us = us;
+    }
+
+    /// Adds a sensor delta, read at `now`
+    pub fn update(&mut self, dx: i16, dy: i16, now: Instant) {
+        self.x += dx as i64;
+        self.y += dy as i64;
+        let start = match self.win_start {
+            Some(start) => start,
+            None => {
+                // the first delta is motion before the window
+                self.win_start = Some(now);
+                return;
+            }
+        };
+        self.win_x = self.win_x.saturating_add(dx as i32);
+        self.win_y = self.win_y.saturating_add(dy as i32);
+
+        let elapsed = now.duration_since(start);
+        if elapsed != Duration::ZERO && elapsed.as_micros() >= self.window_us as u64 {
+            // counts per window, to mm/s
+            let scale = self.to_mm(1) / elapsed.as_secs_f32();
+            self.velocity = Velocity {
+                x: self.win_x as f32 * scale,
+                y: self.win_y as f32 * scale,
+                at: now,
+            };
+            // the delta of this sample is in the closed window
+            self.win_x = 0;
+            self.win_y = 0;
+            self.win_start = Some(now);
+        }
+    }
+
+    /// The position in sensor counts at the current resolution
+    pub fn counts(&self) -> (i64, i64) {
+        (self.x, self.y)
+    }
+
+    /// The position in um
+    pub fn position_um(&self) -> (i64, i64) {
+        let cpi = self.cpi.max(1) as i64;
+        (
+            self.base_x + self.x * UM_PER_INCH / cpi,
+            self.base_y + self.y * UM_PER_INCH / cpi,
+        )
+    }
+
+    /// The position in mm
+    pub fn position_mm(&self) -> (f32, f32) {
+        let (x, y) = self.position_um();
+        (x as f32 / 1000.0, y as f32 / 1000.0)
+    }
+
+    /// The latest velocity estimate
+    pub fn velocity(&self) -> Velocity {
+        self.velocity
+    }
+
+    /// Converts counts at the current resolution to mm
+    pub fn to_mm(&self, counts: i32) -> f32 {
+        counts as f32 * (UM_PER_INCH as f32 / 1000.0) / self.cpi.max(1) as f32
+    }
+
+    /// Restarts at the origin
+    pub fn reset(&mut self) {
+        *self = Odometer {
+            window_us: self.window_us,
+            ..Odometer::new(self.cpi)
+        };
+    }
+}
+
+/// Position and heading in world coordinates
+///
+/// At heading 0 the robot faces along world x, the heading (radians,
+/// within -pi..pi) increases turning left (counter-clockwise).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Pose {
+    /// Position in um
+    pub x: i64,
+    pub y: i64,
+    pub heading: f32,
+}
+
+impl Pose {
+    /// The position in mm
+    pub fn position_mm(&self) -> (f32, f32) {
+        (self.x as f32 / 1000.0, self.y as f32 / 1000.0)
+    }
+}
+
+/// Odometry of two sensors, left and right of the centre of the robot
+#[derive(Clone, Copy, Debug)]
+pub struct DualOdometer {
+    left: Odometer,
+    right: Odometer,
+    baseline: f32,
+    pose: Pose,
+    // fractions of um not yet in the pose
+    rem_x: f32,
+    rem_y: f32,
+}
+
+impl DualOdometer {
+    /// Two sensors at `cpi`, `baseline` mm apart
+    pub const fn new(cpi: u16, baseline: f32) -> Self {
+        DualOdometer {
+            left: Odometer::new(cpi),
+            right: Odometer::new(cpi),
+            baseline,
+            pose: Pose {
+                x: 0,
+                y: 0,
+                heading: 0.0,
+            },
+            rem_x: 0.0,
+            rem_y: 0.0,
+        }
+    }
+
+    /// Sets the resolution of both sensors
+    pub fn set_cpi(&mut self, cpi: u16) {
+        self.left.set_cpi(cpi);
+        self.right.set_cpi(cpi);
+    }
+
+    /// Sets the window the velocity is estimated over, in us
+    pub fn set_window_us(&mut self, us: u32) {
+        self.left.set_window_us(us);
+        self.right.set_window_us(us);
+    }
+
+    /// Adds the deltas of both sensors, read at `now`
+    pub fn update(&mut self, left: (i16, i16), right: (i16, i16), now: Instant) {
+        self.left.update(left.0, left.1, now);
+        self.right.update(right.0, right.1, now);
+
+        let (lx, ly) = (
+            self.left.to_mm(left.0 as i32),
+            self.left.to_mm(left.1 as i32),
+        );
+        let (rx, ry) = (
+            self.right.to_mm(right.0 as i32),
+            self.right.to_mm(right.1 as i32),
+        );
+
+        let turn = (ry - ly) / self.baseline;
+        let forward = (ly + ry) / 2.0;
+        // turning moves the sensors forward/backward only, the sideways
+        // motion is that of the centre
+        let right_side = (lx + rx) / 2.0;
+
+        // integrate along the mean heading of the step
+        let heading = self.pose.heading + turn / 2.0;
+        let (sin, cos) = (libm::sinf(heading), libm::cosf(heading));
+        let x = (forward * cos + right_side * sin) * 1000.0 + self.rem_x;
+        let y = (forward * sin - right_side * cos) * 1000.0 + self.rem_y;
+        let (ix, iy) = (libm::truncf(x), libm::truncf(y));
+        self.rem_x = x - ix;
+        self.rem_y = y - iy;
+        self.pose.x += ix as i64;
+        self.pose.y += iy as i64;
+        self.pose.heading = wrap(self.pose.heading + turn);
+    }
+
+    /// The pose of the centre of the robot
+    pub fn pose(&self) -> Pose {
+        self.pose
+    }
+
+    /// The latest velocity estimate of the centre, forward (y) and to the
+    /// right (x) in mm/s, and the turn rate in rad/s (counter-clockwise)
+    pub fn velocity(&self) -> (Velocity, f32) {
+        let (l, r) = (self.left.velocity(), self.right.velocity());
+        (
+            Velocity {
+                x: (l.x + r.x) / 2.0,
+                y: (l.y + r.y) / 2.0,
+                at: r.at,
+            },
+            (r.y - l.y) / self.baseline,
+        )
+    }
+
+    /// The odometers of the left and right sensor
+    pub fn sensors(&self) -> (&Odometer, &Odometer) {
+        (&self.left, &self.right)
+    }
+
+    /// Restarts at the origin, heading 0
+    pub fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+        self.pose = Pose::default();
+        self.rem_x = 0.0;
+        self.rem_y = 0.0;
+    }
+}
+
+// wraps an angle to -pi..pi
+fn wrap(a: f32) -> f32 {
+    use core::f32::consts::PI;
+    if a >= PI {
+        a - 2.0 * PI
+    } else if a < -PI {
+        a + 2.0 * PI
+    } else {
+        a
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time::tests::at;
+    use core::f32::consts::PI;
+
+    // 1 cycle per us
+    const HZ: u32 = 1_000_000;
+
+    fn us(t: u64) -> Instant {
+        Instant::from_cycles(t)
+    }
+
+    fn assert_near(a: f32, b: f32, eps: f32) {
+        assert!((a - b).abs() <= eps, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn cpi_change_is_folded_into_the_position() {
+        let mut odometer = Odometer::new(1000);
+        odometer.update(1000, -500, us(0));
+        assert_eq!(odometer.position_um(), (25_400, -12_700));
+
+        // the same cpi changes nothing
+        odometer.set_cpi(1000);
+        assert_eq!(odometer.counts(), (1000, -500));
+
+        odometer.set_cpi(2000);
+        assert_eq!(odometer.counts(), (0, 0));
+        odometer.update(2000, 1000, us(1000));
+        assert_eq!(odometer.counts(), (2000, 1000));
+        assert_eq!(odometer.position_um(), (50_800, 0));
+        assert_eq!(odometer.position_mm(), (50.8, 0.0));
+
+        odometer.reset();
+        assert_eq!(odometer.position_um(), (0, 0));
+        assert_eq!(odometer.to_mm(2000), 25.4);
+    }
+
+    #[test]
+    fn velocity_over_the_window() {
+        at(HZ, || {
+            let mut odometer = Odometer::new(1000);
+            // 10 counts per ms, 254 mm/s
+            for t in 0..=20 {
+                odometer.update(10, -5, us(t * 1000));
+                if t < 10 {
+                    assert_eq!(odometer.velocity(), Velocity::default());
+                }
+            }
+            let velocity = odometer.velocity();
+            assert_eq!(velocity.at, us(20_000));
+            assert_near(velocity.x, 254.0, 1e-3);
+            assert_near(velocity.y, -127.0, 1e-3);
+
+            // a shorter window, the motion in the last is of the old cpi
+            odometer.set_window_us(2000);
+            odometer.set_cpi(2000);
+            for t in 21..=23 {
+                odometer.update(10, 0, us(t * 1000));
+            }
+            assert_eq!(odometer.velocity().at, us(23_000));
+            assert_near(odometer.velocity().x, 127.0, 1e-3);
+        });
+    }
+
+    #[test]
+    fn straight_and_sideways() {
+        let mut dual = DualOdometer::new(1000, 100.0);
+        // forward, along world x at heading 0
+        dual.update((0, 1000), (0, 1000), us(0));
+        assert_eq!(
+            dual.pose(),
+            Pose {
+                x: 25_400,
+                y: 0,
+                heading: 0.0
+            }
+        );
+        // to the right, world -y
+        dual.update((1000, 0), (1000, 0), us(1000));
+        assert_eq!(dual.pose().position_mm(), (25.4, -25.4));
+        let (left, right) = dual.sensors();
+        assert_eq!(left.counts(), right.counts());
+
+        dual.reset();
+        assert_eq!(dual.pose(), Pose::default());
+    }
+
+    #[test]
+    fn turning_integrates_the_heading() {
+        let mut dual = DualOdometer::new(1000, 100.0);
+        // turning in place, counter-clockwise, 0.7874 mm per side and step
+        for t in 0..100 {
+            dual.update((0, -31), (0, 31), us(t * 1000));
+        }
+        let heading = 100.0 * 2.0 * 0.7874 / 100.0;
+        assert_near(dual.pose().heading, heading, 1e-4);
+        assert_eq!((dual.pose().x, dual.pose().y), (0, 0));
+
+        // forward, now along world y
+        dual.update((0, 1000), (0, 1000), us(100_000));
+        let pose = dual.pose();
+        assert_near(pose.x as f32, 25_400.0 * libm::cosf(heading), 1.0);
+        assert_near(pose.y as f32, 25_400.0 * libm::sinf(heading), 1.0);
+
+        // on past a half turn, the heading wraps to -pi..pi
+        for t in 0..100 {
+            dual.update((0, -31), (0, 31), us(200_000 + t * 1000));
+        }
+        assert_near(dual.pose().heading, 2.0 * heading - 2.0 * PI, 1e-3);
+    }
+
+    #[test]
+    fn angles_wrap() {
+        assert_eq!(wrap(1.0), 1.0);
+        assert_near(wrap(PI + 0.5), -PI + 0.5, 1e-6);
+        assert_near(wrap(-PI - 0.5), PI - 0.5, 1e-6);
+    }
+}
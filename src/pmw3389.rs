@@ -1,12 +1,53 @@
 /// PWM3389 gaming mouse sensor driver
-use stm32f4xx_hal::prelude::*;
-
-use crate::DwtDelay;
+use crate::rprintln;
+use crate::settings::{Lift, Settings};
 
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use embedded_hal::blocking::spi::{Transfer, Write};
 use embedded_hal::digital::v2::OutputPin;
 
-use rtt_target::{rprint, rprintln};
+/// A motion burst, the motion and statistics of the last frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Burst {
+    /// Motion since the last burst (MOT)
+    pub motion: bool,
+    /// The sensor is lifted off the surface (Lift_Stat)
+    pub lifted: bool,
+    pub dx: i16,
+    pub dy: i16,
+    /// Surface quality, the number of features on the surface / 8
+    pub squal: u8,
+    /// Upper byte of the sum of the 1296 raw data values of the frame
+    pub raw_data_sum: u8,
+    pub max_raw_data: u8,
+    pub min_raw_data: u8,
+    /// Shutter (exposure) time, in clock cycles, rises on dark or poor
+    /// surfaces and when lifted
+    pub shutter: u16,
+}
+
+impl Burst {
+    /// Decodes the 12 byte burst
+    ///
+    /// BYTE[00] Motion, bit 7 MOT (motion detected), bit 3 Lift_Stat (1 when
+    /// off the surface), BYTE[01] Observation, BYTE[02..=03] Delta_X_L/H,
+    /// BYTE[04..=05] Delta_Y_L/H, BYTE[06] SQUAL, BYTE[07] Raw_Data_Sum,
+    /// BYTE[08] Maximum_Raw_Data, BYTE[09] Minimum_Raw_Data, BYTE[10..=11]
+    /// Shutter_Upper/Lower.
+    pub fn from_bytes(buf: &[u8; 12]) -> Self {
+        Burst {
+            motion: buf[0] & 0x80 != 0,
+            lifted: buf[0] & 0x08 != 0,
+            dx: i16::from_le_bytes([buf[2], buf[3]]),
+            dy: i16::from_le_bytes([buf[4], buf[5]]),
+            squal: buf[6],
+            raw_data_sum: buf[7],
+            max_raw_data: buf[8],
+            min_raw_data: buf[9],
+            shutter: u16::from_be_bytes([buf[10], buf[11]]),
+        }
+    }
+}
 
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -75,16 +116,17 @@ impl Register {
     }
 }
 
-pub struct Pmw3389<SPI, CS> {
+pub struct Pmw3389<SPI, CS, D> {
     spi: SPI,
     cs: CS,
-    delay: DwtDelay,
+    delay: D,
 }
 
-impl<SPI, CS, E> Pmw3389<SPI, CS>
+impl<SPI, CS, D, E> Pmw3389<SPI, CS, D>
 where
     SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
     CS: OutputPin,
+    D: DelayUs<u32> + DelayMs<u32>,
 {
     fn com_begin(&mut self) {
         self.cs.set_low().ok();
@@ -94,8 +136,8 @@ where
         self.cs.set_high().ok();
     }
 
-    /// Creates a new driver from a SPI peripheral and a NCS pin
-    pub fn new(spi: SPI, cs: CS, delay: DwtDelay) -> Result<Self, E> {
+    /// Creates a new driver from a SPI peripheral, a NCS pin and a delay provider
+    pub fn new(spi: SPI, cs: CS, delay: D) -> Result<Self, E> {
         let mut pmw3389 = Pmw3389 { spi, cs, delay };
 
         rprintln!("pmw3389 - new");
@@ -219,8 +261,49 @@ where
         self.read_register(Register::ProductId)
     }
 
-    /// Read status
-    pub fn read_status(&mut self) -> Result<((i16, i16)), E> {
+    /// Sets the resolution, 50..=16000 cpi in steps of 50
+    pub fn set_cpi(&mut self, cpi: u16) -> Result<(), E> {
+        let steps = cpi / 50;
+        self.write_register(Register::ResolutionL, steps as u8)?;
+        self.write_register(Register::ResolutionH, (steps >> 8) as u8)
+    }
+
+    /// Sets the lift-off detection distance
+    pub fn set_lift(&mut self, lift: Lift) -> Result<(), E> {
+        let byte = match lift {
+            Lift::Mm2 => 0x02,
+            Lift::Mm3 => 0x03,
+        };
+        self.write_register(Register::LiftConfig, byte)
+    }
+
+    /// Enables (or disables) angle snapping
+    pub fn set_angle_snap(&mut self, enable: bool) -> Result<(), E> {
+        self.write_register(Register::AngleSnap, if enable { 0x80 } else { 0x00 })
+    }
+
+    /// Enables (or disables) rest mode, the sensor lowers its frame rate
+    /// when not moving
+    pub fn set_rest(&mut self, enable: bool) -> Result<(), E> {
+        self.write_register(Register::Config2, if enable { 0x20 } else { 0x00 })
+    }
+
+    /// Applies the sensor part of the user settings
+    pub fn apply(&mut self, settings: &Settings) -> Result<(), E> {
+        self.set_cpi(settings.cpi)?;
+        self.set_lift(settings.lift)?;
+        self.set_angle_snap(settings.angle_snap)?;
+        self.set_rest(settings.rest)
+    }
+
+    /// Read status, returns the motion deltas `(x, y)`
+    pub fn read_status(&mut self) -> Result<(i16, i16), E> {
+        let burst = self.read_burst()?;
+        Ok((burst.dx, burst.dy))
+    }
+
+    /// Reads a motion burst, motion and frame statistics
+    pub fn read_burst(&mut self) -> Result<Burst, E> {
         self.com_begin();
 
         self.spi.transfer(&mut [Register::MotionBurst.addr()])?;
@@ -236,36 +319,7 @@ where
 
         self.com_end();
 
-        //     SPI.endTransaction(); // Per:Not sure what it does
-        //     /*
-        //     BYTE[00] = Motion    = if the 7th bit is 1, a motion is detected.
-        //            ==> 7 bit: MOT (1 when motion is detected)
-        //            ==> 3 bit: 0 when chip is on surface / 1 when off surface
-        //            ] = Observation
-        //     BYTE[02] = Delta_X_L = dx (LSB)
-        //     BYTE[03] = Delta_X_H = dx (MSB)
-        //     BYTE[04] = Delta_Y_L = dy (LSB)
-        //     BYTE[05] = Delta_Y_H = dy (MSB)
-        //     BYTE[06] = SQUAL     = Surface Quality register, max 0x80
-        //                          - Number of features on the surface = SQUAL * 8
-        //     BYTE[07] = Raw_Data_Sum   = It reports the upper byte of an 18‐bit counter which sums all 1296 raw data in the current frame;
-        //                                * Avg value = Raw_Data_Sum * 1024 / 1296
-        //     BYTE[08] = Maximum_Raw_Data  = Max raw data value in current frame, max=127
-        //     BYTE[09] = Minimum_Raw_Data  = Min raw data value in current frame, max=127
-        //     BYTE[10] = Shutter_Upper     = Shutter LSB
-        //     BYTE[11] = Shutter_Lower     = Shutter MSB, Shutter = shutter is adjusted to keep the average raw data values within normal operating ranges
-        //     */
-        //     int motion = (burstBuffer[0] & 0x80) > 0;
-        let _motion = buf[0] & 0x80;
-        let _surface = buf[0] & 0x08;
-        // 0 if on surface / 1 if off surface
-
-        let x: i16 = (((buf[3] as u16) << 8) | buf[2] as u16) as i16;
-        let y: i16 = (((buf[5] as u16) << 8) | buf[4] as u16) as i16;
-
-        let _squal = buf[6];
-
-        Ok((x, y))
+        Ok(Burst::from_bytes(&buf))
     }
 
     // Upload the firmware
@@ -1,12 +1,11 @@
 /// PWM3389 gaming mouse sensor driver
-use crate::DwtDelay;
-use stm32f4xx_hal::prelude::*;
+use crate::bus::Sleep;
+pub use crate::pmw3389::Burst;
+use crate::rprintln;
 
+use embedded_hal::blocking::delay::{DelayMs, DelayUs};
 use embedded_hal::blocking::spi::Transfer;
 use embedded_hal::digital::v2::OutputPin;
-// use stm32f4xx_hal::prelude::*;
-
-use rtt_target::rprintln;
 
 // struct SPI_EMU<SPI, E>
 // where
@@ -82,17 +81,30 @@ impl Register {
     }
 }
 
-pub struct Pmw3389e<SPI, E>
+/// Sensor power state
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Power {
+    /// Full operation, rest modes disabled
+    Run,
+    /// Sensor downshifts to the Rest1..3 modes on its own when idle
+    Rest,
+    /// Sensor shut down, must be re-initialized to leave
+    Shutdown,
+}
+
+pub struct Pmw3389e<SPI, D, E>
 where
     SPI: Transfer<u8, Error = E> + OutputPin,
 {
     spi: SPI,
-    delay: DwtDelay,
+    delay: D,
+    power: Power,
 }
 
-impl<SPI, E> Pmw3389e<SPI, E>
+impl<SPI, D, E> Pmw3389e<SPI, D, E>
 where
     SPI: Transfer<u8, Error = E> + OutputPin,
+    D: DelayUs<u32> + DelayMs<u32>,
 {
     fn com_begin(&mut self) {
         self.spi.set_low().ok();
@@ -102,9 +114,13 @@ where
         self.spi.set_high().ok();
     }
 
-    /// Creates a new driver from a SPI peripheral and a NCS pin
-    pub fn new(spi: SPI, delay: DwtDelay) -> Result<Self, E> {
-        let mut pmw3389 = Pmw3389e { spi, delay };
+    /// Creates a new driver from an (emulated) SPI bus and a delay provider
+    pub fn new(spi: SPI, delay: D) -> Result<Self, E> {
+        let mut pmw3389 = Pmw3389e {
+            spi,
+            delay,
+            power: Power::Rest,
+        };
 
         rprintln!("pmw3389 - new");
 
@@ -124,27 +140,7 @@ where
 
         rprintln!("reset");
 
-        // force reset
-        pmw3389.write_register(Register::PowerUpReset, 0x5a)?;
-
-        // wait for reboot
-        pmw3389.delay.delay_ms(50);
-
-        // read product id
-        let id = pmw3389.product_id()?;
-        rprintln!("product_id 0x{:x}", id);
-
-        let srom_id = pmw3389.read_register(Register::SROMId)?;
-        rprintln!("srom_id {}, 0x{:x}", srom_id, srom_id);
-
-        // read registers 0x02 to 0x06 (and discard the data)
-        pmw3389.read_register(Register::Motion)?;
-        pmw3389.read_register(Register::DeltaXL)?;
-        pmw3389.read_register(Register::DeltaXH)?;
-        pmw3389.read_register(Register::DeltaYL)?;
-        pmw3389.read_register(Register::DeltaYH)?;
-
-        pmw3389.upload_firmware()?;
+        pmw3389.power_up()?;
 
         // pmw3389.delay.delay_ms(1000);
 
@@ -244,71 +240,69 @@ where
         self.read_register(Register::ProductId)
     }
 
-    // /// Read status
-    // pub fn read_status(&mut self) -> Result<(), E> {
-    //     self.com_begin();
-
-    //     // self.write_register(Register::Motion, 0x01)?;
-
-    //     self.spi.transfer(&mut [Register::MotionBurst.addr()])?;
-    //     // self.write_register(Register::MotionBurst, 0x00);
-
-    //     self.delay.delay_us(35); // waits for tSRAD
-
-    //     // read burst buffer
-    //     let mut buf = [0u8; 12];
-    //     self.spi.transfer(&mut buf)?;
-
-    //     // tSCLK-NCS for read operation is 120ns
-    //     self.delay.delay_us(120);
-
-    //     self.com_end();
-
-    //     rprint!("burst [");
-    //     for j in buf.iter() {
-    //         rprint!("0x{:02x}, ", j);
-    //     }
-    //     rprintln!("]");
-
-    //     //     SPI.endTransaction(); // Per:Not sure what it does
-    //     //     /*
-    //     //     BYTE[00] = Motion    = if the 7th bit is 1, a motion is detected.
-    //     //            ==> 7 bit: MOT (1 when motion is detected)
-    //     //            ==> 3 bit: 0 when chip is on surface / 1 when off surface
-    //     //            ] = Observation
-    //     //     BYTE[02] = Delta_X_L = dx (LSB)
-    //     //     BYTE[03] = Delta_X_H = dx (MSB)
-    //     //     BYTE[04] = Delta_Y_L = dy (LSB)
-    //     //     BYTE[05] = Delta_Y_H = dy (MSB)
-    //     //     BYTE[06] = SQUAL     = Surface Quality register, max 0x80
-    //     //                          - Number of features on the surface = SQUAL * 8
-    //     //     BYTE[07] = Raw_Data_Sum   = It reports the upper byte of an 18‐bit counter which sums all 1296 raw data in the current frame;
-    //     //                                * Avg value = Raw_Data_Sum * 1024 / 1296
-    //     //     BYTE[08] = Maximum_Raw_Data  = Max raw data value in current frame, max=127
-    //     //     BYTE[09] = Minimum_Raw_Data  = Min raw data value in current frame, max=127
-    //     //     BYTE[10] = Shutter_Upper     = Shutter LSB
-    //     //     BYTE[11] = Shutter_Lower     = Shutter MSB, Shutter = shutter is adjusted to keep the average raw data values within normal operating ranges
-    //     //     */
-    //     //     int motion = (burstBuffer[0] & 0x80) > 0;
-    //     let motion = buf[0] & 0x80;
-    //     let surface = buf[0] & 0x08;
-    //     // 0 if on surface / 1 if off surface
-
-    //     let x = buf[2] as u16 + (buf[3] as u16) << 8;
-    //     let y = buf[4] as u16 + (buf[5] as u16) << 8;
-
-    //     let squal = buf[6];
-
-    //     rprintln!(
-    //         "motion {}, surface {}, (x, y) {:?}, squal {}",
-    //         motion,
-    //         surface,
-    //         (x, y),
-    //         squal
-    //     );
-
-    //     Ok(())
-    //}
+    /// Current power state of the sensor
+    pub fn power(&self) -> Power {
+        self.power
+    }
+
+    // Power up reset followed by the firmware upload, cf p.17 of the datasheet
+    fn power_up(&mut self) -> Result<(), E> {
+        // force reset
+        self.write_register(Register::PowerUpReset, 0x5a)?;
+
+        // wait for reboot
+        self.delay.delay_ms(50);
+
+        // read product id
+        let id = self.product_id()?;
+        rprintln!("product_id 0x{:x}", id);
+
+        let srom_id = self.read_register(Register::SROMId)?;
+        rprintln!("srom_id {}, 0x{:x}", srom_id, srom_id);
+
+        // read registers 0x02 to 0x06 (and discard the data)
+        self.read_register(Register::Motion)?;
+        self.read_register(Register::DeltaXL)?;
+        self.read_register(Register::DeltaXH)?;
+        self.read_register(Register::DeltaYL)?;
+        self.read_register(Register::DeltaYH)?;
+
+        // leaves the sensor with Rest_En set
+        self.upload_firmware()?;
+        self.power = Power::Rest;
+
+        Ok(())
+    }
+
+    /// Read status, returns the motion deltas `(x, y)`
+    ///
+    /// Requires burst mode, i.e., a prior write to the MotionBurst register.
+    pub fn read_status(&mut self) -> Result<(i16, i16), E> {
+        let burst = self.read_burst()?;
+        Ok((burst.dx, burst.dy))
+    }
+
+    /// Reads a motion burst, motion and frame statistics
+    ///
+    /// Requires burst mode, i.e., a prior write to the MotionBurst register.
+    pub fn read_burst(&mut self) -> Result<Burst, E> {
+        self.com_begin();
+
+        self.spi.transfer(&mut [Register::MotionBurst.addr()])?;
+
+        self.delay.delay_us(35); // waits for tSRAD
+
+        // read burst buffer
+        let mut buf = [0u8; 12];
+        self.spi.transfer(&mut buf)?;
+
+        // tSCLK-NCS for read operation is 120ns
+        self.delay.delay_us(1);
+
+        self.com_end();
+
+        Ok(Burst::from_bytes(&buf))
+    }
 
     // Upload the firmware
     pub fn upload_firmware(&mut self) -> Result<(), E> {
@@ -670,3 +664,58 @@ where
         0x23, 0xc4, 0xeb, 0x54, 0x2a, 0xb7, 0xec, 0x5a, 0x36, 0xcf, 0x81, 0x10, 0xac, 0x74,
     ];
 }
+
+impl<SPI, D, E> Pmw3389e<SPI, D, E>
+where
+    SPI: Transfer<u8, Error = E> + OutputPin + Sleep<Error = E>,
+    D: DelayUs<u32> + DelayMs<u32>,
+{
+    /// Resets the spi port and powers up the sensor after a shutdown
+    fn leave_shutdown(&mut self) -> Result<(), E> {
+        // drop and raise ncs to reset the spi port
+        self.com_begin();
+        self.delay.delay_us(40);
+        self.com_end();
+        self.delay.delay_us(40);
+        self.power_up()
+    }
+
+    /// Enables the sensor rest modes and puts the bus to sleep
+    ///
+    /// The bus wakes up on the next register access, e.g., when polling
+    /// for motion.
+    pub fn rest(&mut self) -> Result<(), E> {
+        if self.power == Power::Shutdown {
+            self.leave_shutdown()?;
+        }
+        // Rest_En, bit 5 of Config2
+        self.write_register(Register::Config2, 0x20)?;
+        self.power = Power::Rest;
+        self.spi.sleep()
+    }
+
+    /// Shuts down the sensor and puts the bus to sleep
+    pub fn shutdown(&mut self) -> Result<(), E> {
+        if self.power != Power::Shutdown {
+            self.write_register(Register::Shutdown, 0xb6)?;
+            // tSTDOWN, the sensor is unresponsive while shutting down
+            self.delay.delay_us(500);
+            self.power = Power::Shutdown;
+        }
+        self.spi.sleep()
+    }
+
+    /// Wakes up the bus and returns the sensor to full operation
+    ///
+    /// Leaving shutdown requires a power up reset and a new firmware upload.
+    pub fn run(&mut self) -> Result<(), E> {
+        self.spi.wake()?;
+        if self.power == Power::Shutdown {
+            self.leave_shutdown()?;
+        }
+        // disable Rest_En
+        self.write_register(Register::Config2, 0x00)?;
+        self.power = Power::Run;
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+//! Low power operation while the USB bus is suspended
+//!
+//! When the host suspends the bus (e.g., the PC goes to sleep), the device
+//! should draw as little as possible. `UsbPower` follows the state of the
+//! `UsbDevice`, and while suspended stops the PHY clock of the USB core and
+//! divides the AHB clock (HCLK) by 2, 24 MHz at the 48 MHz of the firmware.
+//! The USB core needs HCLK of at least 14.2 MHz to run (and to detect the
+//! resume), so it is divided no further, and not at all below 28.4 MHz.
+//!
+//! Everything clocked from HCLK slows down accordingly, the DWT cycle
+//! counter and the APB buses (e.g., the SPI clock). `time` counts the cycles
+//! of the divided clock double (`time::switch_clock`), so instants, delays
+//! and deadlines stay right across the switch. Schedules in raw cycles do
+//! not, while lowered the periods of RTIC tasks (`cyccnt`) stretch 2x,
+//! e.g., a task polling every 1 ms runs every 2 ms.
+//!
+//! The USB interrupt still fires on resume (or reset) of the bus, call
+//! `interrupt` first thing in the handler to restore the clocks before
+//! polling the device, and `update` after.
+//!
+//! If enabled by the host (SET_FEATURE DEVICE_REMOTE_WAKEUP), the device may
+//! wake the host, `wakeup` starts the resume signalling (DCTL RWUSIG),
+//! `end_wakeup` must stop it 1 to 15 ms later (USB 2.0, 7.1.7.7). If the
+//! host does not resume the bus in `WAKEUP_TIMEOUT_MS`, `wakeup_expired`
+//! lowers the clocks again, rather than staying at full power.
+use stm32f4xx_hal::stm32;
+use usb_device::device::UsbDeviceState;
+
+use crate::time::{self, Deadline, Duration};
+
+/// The time the host has to resume the bus after a remote wakeup
+pub const WAKEUP_TIMEOUT_MS: u64 = 100;
+
+// AHB prescaler while suspended, HCLK = SYSCLK / 2
+const HPRE_DIV2: u8 = 0b1000;
+// the lowest HCLK of the USB core, in Hz
+const MIN_HCLK: u32 = 14_200_000;
+
+pub struct UsbPower {
+    // the bus is suspended (as last seen by `update`)
+    suspended: bool,
+    // the clocks are lowered, and the AHB prescaler to restore
+    lowered: Option<u8>,
+    // the clocks were restored by an interrupt while suspended
+    interrupted: bool,
+    // the host enabled remote wakeup
+    remote_wakeup: bool,
+    // the host should resume the bus before, after a remote wakeup
+    wakeup: Option<Deadline>,
+}
+
+impl UsbPower {
+    pub const fn new() -> Self {
+        UsbPower {
+            suspended: false,
+            lowered: None,
+            interrupted: false,
+            remote_wakeup: false,
+            wakeup: None,
+        }
+    }
+
+    /// True while the bus is suspended
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// True while the clocks are lowered
+    pub fn is_low_power(&self) -> bool {
+        self.lowered.is_some()
+    }
+
+    /// True while the clocks are lowered and the host enabled remote wakeup
+    pub fn can_wakeup(&self) -> bool {
+        self.lowered.is_some() && self.remote_wakeup
+    }
+
+    /// Restores the clocks, call at the start of the USB interrupt
+    pub fn interrupt(&mut self) {
+        if self.lowered.is_some() {
+            self.restore();
+            self.interrupted = true;
+        }
+    }
+
+    /// Follows the device state, call after `UsbDevice::poll`
+    ///
+    /// Lowers the clocks when the bus is suspended (or stays suspended after
+    /// an interrupt). Returns `Some(true)` when the bus is suspended,
+    /// `Some(false)` when resumed, and `None` if unchanged.
+    pub fn update(&mut self, state: UsbDeviceState, remote_wakeup: bool) -> Option<bool> {
+        let suspended = state == UsbDeviceState::Suspend;
+        let was_suspended = self.suspended;
+        // after a remote wakeup the device stays suspended until the host
+        // resumes, with the clocks restored
+        if suspended && (!was_suspended || self.interrupted) {
+            self.remote_wakeup = remote_wakeup;
+            self.lower();
+        }
+        if !suspended || self.lowered.is_some() {
+            self.wakeup = None;
+        }
+        self.interrupted = false;
+        self.suspended = suspended;
+
+        match (was_suspended, suspended) {
+            (false, true) => Some(true),
+            (true, false) => Some(false),
+            _ => None,
+        }
+    }
+
+    /// Starts waking the host, if suspended and enabled by the host
+    ///
+    /// Restores the clocks and starts the resume signalling, returns false
+    /// if the host can not be woken. Call `end_wakeup` 1 to 15 ms later.
+    pub fn wakeup(&mut self) -> bool {
+        if !self.can_wakeup() {
+            return false;
+        }
+        self.restore();
+        let device = unsafe { &*stm32::OTG_FS_DEVICE::ptr() };
+        device.dctl.modify(|_, w| w.rwusig().set_bit());
+        self.wakeup = Some(Deadline::after(Duration::from_millis(WAKEUP_TIMEOUT_MS)));
+        true
+    }
+
+    /// Lowers the clocks again if the host did not resume the bus after a
+    /// remote wakeup, returns true if so
+    ///
+    /// Call periodically, e.g., when polling the sensor.
+    pub fn wakeup_expired(&mut self) -> bool {
+        match self.wakeup {
+            Some(deadline) if deadline.is_expired() => {
+                self.wakeup = None;
+                if self.suspended {
+                    self.lower();
+                    return true;
+                }
+                false
+            }
+            _ => false,
+        }
+    }
+
+    /// Ends the resume signalling
+    pub fn end_wakeup(&mut self) {
+        let device = unsafe { &*stm32::OTG_FS_DEVICE::ptr() };
+        device.dctl.modify(|_, w| w.rwusig().clear_bit());
+    }
+
+    fn lower(&mut self) {
+        if self.lowered.is_some() {
+            return;
+        }
+        let (rcc, pwrclk) = unsafe { (&*stm32::RCC::ptr(), &*stm32::OTG_FS_PWRCLK::ptr()) };
+        pwrclk.pcgcctl.modify(|_, w| w.stppclk().set_bit());
+        let hpre = rcc.cfgr.read().hpre().bits();
+        self.lowered = Some(hpre);
+        if ahb_divider(hpre) == 1 && time::cycle_frequency() / 2 >= MIN_HCLK {
+            time::switch_clock(2, || {
+                rcc.cfgr.modify(|_, w| unsafe { w.hpre().bits(HPRE_DIV2) })
+            });
+        }
+    }
+
+    fn restore(&mut self) {
+        if let Some(hpre) = self.lowered.take() {
+            let (rcc, pwrclk) = unsafe { (&*stm32::RCC::ptr(), &*stm32::OTG_FS_PWRCLK::ptr()) };
+            time::switch_clock(1, || rcc.cfgr.modify(|_, w| unsafe { w.hpre().bits(hpre) }));
+            pwrclk.pcgcctl.modify(|_, w| w.stppclk().clear_bit());
+        }
+    }
+}
+
+// the AHB prescaler divider of the HPRE bits (1, 2, 4, ..., 512, no 32)
+fn ahb_divider(hpre: u8) -> u32 {
+    match hpre {
+        0b1000..=0b1011 => 2 << (hpre - 0b1000),
+        0b1100..=0b1111 => 64 << (hpre - 0b1100),
+        _ => 1,
+    }
+}
+
+impl Default for UsbPower {
+    fn default() -> Self {
+        UsbPower::new()
+    }
+}
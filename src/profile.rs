@@ -0,0 +1,420 @@
+//! Execution profiling on the DWT cycle counter
+//!
+//! Code is timed in named scopes, `let _scope = profile::scope("poll");`
+//! measures from there to the end of the block (when the guard is dropped).
+//! The cycles are collected per scope name in a fixed table (`Profiler`,
+//! `MAX_SCOPES` entries), as count, min, max and mean. Scopes may nest, and
+//! be used from any task or interrupt handler, the table is updated in a
+//! (short) critical section. A scope that preempts another is counted in
+//! both.
+//!
+//! `dump` takes the table (resetting it), and sends it as `Record`s, one
+//! per scope, e.g., to an RTT channel. A host tool (`profile-view`) decodes
+//! the records and renders the dumps. A record is 40 bytes:
+//!
+//! | bytes  | content                                               |
+//! |--------|-------------------------------------------------------|
+//! | 0      | `SYNC` (0x5a)                                         |
+//! | 1      | index of the scope in the dump                        |
+//! | 2      | number of scopes in the dump                          |
+//! | 3..7   | cycle counter frequency in Hz                         |
+//! | 7..19  | name, UTF-8, zero padded (truncated to `NAME_LEN`)    |
+//! | 19..23 | count                                                 |
+//! | 23..27 | min cycles                                            |
+//! | 27..31 | max cycles                                            |
+//! | 31..35 | mean cycles                                           |
+//! | 35..39 | cycles since the previous dump (the period)           |
+//! | 39     | CRC-8 (polynomial 0x07) of bytes 0..39                |
+//!
+//! All numbers are little endian. As for `trace`, the decoder (a
+//! `trace::FrameDecoder`) looks for the sync byte and checks the CRC. While
+//! the clocks are lowered (see `power`) a cycle is longer, the frequency is
+//! that of the counter at the time of the dump (`time::cycle_frequency`).
+use crate::trace::{crc8, FrameDecoder, Framed};
+
+#[cfg(feature = "stm32")]
+use crate::time;
+#[cfg(feature = "stm32")]
+use core::cell::{Cell, RefCell};
+#[cfg(feature = "stm32")]
+use cortex_m::interrupt::{self, Mutex};
+#[cfg(feature = "stm32")]
+use stm32f4xx_hal::stm32::DWT;
+
+/// The size of a record
+pub const RECORD_LEN: usize = 40;
+
+/// The first byte of a record
+pub const SYNC: u8 = 0x5a;
+
+/// The maximum length of a scope name in a record
+pub const NAME_LEN: usize = 12;
+
+/// The number of scopes in the table, further scopes are not recorded
+pub const MAX_SCOPES: usize = 16;
+
+/// The cycles spent in a scope
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    pub count: u32,
+    pub min: u32,
+    pub max: u32,
+    pub total: u64,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Stats {
+            count: 0,
+            min: u32::MAX,
+            max: 0,
+            total: 0,
+        }
+    }
+
+    pub fn add(&mut self, cycles: u32) {
+        self.count = self.count.saturating_add(1);
+        self.min = self.min.min(cycles);
+        self.max = self.max.max(cycles);
+        self.total += cycles as u64;
+    }
+
+    /// The mean cycles, 0 if none
+    pub fn mean(&self) -> u32 {
+        if self.count == 0 {
+            0
+        } else {
+            (self.total / self.count as u64) as u32
+        }
+    }
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new()
+    }
+}
+
+/// A table of scopes
+#[derive(Clone, Copy, Debug)]
+pub struct Profiler {
+    scopes: [(&'static str, Stats); MAX_SCOPES],
+    len: usize,
+    missed: u32,
+}
+
+impl Profiler {
+    pub const fn new() -> Self {
+        Profiler {
+            scopes: [("", Stats::new()); MAX_SCOPES],
+            len: 0,
+            missed: 0,
+        }
+    }
+
+    /// Adds `cycles` to the scope `name`, a new scope takes the next entry
+    /// of the table (if any)
+    pub fn record(&mut self, name: &'static str, cycles: u32) {
+        if let Some((_, stats)) = self.scopes[..self.len].iter_mut().find(|(n, _)| *n == name) {
+            stats.add(cycles);
+        } else if self.len < MAX_SCOPES {
+            self.scopes[self.len] = (name, Stats::new());
+            self.scopes[self.len].1.add(cycles);
+            self.len += 1;
+        } else {
+            self.missed = self.missed.saturating_add(1);
+        }
+    }
+
+    /// The scopes, in order of first use
+    pub fn scopes(&self) -> impl Iterator<Item = (&'static str, &Stats)> {
+        self.scopes[..self.len].iter().map(|(n, s)| (*n, s))
+    }
+
+    /// The number of measurements not recorded, the table was full
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+
+    /// Clears the stats, keeping the scopes (and their order)
+    pub fn reset(&mut self) {
+        for (_, stats) in &mut self.scopes[..self.len] {
+            *stats = Stats::new();
+        }
+        self.missed = 0;
+    }
+
+    /// The records of the scopes, counted at `frequency` Hz over `period`
+    /// cycles
+    pub fn records(&self, frequency: u32, period: u32) -> impl Iterator<Item = Record> + '_ {
+        let scopes = self.len as u8;
+        self.scopes().enumerate().map(move |(i, (name, stats))| {
+            let mut record = Record {
+                index: i as u8,
+                scopes,
+                frequency,
+                name: [0; NAME_LEN],
+                count: stats.count,
+                min: if stats.count == 0 { 0 } else { stats.min },
+                max: stats.max,
+                mean: stats.mean(),
+                period,
+            };
+            let len = name.len().min(NAME_LEN);
+            record.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+            record
+        })
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::new()
+    }
+}
+
+/// The stats of a scope, as sent
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Record {
+    pub index: u8,
+    pub scopes: u8,
+    pub frequency: u32,
+    pub name: [u8; NAME_LEN],
+    pub count: u32,
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    pub period: u32,
+}
+
+impl Record {
+    /// The name, up to the padding (or an invalid character)
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|b| *b == 0).unwrap_or(NAME_LEN);
+        match core::str::from_utf8(&self.name[..len]) {
+            Ok(name) => name,
+            Err(e) => core::str::from_utf8(&self.name[..e.valid_up_to()]).unwrap_or(""),
+        }
+    }
+
+    /// True for the last record of a dump
+    pub fn is_last(&self) -> bool {
+        self.index as usize + 1 >= self.scopes as usize
+    }
+
+    /// Cycles in us
+    pub fn to_us(&self, cycles: u32) -> f32 {
+        cycles as f32 * 1e6 / self.frequency.max(1) as f32
+    }
+
+    /// The share of the period spent in the scope (0..1)
+    pub fn load(&self) -> f32 {
+        if self.period == 0 {
+            0.0
+        } else {
+            self.mean as f32 * self.count as f32 / self.period as f32
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut bytes = [0; RECORD_LEN];
+        bytes[0] = SYNC;
+        bytes[1] = self.index;
+        bytes[2] = self.scopes;
+        bytes[3..7].copy_from_slice(&self.frequency.to_le_bytes());
+        bytes[7..19].copy_from_slice(&self.name);
+        bytes[19..23].copy_from_slice(&self.count.to_le_bytes());
+        bytes[23..27].copy_from_slice(&self.min.to_le_bytes());
+        bytes[27..31].copy_from_slice(&self.max.to_le_bytes());
+        bytes[31..35].copy_from_slice(&self.mean.to_le_bytes());
+        bytes[35..39].copy_from_slice(&self.period.to_le_bytes());
+        bytes[RECORD_LEN - 1] = crc8(&bytes[..RECORD_LEN - 1]);
+        bytes
+    }
+
+    /// The record, `None` if the sync byte or CRC is wrong
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Option<Self> {
+        if bytes[0] != SYNC || crc8(&bytes[..RECORD_LEN - 1]) != bytes[RECORD_LEN - 1] {
+            return None;
+        }
+        let u32_at =
+            |i: usize| u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+        let mut name = [0; NAME_LEN];
+        name.copy_from_slice(&bytes[7..19]);
+        Some(Record {
+            index: bytes[1],
+            scopes: bytes[2],
+            frequency: u32_at(3),
+            name,
+            count: u32_at(19),
+            min: u32_at(23),
+            max: u32_at(27),
+            mean: u32_at(31),
+            period: u32_at(35),
+        })
+    }
+}
+
+impl Framed<RECORD_LEN> for Record {
+    const SYNC: u8 = SYNC;
+
+    fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Option<Self> {
+        Record::from_bytes(bytes)
+    }
+}
+
+/// Decodes records from a byte stream
+pub type Decoder = FrameDecoder<Record, RECORD_LEN>;
+
+// the scopes, and the cycle count at the last dump
+#[cfg(feature = "stm32")]
+static PROFILER: Mutex<RefCell<Profiler>> = Mutex::new(RefCell::new(Profiler::new()));
+#[cfg(feature = "stm32")]
+static SINCE: Mutex<Cell<u32>> = Mutex::new(Cell::new(0));
+
+/// Times a scope, until dropped
+#[cfg(feature = "stm32")]
+#[must_use = "the scope ends when the guard is dropped"]
+pub struct Scope {
+    name: &'static str,
+    start: u32,
+}
+
+#[cfg(feature = "stm32")]
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let cycles = DWT::cycle_count().wrapping_sub(self.start);
+        interrupt::free(|cs| PROFILER.borrow(cs).borrow_mut().record(self.name, cycles));
+    }
+}
+
+/// Starts timing the scope `name`
+///
+/// The scope is measured in (32-bit) cycles, so it must end within a
+/// wrap of the counter (~89 s at 48 MHz).
+#[cfg(feature = "stm32")]
+pub fn scope(name: &'static str) -> Scope {
+    Scope {
+        name,
+        start: DWT::cycle_count(),
+    }
+}
+
+/// Times `f` as the scope `name`
+#[cfg(feature = "stm32")]
+pub fn measure<R>(name: &'static str, f: impl FnOnce() -> R) -> R {
+    let _scope = scope(name);
+    f()
+}
+
+/// The table and the cycles since the last dump, and resets the table
+#[cfg(feature = "stm32")]
+pub fn take() -> (Profiler, u32) {
+    interrupt::free(|cs| {
+        let mut profiler = PROFILER.borrow(cs).borrow_mut();
+        let taken = *profiler;
+        profiler.reset();
+        let now = DWT::cycle_count();
+        let period = now.wrapping_sub(SINCE.borrow(cs).replace(now));
+        (taken, period)
+    })
+}
+
+/// Sends the scopes since the last dump, one record at a time, and
+/// resets them
+///
+/// Returns false if `send` failed (e.g., the channel is full), the
+/// rest of the dump is dropped.
+#[cfg(feature = "stm32")]
+pub fn dump(mut send: impl FnMut(&[u8]) -> bool) -> bool {
+    let (profiler, period) = take();
+    for record in profiler.records(time::cycle_frequency(), period) {
+        if !send(&record.to_bytes()) {
+            return false;
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // the record of a scope measured once, for `cycles`
+    fn record(name: &'static str, cycles: u32) -> Record {
+        let mut profiler = Profiler::new();
+        profiler.record(name, cycles);
+        let record = profiler.records(48_000_000, 48_000).next();
+        record.unwrap()
+    }
+
+    fn decode(data: &[u8]) -> (Vec<Record>, u32) {
+        let mut decoder = Decoder::new();
+        let records = data.iter().filter_map(|b| decoder.push(*b)).collect();
+        (records, decoder.skipped())
+    }
+
+    #[test]
+    fn round_trip() {
+        let r = record("read_burst", 1234);
+        assert_eq!(Record::from_bytes(&r.to_bytes()), Some(r));
+        assert_eq!(r.name(), "read_burst");
+        assert_eq!((r.count, r.min, r.max, r.mean), (1, 1234, 1234, 1234));
+        assert!(r.is_last());
+
+        // names are truncated to `NAME_LEN`
+        let r = record("a scope name too long", 1);
+        assert_eq!(Record::from_bytes(&r.to_bytes()), Some(r));
+        assert_eq!(r.name(), "a scope name");
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        // the sync byte of profile records is 'Z' in text
+        let mut data = b"Zap log line\r\n".to_vec();
+        data.extend_from_slice(&record("poll", 1).to_bytes());
+        data.extend_from_slice(b"Z more");
+        data.extend_from_slice(&record("usb", 20).to_bytes());
+        let mut torn = record("torn", 3).to_bytes();
+        torn[20] ^= 0x01;
+        data.extend_from_slice(&torn);
+        data.extend_from_slice(&record("usb", 4).to_bytes());
+
+        let (records, skipped) = decode(&data);
+        assert_eq!(
+            records,
+            [record("poll", 1), record("usb", 20), record("usb", 4)]
+        );
+        assert_eq!(skipped, 14 + 6 + RECORD_LEN as u32);
+    }
+
+    #[test]
+    fn full_table_counts_missed() {
+        const NAMES: [&str; MAX_SCOPES + 1] = [
+            "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15",
+            "16",
+        ];
+        let mut profiler = Profiler::new();
+        for name in NAMES.iter() {
+            profiler.record(name, 10);
+            profiler.record(name, 30);
+        }
+        assert_eq!(profiler.scopes().count(), MAX_SCOPES);
+        assert_eq!(profiler.missed(), 2);
+        let (name, stats) = profiler.scopes().last().unwrap();
+        assert_eq!(name, "15");
+        assert_eq!(
+            (stats.count, stats.min, stats.max, stats.mean()),
+            (2, 10, 30, 20)
+        );
+
+        // the scopes are kept over a reset, the counts cleared
+        profiler.reset();
+        assert_eq!(profiler.missed(), 0);
+        let records: Vec<Record> = profiler.records(48_000_000, 1000).collect();
+        assert_eq!(records.len(), MAX_SCOPES);
+        assert!(records.iter().all(|r| r.count == 0 && r.min == 0));
+        assert!(records[MAX_SCOPES - 1].is_last());
+    }
+}
@@ -0,0 +1,337 @@
+//! User settings of the mouse
+//!
+//! Sensor configuration (resolution, lift-off distance, angle snapping and
+//! rest mode), the orientation of the sensor axes, the lift calibration of
+//! the motion filter, the report rate and the bindings of the side buttons.
+//! Settings are exchanged with the host as a vendor-defined HID feature
+//! report, see `usb::mouse`, and persisted in a flash `store::Store`.
+use crate::store::{self, Flash, Store};
+
+/// Size of the serialized settings in bytes
+pub const SIZE: usize = 28;
+
+// layout version, first byte of the serialized settings
+const VERSION: u8 = 3;
+// the previous layouts, without orientation and lift calibration, and
+// without bindings
+const VERSION_2: u8 = 2;
+const SIZE_2: usize = 24;
+const VERSION_1: u8 = 1;
+const SIZE_1: usize = 8;
+
+/// Number of key combinations in a `Binding::Keys` sequence
+pub const SEQUENCE: usize = 3;
+
+/// Lift-off detection distance
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lift {
+    Mm2 = 2,
+    Mm3 = 3,
+}
+
+/// What a (side) button does
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Binding {
+    /// The mouse button itself
+    Button,
+    /// Key combinations, `(modifiers, key)` as in the keyboard report (see
+    /// `usb::keyboard`), unused entries have key 0
+    ///
+    /// A single combination (e.g., Ctrl+C) is held as long as the button,
+    /// a sequence (a macro) is typed once when the button is pressed.
+    Keys([(u8, u8); SEQUENCE]),
+    /// A consumer control (media key) usage, e.g., 0xcd Play/Pause
+    Consumer(u16),
+}
+
+impl Binding {
+    /// Serialized binding
+    ///
+    /// | byte | field                                            |
+    /// | ---- | ------------------------------------------------ |
+    /// | 0    | kind, 0 button, 1 keys, 2 consumer               |
+    /// | 1-6  | keys, (modifiers, key) pairs                     |
+    /// | 1-2  | consumer, usage (little endian)                  |
+    /// | 7    | reserved (0)                                     |
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut data = [0; 8];
+        match self {
+            Binding::Button => {}
+            Binding::Keys(keys) => {
+                data[0] = 1;
+                for (i, (modifiers, key)) in keys.iter().enumerate() {
+                    data[1 + 2 * i] = *modifiers;
+                    data[2 + 2 * i] = *key;
+                }
+            }
+            Binding::Consumer(usage) => {
+                data[0] = 2;
+                data[1..3].copy_from_slice(&usage.to_le_bytes());
+            }
+        }
+        data
+    }
+
+    /// Parses a serialized binding, `None` if invalid
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 8 {
+            return None;
+        }
+        match data[0] {
+            0 => Some(Binding::Button),
+            1 => {
+                let mut keys = [(0, 0); SEQUENCE];
+                for (i, k) in keys.iter_mut().enumerate() {
+                    *k = (data[1 + 2 * i], data[2 + 2 * i]);
+                }
+                // at least one key
+                if keys[0].1 == 0 {
+                    return None;
+                }
+                Some(Binding::Keys(keys))
+            }
+            2 => match u16::from_le_bytes([data[1], data[2]]) {
+                0 => None,
+                usage => Some(Binding::Consumer(usage)),
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Orientation of the sensor axes, e.g., for a sensor mounted rotated
+///
+/// The axes are swapped first, then inverted, so a sensor rotated by 90
+/// degrees clockwise is `swap` and `invert_y`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Orientation {
+    /// Swap the x and y axes
+    pub swap: bool,
+    pub invert_x: bool,
+    pub invert_y: bool,
+}
+
+impl Orientation {
+    /// The motion `(dx, dy)` of the sensor in the axes of the mouse
+    pub fn apply(&self, dx: i16, dy: i16) -> (i16, i16) {
+        let (dx, dy) = if self.swap { (dy, dx) } else { (dx, dy) };
+        let invert = |v: i16, on: bool| if on { v.saturating_neg() } else { v };
+        (invert(dx, self.invert_x), invert(dy, self.invert_y))
+    }
+
+    /// Serialized, bit 0 swap, bit 1 invert x, bit 2 invert y
+    pub fn to_byte(&self) -> u8 {
+        self.swap as u8 | (self.invert_x as u8) << 1 | (self.invert_y as u8) << 2
+    }
+
+    /// Parses a serialized orientation, `None` if other bits are set
+    pub fn from_byte(b: u8) -> Option<Self> {
+        if b & !0x07 != 0 {
+            return None;
+        }
+        Some(Orientation {
+            swap: b & 0x01 != 0,
+            invert_x: b & 0x02 != 0,
+            invert_y: b & 0x04 != 0,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    /// Resolution in counts per inch, 50..=16000 in steps of 50
+    pub cpi: u16,
+    pub lift: Lift,
+    /// Snap near horizontal/vertical motion to the axes
+    pub angle_snap: bool,
+    /// Let the sensor enter rest mode (lower power) when not moving
+    pub rest: bool,
+    /// Report interval in ms (1 is 1 kHz)
+    pub polling_ms: u8,
+    pub orientation: Orientation,
+    /// Lift calibration, motion is dropped below this surface quality
+    /// (SQUAL), see `motion::FilterConfig`
+    pub lift_squal: u8,
+    /// Lift calibration, motion is dropped above this shutter time, 0
+    /// disables
+    pub lift_shutter: u16,
+    /// The back (4th) button
+    pub back: Binding,
+    /// The forward (5th) button
+    pub forward: Binding,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            cpi: 16000,
+            lift: Lift::Mm2,
+            angle_snap: false,
+            rest: false,
+            polling_ms: 1,
+            orientation: Orientation::default(),
+            // as `motion::FilterConfig::DEFAULT`
+            lift_squal: 16,
+            lift_shutter: 0,
+            back: Binding::Button,
+            forward: Binding::Button,
+        }
+    }
+}
+
+impl Settings {
+    /// Serialized settings, little endian
+    ///
+    /// | byte  | field                       |
+    /// | ----- | --------------------------- |
+    /// | 0     | version (3)                 |
+    /// | 1-2   | cpi                         |
+    /// | 3     | lift (mm)                   |
+    /// | 4     | angle snap (0/1)            |
+    /// | 5     | rest mode (0/1)             |
+    /// | 6     | polling interval (ms)       |
+    /// | 7     | orientation, see `Orientation::to_byte` |
+    /// | 8-15  | back button `Binding`       |
+    /// | 16-23 | forward button `Binding`    |
+    /// | 24    | lift calibration, SQUAL     |
+    /// | 25    | reserved (0)                |
+    /// | 26-27 | lift calibration, shutter   |
+    ///
+    /// Versions 2 (bytes 0-23, byte 7 reserved) and 1 (bytes 0-7) are still
+    /// accepted by `from_bytes`, with the defaults for the missing fields.
+    pub fn to_bytes(&self) -> [u8; SIZE] {
+        let cpi = self.cpi.to_le_bytes();
+        let mut data = [0; SIZE];
+        data[..8].copy_from_slice(&[
+            VERSION,
+            cpi[0],
+            cpi[1],
+            self.lift as u8,
+            self.angle_snap as u8,
+            self.rest as u8,
+            self.polling_ms,
+            self.orientation.to_byte(),
+        ]);
+        data[8..16].copy_from_slice(&self.back.to_bytes());
+        data[16..24].copy_from_slice(&self.forward.to_bytes());
+        data[24] = self.lift_squal;
+        data[26..28].copy_from_slice(&self.lift_shutter.to_le_bytes());
+        data
+    }
+
+    /// Parses serialized settings, `None` if invalid or out of range
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let version = match data.first() {
+            Some(&VERSION) if data.len() >= SIZE => VERSION,
+            Some(&VERSION_2) if data.len() >= SIZE_2 => VERSION_2,
+            Some(&VERSION_1) if data.len() >= SIZE_1 => VERSION_1,
+            _ => return None,
+        };
+
+        let cpi = u16::from_le_bytes([data[1], data[2]]);
+        if !(50..=16000).contains(&cpi) {
+            return None;
+        }
+
+        let lift = match data[3] {
+            2 => Lift::Mm2,
+            3 => Lift::Mm3,
+            _ => return None,
+        };
+
+        if data[6] == 0 {
+            return None;
+        }
+
+        let mut settings = Settings {
+            // the sensor resolution is in steps of 50 cpi
+            cpi: cpi / 50 * 50,
+            lift,
+            angle_snap: data[4] != 0,
+            rest: data[5] != 0,
+            polling_ms: data[6],
+            ..Settings::default()
+        };
+        if version >= VERSION_2 {
+            settings.back = Binding::from_bytes(&data[8..16])?;
+            settings.forward = Binding::from_bytes(&data[16..24])?;
+        }
+        if version >= VERSION {
+            settings.orientation = Orientation::from_byte(data[7])?;
+            settings.lift_squal = data[24];
+            settings.lift_shutter = u16::from_le_bytes([data[26], data[27]]);
+        }
+        Some(settings)
+    }
+
+    /// Loads the settings from `store`, the defaults if none (valid) are stored
+    ///
+    /// Settings saved in an older layout are picked up if there are none in
+    /// the current one.
+    pub fn load<F: Flash>(store: &mut Store<F>) -> Self {
+        let mut data = [0; SIZE];
+        [(VERSION, SIZE), (VERSION_2, SIZE_2), (VERSION_1, SIZE_1)]
+            .iter()
+            .find_map(|(version, size)| match store.load(*version, &mut data) {
+                Ok(Some(n)) if n == *size => Settings::from_bytes(&data[..n]),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// Saves the settings to `store`
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), store::Error<F::Error>> {
+        store.save(VERSION, &self.to_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let settings = Settings {
+            cpi: 1600,
+            orientation: Orientation {
+                swap: true,
+                invert_x: false,
+                invert_y: true,
+            },
+            lift_squal: 30,
+            lift_shutter: 2000,
+            forward: Binding::Consumer(0xcd),
+            ..Settings::default()
+        };
+        assert_eq!(Settings::from_bytes(&settings.to_bytes()), Some(settings));
+    }
+
+    #[test]
+    fn version_2_has_the_default_orientation_and_calibration() {
+        let current = Settings {
+            back: Binding::Consumer(0xcd),
+            ..Settings::default()
+        };
+        let mut data = current.to_bytes();
+        data[0] = VERSION_2;
+        // reserved in version 2
+        data[7] = 0xff;
+        assert_eq!(Settings::from_bytes(&data[..SIZE_2]), Some(current));
+        // and too short for the current version
+        data[0] = VERSION;
+        assert_eq!(Settings::from_bytes(&data[..SIZE_2]), None);
+    }
+
+    #[test]
+    fn orientation() {
+        let rotated = Orientation {
+            swap: true,
+            invert_x: false,
+            invert_y: true,
+        };
+        assert_eq!(rotated.apply(3, 1), (1, -3));
+        assert_eq!(rotated.apply(i16::MIN, 0), (0, i16::MAX));
+        assert_eq!(Orientation::from_byte(rotated.to_byte()), Some(rotated));
+        assert_eq!(Orientation::from_byte(0x08), None);
+    }
+}
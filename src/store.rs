@@ -0,0 +1,611 @@
+//! Record store on two flash sectors
+//!
+//! Records are appended one after the other to the active sector, and the
+//! most recent valid one is the current. When the active sector is full the
+//! record goes to the other (spare) sector, which becomes the active one,
+//! spreading the wear over both sectors (two 128K sectors hold some 16000
+//! settings records). On the STM32F411 these are the last two sectors, 6
+//! and 7, see `flash`.
+//!
+//! Each record is protected by a CRC, so a record torn by a reset (or power
+//! loss) while being written is skipped, and the previous one is used:
+//!
+//! | bytes   | field                                     |
+//! | ------- | ----------------------------------------- |
+//! | 0-1     | magic, `MAGIC`                            |
+//! | 2       | version (of the payload layout)           |
+//! | 3       | payload length `n`                        |
+//! | 4..     | payload, padded with `0xff` to 4 bytes    |
+//! | last 4  | CRC-32 of header and payload, little endian |
+//!
+//! A sector in use starts with a header, `SECTOR_MAGIC` and a generation
+//! (`u16`, little endian), one up on each switch, so the active sector is
+//! the one with the newer generation. The generation is written before the
+//! magic, a sector with a torn header is not in use.
+//!
+//! A sector is never erased while it may hold the only valid copy. On a
+//! switch the old sector is kept as is, and only erased by `erase_spare`
+//! once the active sector holds a valid record. Until then `load` falls
+//! back to the old sector, e.g., when the first record of the new sector
+//! was torn. Only the record being saved moves to the new sector, records
+//! of other versions (older layouts) are left behind.
+//!
+//! Erasing is slow (seconds for a 128K sector, see `flash`), `save` never
+//! erases. If the spare sector is not erased when the active one is full,
+//! `save` fails with `Error::Full` until `erase_spare` is called, e.g., at
+//! start up before the time critical parts run.
+//!
+//! The record logic only depends on the `Flash` trait, `MemFlash` models the
+//! flash in RAM (e.g., for testing on the host).
+
+const MAGIC: [u8; 2] = [0x53, 0x54];
+const HEADER: u32 = 4;
+const CRC: u32 = 4;
+
+const SECTOR_MAGIC: [u8; 2] = [0x53, 0x47];
+const SECTOR_HEADER: u32 = 4;
+
+/// The number of sectors of a store
+pub const SECTORS: usize = 2;
+
+/// The sectors of a store
+///
+/// Offsets are relative to the start of a sector. Erased memory reads
+/// `0xff`, and programming can only clear bits.
+pub trait Flash {
+    type Error;
+
+    /// Size of a sector in bytes
+    fn size(&self) -> u32;
+
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error>;
+
+    /// Programs `data` at `offset`, the memory is expected to be erased
+    fn write(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Erases `sector`
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error>;
+}
+
+/// Flash modelled in RAM
+///
+/// The memory is split in `SECTORS` sectors. Like real flash, writes only
+/// clear bits, so programming memory that is not erased corrupts it.
+pub struct MemFlash<'a> {
+    mem: &'a mut [u8],
+}
+
+impl<'a> MemFlash<'a> {
+    /// Uses `mem` as flash, the content is kept (i.e., not erased)
+    pub fn new(mem: &'a mut [u8]) -> Self {
+        MemFlash { mem }
+    }
+
+    fn start(&self, sector: usize, offset: u32) -> usize {
+        sector * self.size() as usize + offset as usize
+    }
+}
+
+impl Flash for MemFlash<'_> {
+    type Error = core::convert::Infallible;
+
+    fn size(&self) -> u32 {
+        (self.mem.len() / SECTORS) as u32
+    }
+
+    fn read(&mut self, sector: usize, offset: u32, buf: &mut [u8]) -> Result<(), Self::Error> {
+        let start = self.start(sector, offset);
+        buf.copy_from_slice(&self.mem[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), Self::Error> {
+        let start = self.start(sector, offset);
+        for (m, d) in self.mem[start..start + data.len()].iter_mut().zip(data) {
+            *m &= *d;
+        }
+        Ok(())
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error> {
+        let start = self.start(sector, 0);
+        let end = start + self.size() as usize;
+        for m in self.mem[start..end].iter_mut() {
+            *m = 0xff;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error<E> {
+    Flash(E),
+    /// The active sector is full and the spare is not erased
+    Full,
+}
+
+// the state of a sector, as found when opened
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Sector {
+    Erased,
+    InUse(u16),
+    // not erased and not in use (e.g., a torn header or an interrupted erase)
+    Dirty,
+}
+
+// a scan of a sector
+struct Scan {
+    // offset of the first free (erased) byte, the sector size if full
+    head: u32,
+    // the payload length of the last valid record of the version
+    found: Option<usize>,
+    // a valid record (of any version)
+    valid: bool,
+}
+
+pub struct Store<F> {
+    flash: F,
+    sectors: [Sector; SECTORS],
+    // the sector appended to, `None` if no sector is in use
+    active: Option<usize>,
+    // the first free byte of the active sector
+    head: u32,
+    // the active sector holds a valid record
+    valid: bool,
+}
+
+impl<F> Store<F>
+where
+    F: Flash,
+{
+    /// Opens the store, finding the active sector and its free space
+    pub fn new(flash: F) -> Result<Self, Error<F::Error>> {
+        let mut store = Store {
+            flash,
+            sectors: [Sector::Dirty; SECTORS],
+            active: None,
+            head: 0,
+            valid: false,
+        };
+        for sector in 0..SECTORS {
+            store.sectors[sector] = store.sector(sector)?;
+        }
+        store.active = match store.sectors {
+            [Sector::InUse(a), Sector::InUse(b)] => Some(if newer(b, a) { 1 } else { 0 }),
+            [Sector::InUse(_), _] => Some(0),
+            [_, Sector::InUse(_)] => Some(1),
+            _ => None,
+        };
+        if let Some(active) = store.active {
+            let scan = store.scan(active, None, &mut [])?;
+            store.head = scan.head;
+            store.valid = scan.valid;
+        }
+        Ok(store)
+    }
+
+    /// Releases the flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    /// Loads the most recent valid record of `version` into `data`
+    ///
+    /// Returns the payload length, `None` if there is no such record (or
+    /// its payload does not fit `data`). The active sector is searched
+    /// first, then the previous one (if not erased).
+    pub fn load(&mut self, version: u8, data: &mut [u8]) -> Result<Option<usize>, Error<F::Error>> {
+        let active = match self.active {
+            Some(active) => active,
+            None => return Ok(None),
+        };
+        let found = self.scan(active, Some(version), data)?.found;
+        let other = SECTORS - 1 - active;
+        if found.is_none() && matches!(self.sectors[other], Sector::InUse(_)) {
+            return Ok(self.scan(other, Some(version), data)?.found);
+        }
+        Ok(found)
+    }
+
+    /// Appends a record, switching to the spare sector if the active one is
+    /// full
+    ///
+    /// Nothing is written if the most recent record in the active sector is
+    /// identical. Fails with `Error::Full` if a switch is needed, and the
+    /// spare is not erased.
+    pub fn save(&mut self, version: u8, data: &[u8]) -> Result<(), Error<F::Error>> {
+        assert!(data.len() <= 255);
+        // only a copy in the active sector counts, the other may be erased
+        if let Some(active) = self.active {
+            let mut current = [0; 255];
+            if let Some(n) = self.scan(active, Some(version), &mut current)?.found {
+                if &current[..n] == data {
+                    return Ok(());
+                }
+            }
+        }
+
+        let size = record_size(data.len() as u8);
+        match self.active {
+            Some(_) if self.head + size <= self.flash.size() => {}
+            _ => self.switch()?,
+        }
+
+        let header = [MAGIC[0], MAGIC[1], version, data.len() as u8];
+        let pad = [0xff; 3];
+        let pad = &pad[..(4 - data.len() % 4) % 4];
+
+        let mut crc = crc32(0xffff_ffff, &header);
+        crc = crc32(crc, data);
+        crc = crc32(crc, pad);
+
+        let sector = self.active.unwrap_or(0);
+        let offset = self.head;
+        self.write(sector, offset, &header)?;
+        self.write(sector, offset + HEADER, data)?;
+        self.write(sector, offset + size - CRC, &(!crc).to_le_bytes())?;
+        self.head += size;
+        self.valid = true;
+        Ok(())
+    }
+
+    /// True if there is a sector to erase, see `erase_spare`
+    pub fn needs_erase(&self) -> bool {
+        self.spare().is_some()
+    }
+
+    /// Erases the sector not in use, if the active sector holds a valid
+    /// record (or no sector is in use)
+    ///
+    /// Erasing takes long (and stalls the CPU on the target), call it where
+    /// that does not matter.
+    pub fn erase_spare(&mut self) -> Result<(), Error<F::Error>> {
+        while let Some(sector) = self.spare() {
+            self.flash.erase(sector).map_err(Error::Flash)?;
+            self.sectors[sector] = Sector::Erased;
+        }
+        Ok(())
+    }
+
+    // a sector to erase, never one that may hold the only valid record
+    fn spare(&self) -> Option<usize> {
+        if self.active.is_some() && !self.valid {
+            return None;
+        }
+        (0..SECTORS).find(|s| Some(*s) != self.active && self.sectors[*s] != Sector::Erased)
+    }
+
+    // takes an erased sector into use, one generation up from the active
+    fn switch(&mut self) -> Result<(), Error<F::Error>> {
+        let (sector, generation) = match self.active {
+            Some(active) => match self.sectors[active] {
+                Sector::InUse(generation) => (SECTORS - 1 - active, generation.wrapping_add(1)),
+                _ => (SECTORS - 1 - active, 0),
+            },
+            None => match self.sectors.iter().position(|s| *s == Sector::Erased) {
+                Some(sector) => (sector, 0),
+                None => return Err(Error::Full),
+            },
+        };
+        if self.sectors[sector] != Sector::Erased {
+            return Err(Error::Full);
+        }
+
+        // the generation first, a torn header leaves the sector unused
+        self.write(sector, 2, &generation.to_le_bytes())?;
+        self.write(sector, 0, &SECTOR_MAGIC)?;
+        self.sectors[sector] = Sector::InUse(generation);
+        self.active = Some(sector);
+        self.head = SECTOR_HEADER;
+        self.valid = false;
+        Ok(())
+    }
+
+    fn write(&mut self, sector: usize, offset: u32, data: &[u8]) -> Result<(), Error<F::Error>> {
+        self.flash.write(sector, offset, data).map_err(Error::Flash)
+    }
+
+    // the state of `sector`, an erased sector is checked to the end
+    fn sector(&mut self, sector: usize) -> Result<Sector, Error<F::Error>> {
+        let mut header = [0; SECTOR_HEADER as usize];
+        self.flash
+            .read(sector, 0, &mut header)
+            .map_err(Error::Flash)?;
+        if header[..2] == SECTOR_MAGIC {
+            return Ok(Sector::InUse(u16::from_le_bytes([header[2], header[3]])));
+        }
+
+        let mut buf = [0; 64];
+        let mut offset = 0;
+        while offset < self.flash.size() {
+            let n = buf.len().min((self.flash.size() - offset) as usize);
+            self.flash
+                .read(sector, offset, &mut buf[..n])
+                .map_err(Error::Flash)?;
+            if buf[..n].iter().any(|b| *b != 0xff) {
+                return Ok(Sector::Dirty);
+            }
+            offset += n as u32;
+        }
+        Ok(Sector::Erased)
+    }
+
+    // walks the records of `sector`, copying the last valid record of
+    // `version` into `data`
+    fn scan(
+        &mut self,
+        sector: usize,
+        version: Option<u8>,
+        data: &mut [u8],
+    ) -> Result<Scan, Error<F::Error>> {
+        let size = self.flash.size();
+        let mut offset = SECTOR_HEADER;
+        let mut scan = Scan {
+            head: size,
+            found: None,
+            valid: false,
+        };
+        let mut buf = [0; 255 + 3 + CRC as usize];
+
+        while offset + HEADER <= size {
+            let mut header = [0; HEADER as usize];
+            self.flash
+                .read(sector, offset, &mut header)
+                .map_err(Error::Flash)?;
+            if header == [0xff; HEADER as usize] {
+                scan.head = offset;
+                break;
+            }
+            if header[..2] != MAGIC {
+                // not a record, no more space to use until erased
+                break;
+            }
+
+            let len = header[3];
+            let rec = record_size(len);
+            if offset + rec > size {
+                break;
+            }
+
+            let body = &mut buf[..(rec - HEADER) as usize];
+            self.flash
+                .read(sector, offset + HEADER, body)
+                .map_err(Error::Flash)?;
+            let (payload, stored) = body.split_at(body.len() - CRC as usize);
+            let crc = !crc32(crc32(0xffff_ffff, &header), payload);
+            let valid = crc.to_le_bytes() == stored;
+
+            scan.valid |= valid;
+            if valid && Some(header[2]) == version && len as usize <= data.len() {
+                data[..len as usize].copy_from_slice(&payload[..len as usize]);
+                scan.found = Some(len as usize);
+            }
+            offset += rec;
+        }
+
+        Ok(scan)
+    }
+}
+
+// true if generation `a` is after `b`, across the wrap
+fn newer(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) > 0
+}
+
+fn record_size(len: u8) -> u32 {
+    HEADER + (len as u32).div_ceil(4) * 4 + CRC
+}
+
+// CRC-32 (IEEE 802.3), bitwise, without the final inversion
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec;
+    use std::vec::Vec;
+
+    // two sectors of 64 bytes, a 60 byte record area each
+    const MEM: usize = 2 * 64;
+
+    fn store(mem: &mut [u8]) -> Store<MemFlash<'_>> {
+        Store::new(MemFlash::new(mem)).unwrap()
+    }
+
+    fn load(mem: &mut [u8], version: u8) -> Option<Vec<u8>> {
+        let mut data = [0; 255];
+        let n = store(mem).load(version, &mut data).unwrap()?;
+        Some(data[..n].to_vec())
+    }
+
+    #[test]
+    fn empty_store_has_no_record() {
+        let mut mem = [0xff; MEM];
+        assert_eq!(load(&mut mem, 1), None);
+        assert!(!store(&mut mem).needs_erase());
+    }
+
+    #[test]
+    fn latest_record_of_each_version() {
+        let mut mem = [0xff; MEM];
+        let mut s = store(&mut mem);
+        s.save(1, b"one").unwrap();
+        s.save(2, b"two").unwrap();
+        s.save(2, b"deux").unwrap();
+        assert_eq!(load(&mut mem, 1), Some(b"one".to_vec()));
+        assert_eq!(load(&mut mem, 2), Some(b"deux".to_vec()));
+        assert_eq!(load(&mut mem, 3), None);
+    }
+
+    #[test]
+    fn identical_save_is_not_written() {
+        let mut mem = [0xff; MEM];
+        let mut s = store(&mut mem);
+        s.save(2, b"same").unwrap();
+        let head = s.head;
+        s.save(2, b"same").unwrap();
+        assert_eq!(s.head, head);
+        s.save(2, b"other").unwrap();
+        assert_eq!(s.head, head + record_size(5));
+    }
+
+    #[test]
+    fn torn_record_falls_back_to_the_previous() {
+        let mut mem = [0xff; MEM];
+        store(&mut mem).save(2, b"old").unwrap();
+        let head = store(&mut mem).head as usize;
+        store(&mut mem).save(2, b"new!").unwrap();
+        // the power failed before the CRC was written
+        let end = head + record_size(4) as usize;
+        mem[end - CRC as usize..end].fill(0xff);
+
+        assert_eq!(load(&mut mem, 2), Some(b"old".to_vec()));
+        // the next record goes after the torn one
+        let mut s = store(&mut mem);
+        assert_eq!(s.head as usize, end);
+        s.save(2, b"new!").unwrap();
+        assert_eq!(load(&mut mem, 2), Some(b"new!".to_vec()));
+    }
+
+    #[test]
+    fn crc_mismatch_falls_back_to_the_previous() {
+        let mut mem = [0xff; MEM];
+        store(&mut mem).save(2, b"old").unwrap();
+        let head = store(&mut mem).head as usize;
+        store(&mut mem).save(2, b"new").unwrap();
+        mem[head + HEADER as usize] &= !0x40;
+        assert_eq!(load(&mut mem, 2), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn settings_fall_back_to_version_1() {
+        use crate::settings::{Lift, Settings};
+
+        let mut mem = [0xff; MEM];
+        // cpi 800, lift 3 mm, angle snap, no rest, 2 ms
+        store(&mut mem)
+            .save(1, &[1, 0x20, 0x03, 3, 1, 0, 2, 0])
+            .unwrap();
+        let settings = Settings::load(&mut store(&mut mem));
+        assert_eq!(
+            settings,
+            Settings {
+                cpi: 800,
+                lift: Lift::Mm3,
+                angle_snap: true,
+                polling_ms: 2,
+                ..Settings::default()
+            }
+        );
+
+        // saved in the current layout, preferred from then on
+        let changed = Settings {
+            cpi: 1600,
+            ..settings
+        };
+        changed.save(&mut store(&mut mem)).unwrap();
+        assert_eq!(Settings::load(&mut store(&mut mem)), changed);
+    }
+
+    #[test]
+    fn full_sector_switches_to_the_spare() {
+        let mut mem = [0xff; MEM];
+        let mut s = store(&mut mem);
+        // 5 records of 12 bytes fill the 60 byte record area
+        for i in 0..5u8 {
+            s.save(2, &[i; 4]).unwrap();
+        }
+        assert_eq!(s.active, Some(0));
+        s.save(2, &[5; 4]).unwrap();
+        assert_eq!(s.active, Some(1));
+        // the old sector is kept until erased
+        assert!(s.needs_erase());
+        assert_eq!(mem[SECTOR_HEADER as usize..][..2], MAGIC);
+        assert_eq!(load(&mut mem, 2), Some(vec![5; 4]));
+
+        store(&mut mem).erase_spare().unwrap();
+        assert!(mem[..64].iter().all(|b| *b == 0xff));
+        assert_eq!(load(&mut mem, 2), Some(vec![5; 4]));
+
+        // and back to the first sector, one generation up
+        let mut s = store(&mut mem);
+        for i in 6..11u8 {
+            s.save(2, &[i; 4]).unwrap();
+        }
+        assert_eq!(s.active, Some(0));
+        assert_eq!(s.sectors[0], Sector::InUse(2));
+        assert_eq!(load(&mut mem, 2), Some(vec![10; 4]));
+    }
+
+    #[test]
+    fn full_without_an_erased_spare() {
+        let mut mem = [0xff; MEM];
+        let mut s = store(&mut mem);
+        for i in 0..10u8 {
+            s.save(2, &[i; 4]).unwrap();
+        }
+        // both sectors in use, the spare was never erased
+        assert_eq!(s.save(2, &[10; 4]), Err(Error::Full));
+        s.erase_spare().unwrap();
+        s.save(2, &[10; 4]).unwrap();
+        assert_eq!(load(&mut mem, 2), Some(vec![10; 4]));
+    }
+
+    #[test]
+    fn torn_switch_keeps_the_old_sector() {
+        let mut mem = [0xff; MEM];
+        let mut s = store(&mut mem);
+        for i in 0..6u8 {
+            s.save(2, &[i; 4]).unwrap();
+        }
+        // the first record of the new sector was torn
+        let end = 64 + (SECTOR_HEADER + record_size(4)) as usize;
+        mem[end - CRC as usize..end].fill(0xff);
+
+        assert_eq!(load(&mut mem, 2), Some(vec![4; 4]));
+        let mut s = store(&mut mem);
+        assert_eq!(s.active, Some(1));
+        assert!(!s.needs_erase());
+        s.erase_spare().unwrap();
+        assert_eq!(load(&mut mem, 2), Some(vec![4; 4]));
+
+        // a valid record in the new sector releases the old one
+        let mut s = store(&mut mem);
+        s.save(2, &[4; 4]).unwrap();
+        assert!(s.needs_erase());
+    }
+
+    #[test]
+    fn torn_sector_header_is_not_in_use() {
+        let mut mem = [0xff; MEM];
+        store(&mut mem).save(2, b"old").unwrap();
+        // a switch to sector 1 torn after the generation
+        mem[64 + 2..64 + 4].copy_from_slice(&1u16.to_le_bytes());
+
+        let mut s = store(&mut mem);
+        assert_eq!(s.active, Some(0));
+        assert_eq!(s.sectors[1], Sector::Dirty);
+        s.erase_spare().unwrap();
+        assert_eq!(s.sectors[1], Sector::Erased);
+        assert_eq!(load(&mut mem, 2), Some(b"old".to_vec()));
+    }
+
+    #[test]
+    fn generations_wrap() {
+        assert!(newer(1, 0));
+        assert!(newer(0, u16::MAX));
+        assert!(!newer(u16::MAX, 0));
+    }
+}
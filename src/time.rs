@@ -0,0 +1,713 @@
+//! Timekeeping on the DWT cycle counter
+//!
+//! The cycle counter (CYCCNT) is 32 bits and wraps every 2^32 cycles of
+//! HCLK, ~89 s at the 48 MHz the USB firmware clocks the STM32F411 at (~43 s
+//! at its 100 MHz maximum). `now` extends it to 64 bits, counting the wraps,
+//! so an `Instant` never wraps in practice (12000 years at 48 MHz). The
+//! wraps are seen by reading the counter, `now` must be called at least
+//! once per wrap period (any periodic task or delay does). The extension is
+//! a `Counter`, fed the 32-bit counts.
+//!
+//! A `Duration` is a number of cycles, converted from and to time units by
+//! the core clock (HCLK) frequency, set by `init` (or `set_frequency`, the
+//! default is the 16 MHz reset clock). The conversions and `delay` are
+//! computed in 64 bits, so delays of any length are exact.
+//!
+//! Instants and durations count cycles of the full speed clock. While the
+//! clock is divided (`switch_clock`, e.g., by `power` while the USB bus is
+//! suspended) each cycle of the counter counts as `divider` cycles, so time
+//! runs on across the switch. The counter itself then runs at
+//! `cycle_frequency`, the rate to convert raw cycle counts by (e.g., RTIC
+//! `cyccnt` or profile times).
+//!
+//! `Instant`, `Duration` and `Deadline` are plain values, on the host they
+//! are built from cycle counts (`Instant::from_cycles`).
+//!
+//! `Timer` is a (periodic) `CountDown` on the cycle counter, `StdTimer` the
+//! same on a (std) host. `poll_until` polls a non-blocking operation until
+//! it completes or a count down expires, so a driver can wait on hardware
+//! without hanging when it does not answer.
+use core::ops::{Add, AddAssign, Sub, SubAssign};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embedded_hal::timer::CountDown;
+#[cfg(any(feature = "stm32", feature = "std"))]
+use embedded_hal::timer::Periodic;
+#[cfg(any(feature = "stm32", feature = "std"))]
+use void::Void;
+
+#[cfg(feature = "stm32")]
+use stm32f4xx_hal::{rcc::Clocks, stm32};
+
+// the core clock frequency, in Hz
+static FREQUENCY: AtomicU32 = AtomicU32::new(16_000_000);
+// the core clock divider, relative to `FREQUENCY`
+static DIVIDER: AtomicU32 = AtomicU32::new(1);
+
+/// The core clock frequency in Hz at full speed, the rate of `Instant`s
+/// and `Duration`s
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Sets the core clock frequency at full speed
+pub fn set_frequency(hz: u32) {
+    FREQUENCY.store(hz.max(1), Ordering::Relaxed);
+}
+
+/// The rate of the cycle counter in Hz, `frequency` over the divider
+pub fn cycle_frequency() -> u32 {
+    (frequency() / divider()).max(1)
+}
+
+fn divider() -> u32 {
+    DIVIDER.load(Ordering::Relaxed)
+}
+
+/// Switches the core clock to `frequency / divider`, by `switch` (e.g.,
+/// changing the AHB prescaler)
+///
+/// The cycles counted before are taken at the old divider, those after at
+/// the new, so instants and durations stay right across the switch.
+#[cfg(feature = "stm32")]
+pub fn switch_clock(divider: u32, switch: impl FnOnce()) {
+    cortex_m::interrupt::free(|_| {
+        now();
+        switch();
+        DIVIDER.store(divider.max(1), Ordering::Relaxed);
+    });
+}
+
+/// Enables the cycle counter, and sets the frequency to HCLK
+#[cfg(feature = "stm32")]
+pub fn init(dwt: &mut stm32::DWT, clocks: &Clocks) {
+    // required on Cortex-M7 devices that software lock the DWT (e.g. STM32F7)
+    stm32::DWT::unlock();
+    dwt.enable_cycle_counter();
+    set_frequency(clocks.hclk().0);
+}
+
+/// Extends a 32-bit cycle count to 64 bits, counting the wraps
+///
+/// `update` must see the count at least once per wrap period.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counter {
+    cycles: u64,
+    last: u32,
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Counter { cycles: 0, last: 0 }
+    }
+
+    /// The instant of the count `low`, the cycles since the last count
+    /// taken `divider` times
+    pub fn update(&mut self, low: u32, divider: u32) -> Instant {
+        let elapsed = low.wrapping_sub(self.last);
+        self.last = low;
+        self.cycles = self.cycles.wrapping_add(elapsed as u64 * divider as u64);
+        Instant::from_cycles(self.cycles)
+    }
+}
+
+/// The current time
+#[cfg(feature = "stm32")]
+pub fn now() -> Instant {
+    static mut COUNTER: Counter = Counter::new();
+
+    cortex_m::interrupt::free(|_| {
+        let low = stm32::DWT::cycle_count();
+        // safe, only accessed within the critical section
+        unsafe { (*core::ptr::addr_of_mut!(COUNTER)).update(low, divider()) }
+    })
+}
+
+/// Waits for `duration`
+#[cfg(feature = "stm32")]
+pub fn delay(duration: Duration) {
+    let deadline = Deadline::after(duration);
+    while !deadline.is_expired() {}
+}
+
+/// The operation did not complete in time, or failed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeoutError<E> {
+    Timeout,
+    Other(E),
+}
+
+/// Polls `poll` until it completes or fails, or `timer` counts down
+/// `timeout`
+///
+/// `poll` is called at least once, and again as long as it returns
+/// `nb::Error::WouldBlock`.
+pub fn poll_until<C, T, E>(
+    timer: &mut C,
+    timeout: impl Into<C::Time>,
+    mut poll: impl FnMut() -> nb::Result<T, E>,
+) -> Result<T, TimeoutError<E>>
+where
+    C: CountDown,
+{
+    timer.start(timeout);
+    loop {
+        match poll() {
+            Ok(t) => return Ok(t),
+            Err(nb::Error::Other(e)) => return Err(TimeoutError::Other(e)),
+            Err(nb::Error::WouldBlock) => {}
+        }
+        if timer.wait().is_ok() {
+            return Err(TimeoutError::Timeout);
+        }
+    }
+}
+
+/// Polls `poll` until it completes or fails, for at most `timeout`
+#[cfg(feature = "stm32")]
+pub fn with_timeout<T, E>(
+    timeout: Duration,
+    poll: impl FnMut() -> nb::Result<T, E>,
+) -> Result<T, TimeoutError<E>> {
+    poll_until(&mut Timer::new(), timeout, poll)
+}
+
+/// A periodic count down on the cycle counter
+///
+/// `wait` returns `Ok` once per period, the next period counts from the end
+/// of the last (not from `wait`), so the timer does not drift. A timer not
+/// started never expires.
+#[cfg(feature = "stm32")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Timer {
+    deadline: Option<Instant>,
+    period: Duration,
+}
+
+#[cfg(feature = "stm32")]
+impl Timer {
+    pub const fn new() -> Self {
+        Timer {
+            deadline: None,
+            period: Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(feature = "stm32")]
+impl CountDown for Timer {
+    type Time = Duration;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Duration>,
+    {
+        self.period = count.into();
+        self.deadline = Some(now() + self.period);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        match self.deadline {
+            Some(deadline) if now() >= deadline => {
+                self.deadline = Some(deadline + self.period);
+                Ok(())
+            }
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+#[cfg(feature = "stm32")]
+impl Periodic for Timer {}
+
+/// A periodic count down, and delays, on a (std) host
+///
+/// The host counterpart of `Timer` and `DwtDelay`, for drivers that need
+/// both. Durations are converted by `frequency`, as on the target.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdTimer {
+    deadline: Option<std::time::Instant>,
+    period: std::time::Duration,
+}
+
+#[cfg(feature = "std")]
+impl StdTimer {
+    pub fn new() -> Self {
+        StdTimer::default()
+    }
+}
+
+#[cfg(feature = "std")]
+impl CountDown for StdTimer {
+    type Time = Duration;
+
+    fn start<T>(&mut self, count: T)
+    where
+        T: Into<Duration>,
+    {
+        self.period = std::time::Duration::from_micros(count.into().as_micros());
+        self.deadline = Some(std::time::Instant::now() + self.period);
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Void> {
+        match self.deadline {
+            Some(deadline) if std::time::Instant::now() >= deadline => {
+                self.deadline = Some(deadline + self.period);
+                Ok(())
+            }
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Periodic for StdTimer {}
+
+#[cfg(feature = "std")]
+impl embedded_hal::blocking::delay::DelayUs<u32> for StdTimer {
+    fn delay_us(&mut self, us: u32) {
+        std::thread::sleep(std::time::Duration::from_micros(us as u64));
+    }
+}
+
+#[cfg(feature = "std")]
+impl embedded_hal::blocking::delay::DelayMs<u32> for StdTimer {
+    fn delay_ms(&mut self, ms: u32) {
+        std::thread::sleep(std::time::Duration::from_millis(ms as u64));
+    }
+}
+
+/// A span of time, in cycles of the core clock
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Duration {
+    cycles: u64,
+}
+
+impl Duration {
+    pub const ZERO: Duration = Duration { cycles: 0 };
+    pub const MAX: Duration = Duration { cycles: u64::MAX };
+
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Duration { cycles }
+    }
+
+    pub fn from_micros(us: u64) -> Self {
+        // short delays are common, spare them the 64-bit division
+        let hz = frequency();
+        let mhz = hz / 1_000_000;
+        if mhz * 1_000_000 == hz {
+            Duration::from_cycles(us.saturating_mul(mhz as u64))
+        } else {
+            Self::from_units(us, 1_000_000)
+        }
+    }
+
+    pub fn from_millis(ms: u64) -> Self {
+        Self::from_units(ms, 1_000)
+    }
+
+    pub fn from_secs(s: u64) -> Self {
+        Self::from_units(s, 1)
+    }
+
+    pub const fn as_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The duration in us, rounded down
+    pub fn as_micros(&self) -> u64 {
+        self.as_units(1_000_000)
+    }
+
+    /// The duration in ms, rounded down
+    pub fn as_millis(&self) -> u64 {
+        self.as_units(1_000)
+    }
+
+    /// The duration in seconds
+    pub fn as_secs_f32(&self) -> f32 {
+        self.cycles as f32 / frequency() as f32
+    }
+
+    pub fn checked_add(self, rhs: Duration) -> Option<Duration> {
+        self.cycles
+            .checked_add(rhs.cycles)
+            .map(Duration::from_cycles)
+    }
+
+    pub fn checked_sub(self, rhs: Duration) -> Option<Duration> {
+        self.cycles
+            .checked_sub(rhs.cycles)
+            .map(Duration::from_cycles)
+    }
+
+    pub fn saturating_sub(self, rhs: Duration) -> Duration {
+        Duration::from_cycles(self.cycles.saturating_sub(rhs.cycles))
+    }
+
+    // n units of 1/`per_sec` seconds, split in whole seconds and the rest
+    // so the products can not overflow (saturating at `MAX`)
+    fn from_units(n: u64, per_sec: u64) -> Self {
+        let hz = frequency() as u64;
+        let cycles = (n / per_sec)
+            .saturating_mul(hz)
+            .saturating_add((n % per_sec) * hz / per_sec);
+        Duration { cycles }
+    }
+
+    fn as_units(&self, per_sec: u64) -> u64 {
+        let hz = frequency() as u64;
+        (self.cycles / hz)
+            .saturating_mul(per_sec)
+            .saturating_add((self.cycles % hz) * per_sec / hz)
+    }
+}
+
+impl Add for Duration {
+    type Output = Duration;
+
+    fn add(self, rhs: Duration) -> Duration {
+        self.checked_add(rhs).expect("overflow adding durations")
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        self.checked_sub(rhs)
+            .expect("overflow subtracting durations")
+    }
+}
+
+/// A point in time, cycles since the counter was enabled
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    cycles: u64,
+}
+
+impl Instant {
+    pub const fn from_cycles(cycles: u64) -> Self {
+        Instant { cycles }
+    }
+
+    pub const fn as_cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// The time since `earlier`, zero if `earlier` is later
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_cycles(self.cycles.saturating_sub(earlier.cycles))
+    }
+
+    /// The time since `earlier`, `None` if `earlier` is later
+    pub fn checked_duration_since(&self, earlier: Instant) -> Option<Duration> {
+        self.cycles
+            .checked_sub(earlier.cycles)
+            .map(Duration::from_cycles)
+    }
+
+    /// The time since this instant
+    #[cfg(feature = "stm32")]
+    pub fn elapsed(&self) -> Duration {
+        now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant::from_cycles(self.cycles.saturating_add(rhs.cycles))
+    }
+}
+
+impl AddAssign<Duration> for Instant {
+    fn add_assign(&mut self, rhs: Duration) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, rhs: Duration) -> Instant {
+        Instant::from_cycles(self.cycles.saturating_sub(rhs.cycles))
+    }
+}
+
+impl SubAssign<Duration> for Instant {
+    fn sub_assign(&mut self, rhs: Duration) {
+        *self = *self - rhs;
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// A point in time to wait for, or give up at
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadline {
+    at: Instant,
+}
+
+impl Deadline {
+    pub const fn at(at: Instant) -> Self {
+        Deadline { at }
+    }
+
+    /// The deadline `duration` from now
+    #[cfg(feature = "stm32")]
+    pub fn after(duration: Duration) -> Self {
+        Deadline::at(now() + duration)
+    }
+
+    pub fn instant(&self) -> Instant {
+        self.at
+    }
+
+    /// True if the deadline has passed at `now`
+    pub fn is_expired_at(&self, now: Instant) -> bool {
+        now >= self.at
+    }
+
+    /// The time left at `now`, zero if expired
+    pub fn remaining_at(&self, now: Instant) -> Duration {
+        self.at.duration_since(now)
+    }
+
+    /// True if the deadline has passed
+    #[cfg(feature = "stm32")]
+    pub fn is_expired(&self) -> bool {
+        self.is_expired_at(now())
+    }
+
+    /// The time left, zero if expired
+    #[cfg(feature = "stm32")]
+    pub fn remaining(&self) -> Duration {
+        self.remaining_at(now())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // the frequency is global, the tests setting it run one at a time
+    static FREQUENCY_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Runs `test` at the core clock frequency `hz`, for the tests of
+    /// other modules as well
+    pub(crate) fn at(hz: u32, test: impl FnOnce()) {
+        let _lock = FREQUENCY_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_frequency(hz);
+        test();
+    }
+
+    #[test]
+    fn conversions_at_48_mhz() {
+        at(48_000_000, || {
+            assert_eq!(Duration::from_micros(1).as_cycles(), 48);
+            assert_eq!(Duration::from_millis(1).as_cycles(), 48_000);
+            assert_eq!(Duration::from_secs(90).as_cycles(), 4_320_000_000);
+            assert_eq!(Duration::from_cycles(47).as_micros(), 0);
+            assert_eq!(Duration::from_cycles(48_047).as_micros(), 1000);
+            assert_eq!(
+                Duration::from_millis(u32::MAX as u64).as_millis(),
+                u32::MAX as u64
+            );
+            assert_eq!(Duration::from_micros(u64::MAX), Duration::MAX);
+        });
+    }
+
+    #[test]
+    fn conversions_at_a_fractional_mhz() {
+        // the 64-bit division path, 1 us is 16.384 cycles
+        at(16_384_000, || {
+            assert_eq!(Duration::from_micros(1).as_cycles(), 16);
+            assert_eq!(Duration::from_micros(1000).as_cycles(), 16_384);
+            assert_eq!(Duration::from_micros(1_000_500).as_cycles(), 16_392_192);
+            assert_eq!(Duration::from_cycles(16_384).as_micros(), 1000);
+            assert_eq!(
+                Duration::from_secs(u32::MAX as u64).as_secs_f32(),
+                u32::MAX as f32
+            );
+            assert_eq!(Duration::from_micros(u64::MAX), Duration::MAX);
+        });
+    }
+
+    #[test]
+    fn counter_extends_across_wraps() {
+        let mut counter = Counter::new();
+        assert_eq!(
+            counter.update(u32::MAX - 1, 1).as_cycles(),
+            u32::MAX as u64 - 1
+        );
+        assert_eq!(counter.update(1, 1).as_cycles(), 1 << 32 | 1);
+        assert_eq!(counter.update(1, 1).as_cycles(), 1 << 32 | 1);
+        assert_eq!(counter.update(0, 1).as_cycles(), 2 << 32);
+
+        let before = Instant::from_cycles(u32::MAX as u64 - 1);
+        let after = Counter {
+            cycles: u32::MAX as u64 - 1,
+            last: u32::MAX - 1,
+        }
+        .update(10, 1);
+        assert_eq!((after - before).as_cycles(), 12);
+    }
+
+    #[test]
+    fn counter_scales_a_divided_clock() {
+        let mut counter = Counter::new();
+        let before = counter.update(1000, 1);
+        // the clock is divided by 2 after 1000, and restored at 999, after
+        // a wrap of the counter
+        let divided = counter.update(u32::MAX, 2);
+        let restored = counter.update(999, 2);
+        let after = counter.update(1999, 1);
+        assert_eq!((divided - before).as_cycles(), 2 * (u32::MAX as u64 - 1000));
+        assert_eq!((restored - before).as_cycles(), 2 * u32::MAX as u64);
+        assert_eq!((after - restored).as_cycles(), 1000);
+    }
+
+    #[test]
+    fn cycle_frequency_follows_the_divider() {
+        at(48_000_000, || {
+            assert_eq!(cycle_frequency(), 48_000_000);
+            DIVIDER.store(2, Ordering::Relaxed);
+            assert_eq!(frequency(), 48_000_000);
+            assert_eq!(cycle_frequency(), 24_000_000);
+            assert_eq!(Duration::from_millis(1).as_cycles(), 48_000);
+            DIVIDER.store(1, Ordering::Relaxed);
+        });
+    }
+
+    // a periodic count down expiring after `period` waits
+    struct CountWaits {
+        period: u32,
+        left: u32,
+        starts: u32,
+    }
+
+    impl CountWaits {
+        fn new() -> Self {
+            CountWaits {
+                period: 0,
+                left: 0,
+                starts: 0,
+            }
+        }
+    }
+
+    impl CountDown for CountWaits {
+        type Time = u32;
+
+        fn start<T: Into<u32>>(&mut self, count: T) {
+            self.period = count.into();
+            self.left = self.period;
+            self.starts += 1;
+        }
+
+        fn wait(&mut self) -> nb::Result<(), Void> {
+            if self.left == 0 {
+                self.left = self.period;
+                return Ok(());
+            }
+            self.left -= 1;
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    // an operation completing (or failing) on poll `n`
+    fn ready_on<T: Copy, E: Copy>(
+        n: u32,
+        result: Result<T, E>,
+    ) -> impl FnMut() -> nb::Result<T, E> {
+        let mut polls = 0;
+        move || {
+            polls += 1;
+            match result {
+                _ if polls < n => Err(nb::Error::WouldBlock),
+                Ok(t) => Ok(t),
+                Err(e) => Err(nb::Error::Other(e)),
+            }
+        }
+    }
+
+    #[test]
+    fn poll_until_completes_in_time() {
+        let mut timer = CountWaits::new();
+        let done = poll_until(&mut timer, 5u32, ready_on::<_, ()>(1, Ok(7)));
+        assert_eq!(done, Ok(7));
+        let done = poll_until(&mut timer, 5u32, ready_on::<_, ()>(6, Ok(8)));
+        assert_eq!(done, Ok(8));
+        let failed = poll_until(&mut timer, 5u32, ready_on::<u8, _>(3, Err("nack")));
+        assert_eq!(failed, Err(TimeoutError::Other("nack")));
+    }
+
+    #[test]
+    fn poll_until_times_out() {
+        let mut timer = CountWaits::new();
+        let late = poll_until(&mut timer, 5u32, ready_on::<_, ()>(7, Ok(1)));
+        assert_eq!(late, Err(TimeoutError::Timeout));
+    }
+
+    #[test]
+    fn poll_until_restarts_the_timer() {
+        // the periodic timer expired once already, each call counts the
+        // whole timeout again
+        let mut timer = CountWaits::new();
+        let late = poll_until(&mut timer, 2u32, ready_on::<_, ()>(100, Ok(1)));
+        assert_eq!(late, Err(TimeoutError::Timeout));
+        let done = poll_until(&mut timer, 5u32, ready_on::<_, ()>(6, Ok(2)));
+        assert_eq!(done, Ok(2));
+        assert_eq!(timer.starts, 2);
+    }
+
+    #[test]
+    fn std_timer_times_out() {
+        at(48_000_000, || {
+            let started = std::time::Instant::now();
+            let mut timer = StdTimer::new();
+            let late = poll_until(&mut timer, Duration::from_millis(2), || {
+                Err::<(), _>(nb::Error::<()>::WouldBlock)
+            });
+            assert_eq!(late, Err(TimeoutError::Timeout));
+            assert!(started.elapsed() >= std::time::Duration::from_millis(2));
+            // periodic, the next period counts from the end of the last
+            assert!(timer.wait().is_err());
+        });
+    }
+
+    #[test]
+    fn instant_arithmetic_saturates() {
+        let early = Instant::from_cycles(100);
+        let late = Instant::from_cycles(250);
+        assert_eq!(late - early, Duration::from_cycles(150));
+        assert_eq!(early - late, Duration::ZERO);
+        assert_eq!(early.checked_duration_since(late), None);
+        assert_eq!(early - Duration::from_cycles(200), Instant::from_cycles(0));
+        assert_eq!(late + Duration::MAX, Instant::from_cycles(u64::MAX));
+        assert_eq!(
+            Duration::from_cycles(1).checked_sub(Duration::from_cycles(2)),
+            None
+        );
+        assert_eq!(Duration::MAX.checked_add(Duration::from_cycles(1)), None);
+
+        let deadline = Deadline::at(late);
+        assert!(!deadline.is_expired_at(early));
+        assert_eq!(deadline.remaining_at(early), Duration::from_cycles(150));
+        assert!(deadline.is_expired_at(late));
+        assert_eq!(
+            deadline.remaining_at(late + Duration::from_cycles(1)),
+            Duration::ZERO
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Motion traces, a compact binary record of the sensor motion
+//!
+//! The firmware streams one `Record` per sensor poll (over RTT or the CDC
+//! serial port), a host tool (`trace-capture`) saves them to a file, and
+//! `Replay` feeds a saved trace back as sensor bursts, into the motion
+//! filter and pipeline or the USB HID path, so tracking problems can be
+//! reproduced without the sensor.
+//!
+//! A record is 12 bytes:
+//!
+//! | bytes | content                                         |
+//! |-------|-------------------------------------------------|
+//! | 0     | `SYNC` (0xa5)                                   |
+//! | 1..5  | timestamp in us, little endian (wraps)          |
+//! | 5..7  | dx, little endian                               |
+//! | 7..9  | dy, little endian                               |
+//! | 9     | SQUAL                                           |
+//! | 10    | flags (`flags::MOTION`, ...)                    |
+//! | 11    | CRC-8 (polynomial 0x07) of bytes 0..11          |
+//!
+//! A file is just the records back to back. The stream has no other
+//! framing, the decoder looks for the sync byte and checks the CRC, so it
+//! recovers from lost bytes and skips other data on the same channel (e.g.,
+//! console text). Such data may contain the sync byte (0xa5 is a UTF-8
+//! continuation byte, e.g., in "¥"), the CRC rejects these false syncs and
+//! the decoder resyncs at the next sync byte.
+//!
+//! The framing (sync byte first, CRC-8 last) is shared with the records of
+//! `profile`, decoded by the same `FrameDecoder`.
+use core::marker::PhantomData;
+
+use crate::pmw3389::Burst;
+
+/// The size of a record
+pub const RECORD_LEN: usize = 12;
+
+/// The first byte of a record
+pub const SYNC: u8 = 0xa5;
+
+pub mod flags {
+    /// The sensor reported motion
+    pub const MOTION: u8 = 1 << 0;
+    /// The sensor was lifted off the surface
+    pub const LIFTED: u8 = 1 << 1;
+    /// The motion was dropped by the motion filter
+    pub const GATED: u8 = 1 << 2;
+    /// Records were lost (not sent) before this one
+    pub const DROPPED: u8 = 1 << 3;
+}
+
+/// A sensor sample
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Record {
+    pub time_us: u32,
+    pub dx: i16,
+    pub dy: i16,
+    pub squal: u8,
+    pub flags: u8,
+}
+
+impl Record {
+    /// A record of a sensor burst read at `time_us`
+    pub fn from_burst(time_us: u32, burst: &Burst) -> Self {
+        let mut flags = 0;
+        if burst.motion {
+            flags |= flags::MOTION;
+        }
+        if burst.lifted {
+            flags |= flags::LIFTED;
+        }
+        Record {
+            time_us,
+            dx: burst.dx,
+            dy: burst.dy,
+            squal: burst.squal,
+            flags,
+        }
+    }
+
+    /// The sensor burst of the record, the shutter and raw data
+    /// statistics are not recorded
+    pub fn burst(&self) -> Burst {
+        Burst {
+            motion: self.flags & flags::MOTION != 0,
+            lifted: self.flags & flags::LIFTED != 0,
+            dx: self.dx,
+            dy: self.dy,
+            squal: self.squal,
+            ..Burst::default()
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let time = self.time_us.to_le_bytes();
+        let dx = self.dx.to_le_bytes();
+        let dy = self.dy.to_le_bytes();
+        let mut bytes = [
+            SYNC, time[0], time[1], time[2], time[3], dx[0], dx[1], dy[0], dy[1], self.squal,
+            self.flags, 0,
+        ];
+        bytes[RECORD_LEN - 1] = crc8(&bytes[..RECORD_LEN - 1]);
+        bytes
+    }
+
+    /// The record, `None` if the sync byte or CRC is wrong
+    pub fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Option<Self> {
+        if bytes[0] != SYNC || crc8(&bytes[..RECORD_LEN - 1]) != bytes[RECORD_LEN - 1] {
+            return None;
+        }
+        Some(Record {
+            time_us: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]),
+            dx: i16::from_le_bytes([bytes[5], bytes[6]]),
+            dy: i16::from_le_bytes([bytes[7], bytes[8]]),
+            squal: bytes[9],
+            flags: bytes[10],
+        })
+    }
+}
+
+impl Framed<RECORD_LEN> for Record {
+    const SYNC: u8 = SYNC;
+
+    fn from_bytes(bytes: &[u8; RECORD_LEN]) -> Option<Self> {
+        Record::from_bytes(bytes)
+    }
+}
+
+/// Decodes records from a byte stream
+pub type Decoder = FrameDecoder<Record, RECORD_LEN>;
+
+/// A record of `N` bytes, framed by a sync byte (first) and a CRC-8 (last)
+pub trait Framed<const N: usize>: Sized {
+    /// The first byte of a record
+    const SYNC: u8;
+
+    /// The record, `None` if the sync byte or CRC is wrong
+    fn from_bytes(bytes: &[u8; N]) -> Option<Self>;
+}
+
+/// Decodes framed records from a byte stream
+///
+/// Bytes before a sync byte are skipped, and a record failing the CRC is
+/// dropped up to the next sync byte in it (a false sync).
+#[derive(Clone, Debug)]
+pub struct FrameDecoder<R, const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    skipped: u32,
+    record: PhantomData<R>,
+}
+
+impl<R: Framed<N>, const N: usize> FrameDecoder<R, N> {
+    pub const fn new() -> Self {
+        FrameDecoder {
+            buf: [0; N],
+            len: 0,
+            skipped: 0,
+            record: PhantomData,
+        }
+    }
+
+    /// Adds a byte of the stream, returns the record it completes
+    pub fn push(&mut self, b: u8) -> Option<R> {
+        if self.len == 0 && b != R::SYNC {
+            self.skipped += 1;
+            return None;
+        }
+        self.buf[self.len] = b;
+        self.len += 1;
+        if self.len < N {
+            return None;
+        }
+
+        if let Some(record) = R::from_bytes(&self.buf) {
+            self.len = 0;
+            return Some(record);
+        }
+        // not a record, resync at the next sync byte
+        let next = self.buf[1..]
+            .iter()
+            .position(|b| *b == R::SYNC)
+            .map_or(N, |i| i + 1);
+        self.buf.copy_within(next.., 0);
+        self.len = N - next;
+        self.skipped += next as u32;
+        None
+    }
+
+    /// The number of bytes skipped, not part of a valid record
+    pub fn skipped(&self) -> u32 {
+        self.skipped
+    }
+}
+
+impl<R: Framed<N>, const N: usize> Default for FrameDecoder<R, N> {
+    fn default() -> Self {
+        FrameDecoder::new()
+    }
+}
+
+/// Replays a recorded trace
+///
+/// An iterator over the records of the trace, or sensor bursts (as
+/// `read_burst` of the drivers) with the time since the previous one.
+pub struct Replay<'a> {
+    data: core::slice::Iter<'a, u8>,
+    decoder: Decoder,
+    last_us: Option<u32>,
+}
+
+impl<'a> Replay<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Replay {
+            data: data.iter(),
+            decoder: Decoder::new(),
+            last_us: None,
+        }
+    }
+
+    /// The next burst and the time since the previous one in us, the
+    /// first at `first_us`
+    pub fn next_burst(&mut self, first_us: u32) -> Option<(Burst, u32)> {
+        let record = self.next()?;
+        let dt_us = self
+            .last_us
+            .map_or(first_us, |last| record.time_us.wrapping_sub(last));
+        self.last_us = Some(record.time_us);
+        Some((record.burst(), dt_us))
+    }
+
+    /// The number of bytes skipped so far, not part of a valid record
+    pub fn skipped(&self) -> u32 {
+        self.decoder.skipped()
+    }
+}
+
+impl Iterator for Replay<'_> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        for b in &mut self.data {
+            if let Some(record) = self.decoder.push(*b) {
+                return Some(record);
+            }
+        }
+        None
+    }
+}
+
+// CRC-8, polynomial x^8 + x^2 + x + 1, no reflection
+pub(crate) fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        crc ^= b;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn record(time_us: u32, dx: i16) -> Record {
+        Record {
+            time_us,
+            dx,
+            dy: -dx,
+            squal: 50,
+            flags: flags::MOTION,
+        }
+    }
+
+    fn decode(data: &[u8]) -> (Vec<Record>, u32) {
+        let mut decoder = Decoder::new();
+        let records = data.iter().filter_map(|b| decoder.push(*b)).collect();
+        (records, decoder.skipped())
+    }
+
+    #[test]
+    fn round_trip() {
+        let r = record(0x1234_5678, -300);
+        assert_eq!(Record::from_bytes(&r.to_bytes()), Some(r));
+    }
+
+    #[test]
+    fn resync_after_garbage() {
+        let mut data = b"log line\r\n".to_vec();
+        data.extend_from_slice(&record(1, 1).to_bytes());
+        data.extend_from_slice(b"\xa5\xa5 more");
+        data.extend_from_slice(&record(2, 2).to_bytes());
+        let (records, skipped) = decode(&data);
+        assert_eq!(records, [record(1, 1), record(2, 2)]);
+        assert_eq!(skipped, 10 + 7);
+    }
+
+    #[test]
+    fn false_sync_in_utf8_text() {
+        // "¥" is c2 a5, the a5 starts a false record
+        let mut data = "price ¥100".as_bytes().to_vec();
+        data.extend_from_slice(&record(3, 3).to_bytes());
+        let (records, skipped) = decode(&data);
+        assert_eq!(records, [record(3, 3)]);
+        assert_eq!(skipped, "price ¥100".len() as u32);
+    }
+
+    #[test]
+    fn resync_after_bad_crc() {
+        let mut bad = record(1, 1).to_bytes();
+        bad[6] ^= 0x10;
+        let mut data = bad.to_vec();
+        data.extend_from_slice(&record(2, 2).to_bytes());
+        // a record cut short by lost bytes
+        data.extend_from_slice(&record(3, 3).to_bytes()[..7]);
+        data.extend_from_slice(&record(4, 4).to_bytes());
+        let (records, skipped) = decode(&data);
+        assert_eq!(records, [record(2, 2), record(4, 4)]);
+        assert_eq!(skipped, RECORD_LEN as u32 + 7);
+    }
+
+    #[test]
+    fn replay_time_wraps() {
+        let mut data = record(u32::MAX - 499, 1).to_bytes().to_vec();
+        data.extend_from_slice(&record(500, 2).to_bytes());
+        let mut replay = Replay::new(&data);
+        assert_eq!(
+            replay.next_burst(1000).map(|(b, dt)| (b.dx, dt)),
+            Some((1, 1000))
+        );
+        assert_eq!(
+            replay.next_burst(1000).map(|(b, dt)| (b.dx, dt)),
+            Some((2, 1000))
+        );
+        assert!(replay.next_burst(1000).is_none());
+    }
+}
@@ -0,0 +1,17 @@
+//! The 96-bit unique device ID of the STM32F4
+//!
+//! Factory programmed, read-only, at `0x1FFF_7A10` (RM0383, 24.1).
+
+const UID: *const u32 = 0x1fff_7a10 as *const u32;
+
+/// The unique device ID, as three 32-bit words (lowest address first)
+pub fn read() -> [u32; 3] {
+    // read-only system memory, always mapped
+    unsafe {
+        [
+            core::ptr::read_volatile(UID),
+            core::ptr::read_volatile(UID.add(1)),
+            core::ptr::read_volatile(UID.add(2)),
+        ]
+    }
+}
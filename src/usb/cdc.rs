@@ -0,0 +1,275 @@
+//! USB CDC-ACM (serial port) class
+//!
+//! A minimal CDC-ACM function, two interfaces (communication and data)
+//! grouped by an interface association descriptor, so it can be combined
+//! with other classes in a composite device (build the device with
+//! `UsbDeviceBuilder::composite_with_iads`). On Linux the port shows up as
+//! `/dev/ttyACM<n>`.
+//!
+//! Output is queued in a small buffer and sent as the host collects it, and
+//! only while a terminal has the port open (DTR set), so logging to a port
+//! nobody listens to does not block.
+//!
+//! Kept in the crate rather than using `usbd-serial`: its `SerialPort`
+//! queues output whether or not a terminal is attached, and has no way to
+//! query the free space of its buffer, which the motion trace needs to
+//! write a record whole or not at all (`space`). The class is small, and
+//! the console needs no more than this.
+use core::fmt;
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+const PACKET_SIZE: u16 = 64;
+const TX_SIZE: usize = 512;
+
+pub struct CdcAcmClass<'a, B: UsbBus> {
+    comm_if: InterfaceNumber,
+    comm_ep: EndpointIn<'a, B>,
+    data_if: InterfaceNumber,
+    read_ep: EndpointOut<'a, B>,
+    write_ep: EndpointIn<'a, B>,
+
+    // line coding, as set by the host (meaningless for USB, kept for
+    // GET_LINE_CODING)
+    line_coding: [u8; 7],
+    dtr: bool,
+
+    // queued output, a ring buffer
+    tx: [u8; TX_SIZE],
+    tx_head: usize,
+    tx_len: usize,
+    // a packet is waiting for the host
+    in_flight: bool,
+    // the last packet was full, end the transfer with a zero length packet
+    zlp: bool,
+}
+
+impl<'a, B: UsbBus> CdcAcmClass<'a, B> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>) -> CdcAcmClass<'a, B> {
+        CdcAcmClass {
+            comm_if: alloc.interface(),
+            comm_ep: alloc.interrupt(8, 255),
+            data_if: alloc.interface(),
+            read_ep: alloc.bulk(PACKET_SIZE),
+            write_ep: alloc.bulk(PACKET_SIZE),
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: [0x00, 0xc2, 0x01, 0x00, 0x00, 0x00, 0x08],
+            dtr: false,
+            tx: [0; TX_SIZE],
+            tx_head: 0,
+            tx_len: 0,
+            in_flight: false,
+            zlp: false,
+        }
+    }
+
+    /// True while a terminal has the port open (DTR set)
+    pub fn dtr(&self) -> bool {
+        self.dtr
+    }
+
+    /// Queues `data` for sending, returns the number of bytes queued
+    ///
+    /// Nothing is queued while the port is closed, and data not fitting
+    /// the buffer is dropped.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        if !self.dtr {
+            return 0;
+        }
+        let n = data.len().min(TX_SIZE - self.tx_len);
+        for b in &data[..n] {
+            self.tx[(self.tx_head + self.tx_len) % TX_SIZE] = *b;
+            self.tx_len += 1;
+        }
+        self.flush();
+        n
+    }
+
+    /// The number of bytes `write` can queue without dropping any, 0 while
+    /// the port is closed
+    pub fn space(&self) -> usize {
+        if self.dtr {
+            TX_SIZE - self.tx_len
+        } else {
+            0
+        }
+    }
+
+    /// Reads received data, `Err(UsbError::WouldBlock)` if none
+    pub fn read(&mut self, data: &mut [u8]) -> Result<usize> {
+        self.read_ep.read(data)
+    }
+
+    // sends the next packet, if the endpoint is free
+    fn flush(&mut self) {
+        if self.in_flight || (self.tx_len == 0 && !self.zlp) {
+            return;
+        }
+
+        let mut packet = [0; PACKET_SIZE as usize];
+        let n = self.tx_len.min(PACKET_SIZE as usize);
+        for (i, p) in packet[..n].iter_mut().enumerate() {
+            *p = self.tx[(self.tx_head + i) % TX_SIZE];
+        }
+
+        if self.write_ep.write(&packet[..n]).is_ok() {
+            self.tx_head = (self.tx_head + n) % TX_SIZE;
+            self.tx_len -= n;
+            self.in_flight = true;
+            self.zlp = n == PACKET_SIZE as usize;
+        }
+    }
+
+    fn is_class_request(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.comm_if) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for CdcAcmClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.iad(
+            self.comm_if,
+            2,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+        )?;
+
+        writer.interface(
+            self.comm_if,
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+        )?;
+
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_HEADER, // bDescriptorSubtype
+                0x10,            // bcdCDC (1.10)
+                0x01,            // bcdCDC
+            ],
+        )?;
+
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_ACM, // bDescriptorSubtype
+                0x02,         // bmCapabilities, line coding and serial state
+            ],
+        )?;
+
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_UNION,      // bDescriptorSubtype
+                self.comm_if.into(), // bControlInterface
+                self.data_if.into(), // bSubordinateInterface
+            ],
+        )?;
+
+        writer.write(
+            CS_INTERFACE,
+            &[
+                CDC_TYPE_CALL_MANAGEMENT, // bDescriptorSubtype
+                0x00,                     // bmCapabilities
+                self.data_if.into(),      // bDataInterface
+            ],
+        )?;
+
+        writer.endpoint(&self.comm_ep)?;
+
+        writer.interface(self.data_if, USB_CLASS_CDC_DATA, 0x00, 0x00)?;
+
+        writer.endpoint(&self.write_ep)?;
+        writer.endpoint(&self.read_ep)?;
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.dtr = false;
+        self.tx_len = 0;
+        self.in_flight = false;
+        self.zlp = false;
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if self.write_ep.address() == addr {
+            self.in_flight = false;
+            self.flush();
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+
+        if !self.is_class_request(req) {
+            return;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING if req.length == 7 => {
+                xfer.accept_with(&self.line_coding).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+
+        if !self.is_class_request(req) {
+            return;
+        }
+
+        match req.request {
+            REQ_SEND_ENCAPSULATED_COMMAND => {
+                xfer.accept().ok();
+            }
+            REQ_SET_LINE_CODING if xfer.data().len() == 7 => {
+                self.line_coding.copy_from_slice(xfer.data());
+                xfer.accept().ok();
+            }
+            REQ_SET_CONTROL_LINE_STATE => {
+                self.dtr = req.value & 0x0001 != 0;
+                if !self.dtr {
+                    // drop output nobody will read
+                    self.tx_len = 0;
+                }
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+impl<B: UsbBus> fmt::Write for CdcAcmClass<'_, B> {
+    /// Queues `s`, see `write`
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write(s.as_bytes());
+        Ok(())
+    }
+}
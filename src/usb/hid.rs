@@ -0,0 +1,632 @@
+//! USB HID class
+//!
+//! A single HID interface with an interrupt IN endpoint for the input
+//! reports, and optionally an interrupt OUT endpoint for output reports.
+//! The report descriptor is provided by the application, e.g., built with
+//! `usb::report`, see `usb::mouse` for a mouse (with keyboard and media
+//! keys, reports told apart by report IDs).
+//!
+//! The last input report and the idle rate are kept per report ID, for the
+//! input report IDs of the descriptor (up to `MAX_INPUTS`). Report ID 0
+//! stands for the reports without ID, of a descriptor without report IDs or
+//! of the boot protocol. Before the first report of an ID, GET_REPORT
+//! returns it all zero (but the ID), of its length in the descriptor.
+use usb_device::class_prelude::*;
+use usb_device::Result;
+
+pub const USB_CLASS_HID: u8 = 0x03;
+
+pub const USB_SUBCLASS_NONE: u8 = 0x00;
+pub const USB_SUBCLASS_BOOT: u8 = 0x01;
+
+pub const USB_INTERFACE_NONE: u8 = 0x00;
+pub const USB_INTERFACE_KEYBOARD: u8 = 0x01;
+pub const USB_INTERFACE_MOUSE: u8 = 0x02;
+
+const REQ_GET_REPORT: u8 = 0x01;
+const REQ_GET_IDLE: u8 = 0x02;
+const REQ_GET_PROTOCOL: u8 = 0x03;
+const REQ_SET_REPORT: u8 = 0x09;
+const REQ_SET_IDLE: u8 = 0x0a;
+const REQ_SET_PROTOCOL: u8 = 0x0b;
+
+const REPORT_TYPE_INPUT: u8 = 0x01;
+const REPORT_TYPE_OUTPUT: u8 = 0x02;
+const REPORT_TYPE_FEATURE: u8 = 0x03;
+
+const DESCR_HID: u8 = 0x21;
+const DESCR_REPORT: u8 = 0x22;
+
+// max size of input and output reports
+const REPORT_SIZE: usize = 64;
+
+/// Max number of input reports kept, report ID 0 and the input report IDs
+/// of the descriptor
+pub const MAX_INPUTS: usize = 8;
+
+// HID short item prefixes, without the size bits
+const ITEM_INPUT: u8 = 0x80;
+const ITEM_REPORT_SIZE: u8 = 0x74;
+const ITEM_REPORT_ID: u8 = 0x84;
+const ITEM_REPORT_COUNT: u8 = 0x94;
+const ITEM_LONG: u8 = 0xfe;
+
+/// Report protocol, as selected by SET_PROTOCOL
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+/// Endpoint configuration
+#[derive(Clone, Copy, Debug)]
+pub struct Config {
+    /// Max packet size in bytes, 8, 16, 32 or 64 for full-speed devices
+    pub packet_size: u16,
+    /// Polling interval in ms (frames) for full-speed devices, 1 to 255
+    pub interval: u8,
+    /// Allocate an interrupt OUT endpoint for output reports
+    pub output: bool,
+}
+
+impl Default for Config {
+    /// 8 byte packets polled each ms (1 kHz), no output endpoint
+    fn default() -> Self {
+        Config {
+            packet_size: 8,
+            interval: 1,
+            output: false,
+        }
+    }
+}
+
+// boot protocol input reports (HID 1.11, appendix B)
+const BOOT_KEYBOARD_SIZE: usize = 8;
+const BOOT_MOUSE_SIZE: usize = 3;
+
+// an input report, returned on GET_REPORT and repeated on idle
+#[derive(Clone, Copy)]
+struct Input {
+    id: u8,
+    // length in bytes by the descriptor, report ID included, 0 if unknown
+    size: usize,
+    data: [u8; REPORT_SIZE],
+    // 0 if none yet
+    len: usize,
+    // idle rate set by the host, in units of 4 ms (0 is infinite)
+    idle: u8,
+    // ms since the report was last sent
+    elapsed: u32,
+}
+
+impl Input {
+    const fn new(id: u8, size: usize) -> Self {
+        Input {
+            id,
+            size,
+            data: [0; REPORT_SIZE],
+            len: 0,
+            idle: 0,
+            elapsed: 0,
+        }
+    }
+
+    fn set(&mut self, data: &[u8]) {
+        let len = data.len().min(REPORT_SIZE);
+        self.data[..len].copy_from_slice(&data[..len]);
+        self.len = len;
+    }
+}
+
+// the input report IDs of a report descriptor, after report ID 0, and
+// their lengths
+fn input_ids(report_descr: &[u8]) -> ([Input; MAX_INPUTS], usize) {
+    let mut inputs = [Input::new(0, 0); MAX_INPUTS];
+    let mut bits = [0; MAX_INPUTS];
+    let mut count = 1;
+    let mut id = 0;
+    let mut report_size = 0;
+    let mut report_count = 0;
+    let mut i = 0;
+    while i < report_descr.len() {
+        let prefix = report_descr[i];
+        if prefix == ITEM_LONG {
+            // bDataSize, bLongItemTag and the data
+            i += 3 + *report_descr.get(i + 1).unwrap_or(&0) as usize;
+            continue;
+        }
+        let size = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        // the unsigned item data, little endian
+        let data = report_descr
+            .iter()
+            .skip(i + 1)
+            .take(size)
+            .rev()
+            .fold(0, |data, b| data << 8 | *b as usize);
+        match prefix & 0xfc {
+            ITEM_REPORT_ID => id = data as u8,
+            ITEM_REPORT_SIZE => report_size = data,
+            ITEM_REPORT_COUNT => report_count = data,
+            ITEM_INPUT => {
+                let index = match inputs[..count].iter().position(|r| r.id == id) {
+                    Some(index) => Some(index),
+                    None if count < MAX_INPUTS => {
+                        inputs[count] = Input::new(id, 0);
+                        count += 1;
+                        Some(count - 1)
+                    }
+                    None => None,
+                };
+                if let Some(index) = index {
+                    bits[index] += report_size * report_count;
+                }
+            }
+            _ => {}
+        }
+        i += 1 + size;
+    }
+    for (input, bits) in inputs[..count].iter_mut().zip(bits.iter()) {
+        if *bits > 0 {
+            input.size = bits.div_ceil(8) + (input.id != 0) as usize;
+        }
+    }
+    (inputs, count)
+}
+
+pub struct HIDClass<'a, B: UsbBus> {
+    report_descr: &'static [u8],
+    interface_subclass: u8,
+    interface_protocol: u8,
+    report_if: InterfaceNumber,
+    report_ep: EndpointIn<'a, B>,
+    output_ep: Option<EndpointOut<'a, B>>,
+
+    // current input reports, by report ID
+    inputs: [Input; MAX_INPUTS],
+    input_count: usize,
+    // the descriptor has report IDs
+    report_ids: bool,
+    // last output report received (OUT endpoint or SET_REPORT)
+    output: [u8; REPORT_SIZE],
+    output_len: usize,
+    // feature report, set by the application (GET_FEATURE) or the host
+    // (SET_FEATURE)
+    feature: [u8; REPORT_SIZE],
+    feature_len: usize,
+    feature_new: bool,
+
+    // a report is waiting for the host
+    in_flight: bool,
+    // the host collected the last report
+    collected: bool,
+
+    protocol: Protocol,
+}
+
+impl<'a, B: UsbBus> HIDClass<'a, B> {
+    /// Creates a new HIDClass with the default (`Config::default`) endpoints
+    ///
+    /// `interface_subclass` is `USB_SUBCLASS_BOOT` for a device usable by
+    /// BIOS/UEFI setups (supporting the boot protocol), `USB_SUBCLASS_NONE`
+    /// otherwise. `interface_protocol` is one of the `USB_INTERFACE_*`
+    /// constants.
+    pub fn new(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descr: &'static [u8],
+        interface_subclass: u8,
+        interface_protocol: u8,
+    ) -> HIDClass<'a, B> {
+        Self::with_config(
+            alloc,
+            report_descr,
+            interface_subclass,
+            interface_protocol,
+            Config::default(),
+        )
+    }
+
+    /// Creates a new HIDClass with both input and output endpoints
+    pub fn new_with_output(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descr: &'static [u8],
+        interface_subclass: u8,
+        interface_protocol: u8,
+    ) -> HIDClass<'a, B> {
+        Self::with_config(
+            alloc,
+            report_descr,
+            interface_subclass,
+            interface_protocol,
+            Config {
+                output: true,
+                ..Config::default()
+            },
+        )
+    }
+
+    /// Creates a new HIDClass with the endpoints given by `config`
+    pub fn with_config(
+        alloc: &'a UsbBusAllocator<B>,
+        report_descr: &'static [u8],
+        interface_subclass: u8,
+        interface_protocol: u8,
+        config: Config,
+    ) -> HIDClass<'a, B> {
+        let (mut inputs, input_count) = input_ids(report_descr);
+        // report ID 0 of a descriptor with report IDs is the boot report
+        if inputs[0].size == 0 && interface_subclass == USB_SUBCLASS_BOOT {
+            inputs[0].size = match interface_protocol {
+                USB_INTERFACE_KEYBOARD => BOOT_KEYBOARD_SIZE,
+                USB_INTERFACE_MOUSE => BOOT_MOUSE_SIZE,
+                _ => 0,
+            };
+        }
+        HIDClass {
+            report_descr,
+            interface_subclass,
+            interface_protocol,
+            report_if: alloc.interface(),
+            report_ep: alloc.interrupt(config.packet_size, config.interval),
+            output_ep: if config.output {
+                Some(alloc.interrupt(config.packet_size, config.interval))
+            } else {
+                None
+            },
+            inputs,
+            input_count,
+            report_ids: input_count > 1,
+            output: [0; REPORT_SIZE],
+            output_len: 0,
+            feature: [0; REPORT_SIZE],
+            feature_len: 0,
+            feature_new: false,
+            in_flight: false,
+            collected: false,
+            protocol: Protocol::Report,
+        }
+    }
+
+    /// Writes an input report, the report is kept (by report ID) for
+    /// GET_REPORT and idle repeats
+    pub fn write(&mut self, data: &[u8]) -> Result<usize> {
+        let n = self.report_ep.write(data)?;
+        self.set_report(data);
+        self.in_flight = true;
+        if let Some(input) = self.input_mut(self.id_of(data)) {
+            input.elapsed = 0;
+        }
+        Ok(n)
+    }
+
+    /// True while a report is waiting to be collected by the host
+    pub fn is_busy(&self) -> bool {
+        self.in_flight
+    }
+
+    /// True (once) when the host has collected the last report
+    ///
+    /// The host collects at most one report per polling interval, at the
+    /// start of a frame (SOF). Writing the next report as soon as the last
+    /// one is collected, each frame carries the data gathered since the
+    /// previous frame.
+    pub fn collected(&mut self) -> bool {
+        core::mem::replace(&mut self.collected, false)
+    }
+
+    /// Updates the current input report of its report ID without sending it
+    ///
+    /// The report returned on GET_REPORT, and repeated when the idle rate
+    /// expires, is the current state of the device. For relative data
+    /// (e.g., mouse motion) this is the report without the motion, set it
+    /// after each `write`. Reports of IDs not in the descriptor are not
+    /// kept.
+    pub fn set_report(&mut self, data: &[u8]) {
+        if let Some(input) = self.input_mut(self.id_of(data)) {
+            input.set(data);
+        }
+    }
+
+    /// Advances the idle timers by `ms` milliseconds
+    ///
+    /// Following the HID specification, the current input report of a
+    /// report ID is repeated when none was sent during the idle period set
+    /// by the host for it (SET_IDLE). With an idle rate of 0 (infinite),
+    /// reports are only sent by `write`. A single report is repeated per
+    /// call, the others on the next. Returns `Ok(true)` if one was.
+    pub fn tick(&mut self, ms: u32) -> Result<bool> {
+        for input in self.inputs[..self.input_count].iter_mut() {
+            input.elapsed = input.elapsed.saturating_add(ms);
+        }
+        let expired = self.inputs[..self.input_count]
+            .iter_mut()
+            .find(|r| r.idle != 0 && r.len != 0 && r.elapsed >= r.idle as u32 * 4);
+        let input = match expired {
+            Some(input) => input,
+            None => return Ok(false),
+        };
+
+        self.report_ep.write(&input.data[..input.len])?;
+        input.elapsed = 0;
+        self.in_flight = true;
+        Ok(true)
+    }
+
+    /// Reads the last output report received from the host, if any
+    ///
+    /// Returns the report length, 0 if no new report was received since the
+    /// last read.
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        let len = self.output_len.min(data.len());
+        data[..len].copy_from_slice(&self.output[..len]);
+        self.output_len = 0;
+        len
+    }
+
+    /// Sets the feature report returned to the host on GET_FEATURE
+    ///
+    /// Until set, GET_FEATURE requests are rejected. If the report
+    /// descriptor uses report IDs, the report starts with its ID.
+    pub fn set_feature(&mut self, data: &[u8]) {
+        let len = data.len().min(REPORT_SIZE);
+        self.feature[..len].copy_from_slice(&data[..len]);
+        self.feature_len = len;
+    }
+
+    /// Reads a feature report written by the host (SET_FEATURE), if any
+    ///
+    /// Returns the report length, 0 if no new report was received since the
+    /// last read. The report is kept for GET_FEATURE until replaced by
+    /// `set_feature`.
+    pub fn read_feature(&mut self, data: &mut [u8]) -> usize {
+        if !self.feature_new {
+            return 0;
+        }
+        self.feature_new = false;
+        let len = self.feature_len.min(data.len());
+        data[..len].copy_from_slice(&self.feature[..len]);
+        len
+    }
+
+    /// The idle rate set by the host for report ID `id`, in units of 4 ms
+    /// (0 is infinite)
+    ///
+    /// Report ID 0 is the rate last set for all reports. `None` if the
+    /// descriptor has no input report `id`.
+    pub fn idle(&self, id: u8) -> Option<u8> {
+        self.input(id).map(|r| r.idle)
+    }
+
+    /// The protocol selected by the host
+    ///
+    /// Always `Protocol::Report` unless the interface is a boot interface.
+    /// The host may switch at any time, so input reports should be written
+    /// in the format of the current protocol.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    // the HID descriptor, returned on GET_DESCRIPTOR and embedded (without
+    // length and type) in the configuration descriptor
+    fn hid_descr(&self) -> [u8; 9] {
+        let descr_len: u16 = self.report_descr.len() as u16;
+        [
+            0x09,                   // bLength
+            DESCR_HID,              // bDescriptorType
+            0x01,                   // bcdHID
+            0x01,                   // bcdHID
+            0x00,                   // bCountryCode
+            0x01,                   // bNumDescriptors
+            DESCR_REPORT,           // bDescriptorType
+            descr_len as u8,        // wDescriptorLength
+            (descr_len >> 8) as u8, // wDescriptorLength
+        ]
+    }
+
+    // the report ID of an input report, 0 for reports without
+    fn id_of(&self, data: &[u8]) -> u8 {
+        if self.report_ids && self.protocol == Protocol::Report {
+            data.first().copied().unwrap_or(0)
+        } else {
+            0
+        }
+    }
+
+    fn input(&self, id: u8) -> Option<&Input> {
+        self.inputs[..self.input_count].iter().find(|r| r.id == id)
+    }
+
+    fn input_mut(&mut self, id: u8) -> Option<&mut Input> {
+        self.inputs[..self.input_count]
+            .iter_mut()
+            .find(|r| r.id == id)
+    }
+
+    fn is_class_request(&self, req: &control::Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.report_if) as u16
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for HIDClass<'_, B> {
+    fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        writer.interface(
+            self.report_if,
+            USB_CLASS_HID,
+            self.interface_subclass,
+            self.interface_protocol,
+        )?;
+
+        writer.write(DESCR_HID, &self.hid_descr()[2..])?;
+
+        writer.endpoint(&self.report_ep)?;
+        if let Some(ep) = &self.output_ep {
+            writer.endpoint(ep)?;
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        for input in self.inputs[..self.input_count].iter_mut() {
+            *input = Input::new(input.id, input.size);
+        }
+        self.output_len = 0;
+        self.in_flight = false;
+        self.collected = false;
+        self.protocol = Protocol::Report;
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if self.report_ep.address() == addr {
+            self.in_flight = false;
+            self.collected = true;
+        }
+    }
+
+    fn endpoint_out(&mut self, addr: EndpointAddress) {
+        if let Some(ep) = &self.output_ep {
+            if ep.address() == addr {
+                if let Ok(n) = ep.read(&mut self.output) {
+                    self.output_len = n;
+                }
+            }
+        }
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = xfer.request();
+
+        if req.request_type == control::RequestType::Standard {
+            match (req.recipient, req.request) {
+                (control::Recipient::Interface, control::Request::GET_DESCRIPTOR) => {
+                    let (dtype, _index) = req.descriptor_type_index();
+                    if dtype == DESCR_HID {
+                        xfer.accept_with(&self.hid_descr()).ok();
+                        return;
+                    } else if dtype == DESCR_REPORT {
+                        // Report descriptor
+                        xfer.accept_with_static(self.report_descr).ok();
+                        return;
+                    }
+                }
+                _ => {
+                    return;
+                }
+            };
+        }
+
+        if !self.is_class_request(req) {
+            return;
+        }
+
+        match req.request {
+            // upper byte report type, lower byte report ID
+            REQ_GET_REPORT if (req.value >> 8) as u8 == REPORT_TYPE_INPUT => {
+                match self.input(req.value as u8) {
+                    Some(input) if input.len > 0 => {
+                        xfer.accept_with(&input.data[..input.len]).ok();
+                    }
+                    Some(input) if input.size > 0 => {
+                        // all zero (no motion) if none yet, but the report ID
+                        let mut report = [0; REPORT_SIZE];
+                        report[0] = input.id;
+                        xfer.accept_with(&report[..input.size.min(REPORT_SIZE)])
+                            .ok();
+                    }
+                    _ => {
+                        xfer.reject().ok();
+                    }
+                }
+            }
+            REQ_GET_REPORT
+                if (req.value >> 8) as u8 == REPORT_TYPE_FEATURE
+                    && self.feature_len > 0
+                    && (req.value as u8 == 0 || self.feature[0] == req.value as u8) =>
+            {
+                xfer.accept_with(&self.feature[..self.feature_len]).ok();
+            }
+            // lower byte report ID
+            REQ_GET_IDLE => match self.idle(req.value as u8) {
+                Some(idle) => {
+                    xfer.accept_with(&[idle]).ok();
+                }
+                None => {
+                    xfer.reject().ok();
+                }
+            },
+            REQ_GET_PROTOCOL if self.interface_subclass == USB_SUBCLASS_BOOT => {
+                xfer.accept_with(&[self.protocol as u8]).ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = xfer.request();
+
+        if !self.is_class_request(req) {
+            return;
+        }
+
+        match req.request {
+            REQ_SET_IDLE => {
+                // upper byte duration, lower byte report ID (0 for all
+                // reports)
+                let (idle, id) = ((req.value >> 8) as u8, req.value as u8);
+                let inputs = &mut self.inputs[..self.input_count];
+                if id == 0 {
+                    inputs.iter_mut().for_each(|r| r.idle = idle);
+                } else if let Some(input) = inputs.iter_mut().find(|r| r.id == id) {
+                    input.idle = idle;
+                } else {
+                    xfer.reject().ok();
+                    return;
+                }
+                // if the new period has already passed, the report is repeated
+                // on the next tick
+                xfer.accept().ok();
+            }
+            // only boot interfaces are required to support SET_PROTOCOL
+            REQ_SET_PROTOCOL if self.interface_subclass == USB_SUBCLASS_BOOT => {
+                match req.value {
+                    0 => self.protocol = Protocol::Boot,
+                    1 => self.protocol = Protocol::Report,
+                    _ => {
+                        xfer.reject().ok();
+                        return;
+                    }
+                }
+                // the last reports are in the format of the previous protocol
+                for input in self.inputs[..self.input_count].iter_mut() {
+                    input.len = 0;
+                }
+                xfer.accept().ok();
+            }
+            // upper byte report type, lower byte report ID
+            REQ_SET_REPORT if (req.value >> 8) as u8 == REPORT_TYPE_OUTPUT => {
+                let data = xfer.data();
+                let len = data.len().min(REPORT_SIZE);
+                self.output[..len].copy_from_slice(&data[..len]);
+                self.output_len = len;
+                xfer.accept().ok();
+            }
+            REQ_SET_REPORT if (req.value >> 8) as u8 == REPORT_TYPE_FEATURE => {
+                let data = xfer.data();
+                let len = data.len().min(REPORT_SIZE);
+                self.feature[..len].copy_from_slice(&data[..len]);
+                self.feature_len = len;
+                self.feature_new = true;
+                xfer.accept().ok();
+            }
+            _ => {
+                xfer.reject().ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,37 @@
+//! USB device identity
+//!
+//! The vendor and product IDs and the manufacturer and product strings are
+//! set at build time by `build.rs`, from the environment:
+//!
+//! | variable           | default        |
+//! | ------------------ | -------------- |
+//! | `USB_VID`          | `c410` (hex)   |
+//! | `USB_PID`          | `0000` (hex)   |
+//! | `USB_MANUFACTURER` | `Fake company` |
+//! | `USB_PRODUCT`      | `mouse`        |
+//!
+//! ```shell
+//! > USB_VID=1209 USB_PID=0001 cargo build --example rtt_rtic_usb_pmw3389 --release
+//! ```
+//!
+//! The serial number is the unique device ID of the chip (see `crate::uid`), so
+//! units plugged into the same host can be told apart.
+
+include!(concat!(env!("OUT_DIR"), "/usb_id.rs"));
+
+/// Length of the serial number, 96 bits in hex
+pub const SERIAL_SIZE: usize = 24;
+
+/// The serial number of a 96-bit unique device ID, in hex
+///
+/// The words are written most significant first, as printed by the ST
+/// tools.
+pub fn serial_number<'a>(uid: &[u32; 3], buf: &'a mut [u8; SERIAL_SIZE]) -> &'a str {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for (i, b) in buf.iter_mut().enumerate() {
+        let word = uid[2 - i / 8];
+        *b = HEX[(word >> (28 - 4 * (i % 8))) as usize & 0xf];
+    }
+    // only ASCII hex digits
+    core::str::from_utf8(buf).unwrap()
+}
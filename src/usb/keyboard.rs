@@ -0,0 +1,220 @@
+//! HID keyboard and consumer control (media key) reports
+//!
+//! The keyboard and consumer control collections share the interface of the
+//! mouse, see `usb::mouse`, told apart by their report IDs. Side buttons bound
+//! to keys (`settings::Binding`) are turned into reports by `press` and
+//! `release`, queued in a `Queue` until the host collects them.
+//!
+//! Usages are from the HID Usage Tables, the Keyboard/Keypad page (0x07) and
+//! the Consumer page (0x0c).
+use super::mouse::{CONSUMER_ID, DESCRIPTOR, KEYBOARD_ID};
+use super::report::{page, Field, ReportType};
+use crate::settings::Binding;
+
+/// Size of the keyboard report in bytes, report ID included
+pub const REPORT_SIZE: usize = DESCRIPTOR.report_len(ReportType::Input, KEYBOARD_ID);
+
+/// Size of the consumer control report in bytes, report ID included
+pub const CONSUMER_REPORT_SIZE: usize = DESCRIPTOR.report_len(ReportType::Input, CONSUMER_ID);
+
+// the fields of the keyboard and consumer control reports, by their first
+// usage
+const MODIFIERS: Field = DESCRIPTOR.field(ReportType::Input, KEYBOARD_ID, page::KEYBOARD, 0xe0);
+const KEYS: Field = DESCRIPTOR.field(ReportType::Input, KEYBOARD_ID, page::KEYBOARD, 0x00);
+const USAGE: Field = DESCRIPTOR.field(ReportType::Input, CONSUMER_ID, page::CONSUMER, 0x000);
+
+/// Modifier bits, the first byte of the keyboard report
+pub mod modifier {
+    pub const LEFT_CTRL: u8 = 0x01;
+    pub const LEFT_SHIFT: u8 = 0x02;
+    pub const LEFT_ALT: u8 = 0x04;
+    pub const LEFT_GUI: u8 = 0x08;
+}
+
+/// Keyboard usages (a few)
+pub mod key {
+    pub const A: u8 = 0x04;
+    pub const C: u8 = 0x06;
+    pub const V: u8 = 0x19;
+    pub const X: u8 = 0x1b;
+    pub const Y: u8 = 0x1c;
+    pub const Z: u8 = 0x1d;
+    pub const ENTER: u8 = 0x28;
+    pub const ESCAPE: u8 = 0x29;
+    pub const TAB: u8 = 0x2b;
+}
+
+/// Consumer control usages (a few)
+pub mod consumer {
+    pub const NEXT_TRACK: u16 = 0xb5;
+    pub const PREVIOUS_TRACK: u16 = 0xb6;
+    pub const STOP: u16 = 0xb7;
+    pub const PLAY_PAUSE: u16 = 0xcd;
+    pub const MUTE: u16 = 0xe2;
+    pub const VOLUME_UP: u16 = 0xe9;
+    pub const VOLUME_DOWN: u16 = 0xea;
+}
+
+/// A keyboard report, modifiers and up to six keys
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct KeyboardReport {
+    pub modifiers: u8,
+    pub keys: [u8; 6],
+}
+
+impl KeyboardReport {
+    /// No keys pressed
+    pub const fn new() -> Self {
+        KeyboardReport {
+            modifiers: 0,
+            keys: [0; 6],
+        }
+    }
+
+    /// A single key combination, e.g., `(modifier::LEFT_CTRL, key::C)`
+    pub const fn combination(modifiers: u8, key: u8) -> Self {
+        KeyboardReport {
+            modifiers,
+            keys: [key, 0, 0, 0, 0, 0],
+        }
+    }
+
+    /// The report as sent over USB, prefixed by the report ID
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        let mut data = [0; REPORT_SIZE];
+        data[0] = KEYBOARD_ID;
+        for i in 0..MODIFIERS.count as usize {
+            MODIFIERS.write_element(&mut data, i, (self.modifiers >> i) as i32 & 1);
+        }
+        for (i, key) in self.keys.iter().enumerate() {
+            KEYS.write_element(&mut data, i, *key as i32);
+        }
+        data
+    }
+}
+
+/// A consumer control report, the pressed usage (0 for none)
+pub fn consumer_report(usage: u16) -> [u8; CONSUMER_REPORT_SIZE] {
+    let mut data = [0; CONSUMER_REPORT_SIZE];
+    data[0] = CONSUMER_ID;
+    USAGE.write(&mut data, usage as i32);
+    data
+}
+
+const QUEUE_SIZE: usize = 16;
+
+/// Reports waiting to be sent, first in first out
+///
+/// Key presses and releases must reach the host in order, and each as a
+/// report of its own, while the interrupt endpoint holds a single report.
+pub struct Queue {
+    reports: [[u8; REPORT_SIZE]; QUEUE_SIZE],
+    lens: [u8; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl Queue {
+    pub const fn new() -> Self {
+        Queue {
+            reports: [[0; REPORT_SIZE]; QUEUE_SIZE],
+            lens: [0; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Queues a report, false (and dropped) if the queue is full
+    pub fn push(&mut self, report: &[u8]) -> bool {
+        if self.len == QUEUE_SIZE || report.len() > REPORT_SIZE {
+            return false;
+        }
+        let i = (self.head + self.len) % QUEUE_SIZE;
+        self.reports[i][..report.len()].copy_from_slice(report);
+        self.lens[i] = report.len() as u8;
+        self.len += 1;
+        true
+    }
+
+    /// The oldest report, left in the queue
+    pub fn peek(&self) -> Option<&[u8]> {
+        if self.len == 0 {
+            None
+        } else {
+            Some(&self.reports[self.head][..self.lens[self.head] as usize])
+        }
+    }
+
+    /// Removes the oldest report, once sent
+    pub fn pop(&mut self) {
+        if self.len > 0 {
+            self.head = (self.head + 1) % QUEUE_SIZE;
+            self.len -= 1;
+        }
+    }
+
+    /// Drops all reports
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of free entries
+    pub fn free(&self) -> usize {
+        QUEUE_SIZE - self.len
+    }
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue::new()
+    }
+}
+
+/// Queues the reports of a bound button being pressed
+///
+/// Returns false for `Binding::Button`, nothing to send, the button is
+/// reported by the mouse. A sequence is dropped as a whole if it does not
+/// fit the queue.
+pub fn press(binding: &Binding, queue: &mut Queue) -> bool {
+    match binding {
+        Binding::Button => return false,
+        Binding::Keys(keys) => {
+            let n = keys.iter().take_while(|(_, key)| *key != 0).count();
+            if n == 1 {
+                // held until released
+                queue.push(&KeyboardReport::combination(keys[0].0, keys[0].1).to_bytes());
+            } else if queue.free() >= 2 * n {
+                // typed, each combination pressed and released
+                for (modifiers, key) in &keys[..n] {
+                    queue.push(&KeyboardReport::combination(*modifiers, *key).to_bytes());
+                    queue.push(&KeyboardReport::new().to_bytes());
+                }
+            }
+        }
+        Binding::Consumer(usage) => {
+            queue.push(&consumer_report(*usage));
+        }
+    }
+    true
+}
+
+/// Queues the reports of a bound button being released, see `press`
+pub fn release(binding: &Binding, queue: &mut Queue) -> bool {
+    match binding {
+        Binding::Button => return false,
+        Binding::Keys(keys) => {
+            // a sequence is already released
+            if keys[1].1 == 0 {
+                queue.push(&KeyboardReport::new().to_bytes());
+            }
+        }
+        Binding::Consumer(_) => {
+            queue.push(&consumer_report(0));
+        }
+    }
+    true
+}
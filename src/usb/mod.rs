@@ -0,0 +1,10 @@
+//! USB device classes
+//!
+//! The classes only depend on the `usb-device` traits, so they build (and
+//! run against a simulated `UsbBus`) on the host as well.
+pub mod cdc;
+pub mod hid;
+pub mod id;
+pub mod keyboard;
+pub mod mouse;
+pub mod report;
@@ -0,0 +1,289 @@
+//! HID mouse report descriptor and reports
+//!
+//! Five buttons, 16-bit relative X/Y (-32767..=32767), a vertical wheel and
+//! a horizontal wheel (AC Pan), sent as an 8 byte report (report ID
+//! `MOUSE_ID` and 7 bytes of data).
+//!
+//! When the host selects the boot protocol (BIOS/UEFI setups), the standard
+//! 3 byte boot mouse report is sent instead: three buttons and 8-bit X/Y,
+//! without report ID.
+//!
+//! The interface also carries a keyboard (`KEYBOARD_ID`) and a consumer
+//! control (`CONSUMER_ID`) collection, for side buttons bound to keys and
+//! media keys, see `usb::keyboard`. Both are only sent in the report
+//! protocol.
+//!
+//! The `settings::Settings` are a vendor-defined feature report
+//! (`SETTINGS_ID`) of the same interface, read with GET_REPORT (GET_FEATURE)
+//! and written with SET_REPORT (SET_FEATURE). The report is in a vendor
+//! application collection of its own: Windows opens the mouse and keyboard
+//! collections exclusively, applications can only open the others. On Linux
+//! the report is accessed through `hidraw`, see the `mouse-config` host
+//! tool.
+
+use super::hid::Protocol;
+use super::report::{
+    consumer, desktop, flags, page, Collection, Field, ReportDescriptor, ReportType,
+};
+use crate::settings;
+
+/// Report ID of the mouse report
+pub const MOUSE_ID: u8 = 1;
+/// Report ID of the keyboard report
+pub const KEYBOARD_ID: u8 = 2;
+/// Report ID of the consumer control report
+pub const CONSUMER_ID: u8 = 3;
+/// Report ID of the settings feature report
+pub const SETTINGS_ID: u8 = 4;
+
+// https://docs.microsoft.com/en-us/windows-hardware/design/component-guidelines/mouse-collection-report-descriptor
+/// The report descriptor, the reports and their layout follow from it
+pub const DESCRIPTOR: ReportDescriptor = ReportDescriptor::new()
+    .usage_page(page::GENERIC_DESKTOP)
+    .usage(desktop::MOUSE)
+    .collection(Collection::Application)
+    .report_id(MOUSE_ID)
+    .usage(desktop::POINTER)
+    .collection(Collection::Physical)
+    // five buttons, padded to a byte
+    .usage_page(page::BUTTON)
+    .usage_minimum(1)
+    .usage_maximum(5)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_count(5)
+    .report_size(1)
+    .input(flags::VARIABLE)
+    .report_count(1)
+    .report_size(3)
+    .input(flags::CONSTANT | flags::VARIABLE)
+    // 16-bit motion
+    .usage_page(page::GENERIC_DESKTOP)
+    .usage(desktop::X)
+    .usage(desktop::Y)
+    .logical_minimum(-32767)
+    .logical_maximum(32767)
+    .report_size(16)
+    .report_count(2)
+    .input(flags::VARIABLE | flags::RELATIVE)
+    // wheels
+    .usage(desktop::WHEEL)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(1)
+    .input(flags::VARIABLE | flags::RELATIVE)
+    .usage_page(page::CONSUMER)
+    .usage(consumer::AC_PAN)
+    .logical_minimum(-127)
+    .logical_maximum(127)
+    .report_size(8)
+    .report_count(1)
+    .input(flags::VARIABLE | flags::RELATIVE)
+    .end_collection()
+    .end_collection()
+    // keyboard, 8 modifiers and 6 keys
+    .usage_page(page::GENERIC_DESKTOP)
+    .usage(desktop::KEYBOARD)
+    .collection(Collection::Application)
+    .report_id(KEYBOARD_ID)
+    .usage_page(page::KEYBOARD)
+    .usage_minimum(0xe0)
+    .usage_maximum(0xe7)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(8)
+    .input(flags::VARIABLE)
+    .usage_minimum(0x00)
+    .usage_maximum(0xff)
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(6)
+    .input(0)
+    .end_collection()
+    // consumer control, a single media key
+    .usage_page(page::CONSUMER)
+    .usage(consumer::CONSUMER_CONTROL)
+    .collection(Collection::Application)
+    .report_id(CONSUMER_ID)
+    .usage_minimum(0x000)
+    .usage_maximum(0x3ff)
+    .logical_minimum(0)
+    .logical_maximum(0x3ff)
+    .report_size(16)
+    .report_count(1)
+    .input(0)
+    .end_collection()
+    // the settings
+    .usage_page(page::VENDOR)
+    .usage(0x01)
+    .collection(Collection::Application)
+    .report_id(SETTINGS_ID)
+    .usage(0x01)
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(settings::SIZE as u8)
+    .feature(flags::VARIABLE)
+    .end_collection();
+
+pub const REPORT_DESCR: &[u8] = &DESCRIPTOR.to_bytes::<{ DESCRIPTOR.len() }>();
+
+/// Size of the report in bytes, report ID included
+pub const REPORT_SIZE: usize = DESCRIPTOR.report_len(ReportType::Input, MOUSE_ID);
+
+// the fields of the mouse report
+const BUTTONS: Field = DESCRIPTOR.field(ReportType::Input, MOUSE_ID, page::BUTTON, 1);
+const X: Field = DESCRIPTOR.field(
+    ReportType::Input,
+    MOUSE_ID,
+    page::GENERIC_DESKTOP,
+    desktop::X,
+);
+const Y: Field = DESCRIPTOR.field(
+    ReportType::Input,
+    MOUSE_ID,
+    page::GENERIC_DESKTOP,
+    desktop::Y,
+);
+const WHEEL: Field = DESCRIPTOR.field(
+    ReportType::Input,
+    MOUSE_ID,
+    page::GENERIC_DESKTOP,
+    desktop::WHEEL,
+);
+const PAN: Field = DESCRIPTOR.field(
+    ReportType::Input,
+    MOUSE_ID,
+    page::CONSUMER,
+    consumer::AC_PAN,
+);
+
+/// Size of the boot protocol report in bytes
+pub const BOOT_REPORT_SIZE: usize = 3;
+
+/// Mouse buttons, the bits of the button field
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Left = 0x01,
+    Right = 0x02,
+    Middle = 0x04,
+    Back = 0x08,
+    Forward = 0x10,
+}
+
+/// A mouse report
+///
+/// ```ignore
+/// let report = MouseReport::new()
+///     .button(Button::Left, true)
+///     .motion(x, y)
+///     .wheel(-1);
+/// hid.write(&report.to_bytes())
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MouseReport {
+    pub buttons: u8,
+    pub x: i16,
+    pub y: i16,
+    pub wheel: i8,
+    pub pan: i8,
+}
+
+impl MouseReport {
+    /// No buttons pressed, no motion
+    pub const fn new() -> Self {
+        MouseReport {
+            buttons: 0,
+            x: 0,
+            y: 0,
+            wheel: 0,
+            pan: 0,
+        }
+    }
+
+    /// Sets all buttons at once, bit 0 is the left button
+    pub fn buttons(mut self, buttons: u8) -> Self {
+        self.buttons = buttons & ((1u16 << BUTTONS.count) - 1) as u8;
+        self
+    }
+
+    /// Sets the state of a single button
+    pub fn button(mut self, button: Button, pressed: bool) -> Self {
+        if pressed {
+            self.buttons |= button as u8;
+        } else {
+            self.buttons &= !(button as u8);
+        }
+        self
+    }
+
+    /// Sets the relative motion
+    ///
+    /// -32768 is outside of the logical range, and is sent as -32767.
+    pub fn motion(mut self, x: i16, y: i16) -> Self {
+        self.x = x.max(-32767);
+        self.y = y.max(-32767);
+        self
+    }
+
+    /// Sets the vertical wheel, positive is away from the user
+    pub fn wheel(mut self, wheel: i8) -> Self {
+        self.wheel = wheel.max(-127);
+        self
+    }
+
+    /// Sets the horizontal wheel (AC Pan), positive is to the right
+    pub fn pan(mut self, pan: i8) -> Self {
+        self.pan = pan.max(-127);
+        self
+    }
+
+    /// The report as sent over USB (little endian), prefixed by the report ID
+    pub fn to_bytes(&self) -> [u8; REPORT_SIZE] {
+        let mut data = [0; REPORT_SIZE];
+        data[0] = MOUSE_ID;
+        for i in 0..BUTTONS.count as usize {
+            BUTTONS.write_element(&mut data, i, (self.buttons >> i) as i32 & 1);
+        }
+        X.write(&mut data, self.x as i32);
+        Y.write(&mut data, self.y as i32);
+        WHEEL.write(&mut data, self.wheel as i32);
+        PAN.write(&mut data, self.pan as i32);
+        data
+    }
+
+    /// The report in the boot protocol format
+    ///
+    /// Buttons 4 and 5, the wheels and motion outside of -127..=127 are
+    /// dropped, clamp the motion before (e.g., `Accumulator::peek_i8`) to
+    /// carry it over to the next report.
+    pub fn to_boot_bytes(&self) -> [u8; BOOT_REPORT_SIZE] {
+        [
+            self.buttons & 0x07,
+            self.x.clamp(-127, 127) as i8 as u8,
+            self.y.clamp(-127, 127) as i8 as u8,
+        ]
+    }
+
+    /// The report in the format of `protocol`, serialized into `buf`
+    pub fn serialize<'b>(&self, protocol: Protocol, buf: &'b mut [u8; REPORT_SIZE]) -> &'b [u8] {
+        match protocol {
+            Protocol::Report => {
+                *buf = self.to_bytes();
+                &buf[..]
+            }
+            Protocol::Boot => {
+                buf[..BOOT_REPORT_SIZE].copy_from_slice(&self.to_boot_bytes());
+                &buf[..BOOT_REPORT_SIZE]
+            }
+        }
+    }
+}
+
+/// A report with motion only
+pub fn report(x: i16, y: i16) -> [u8; REPORT_SIZE] {
+    MouseReport::new().motion(x, y).to_bytes()
+}
@@ -0,0 +1,539 @@
+//! HID report descriptor builder
+//!
+//! Report descriptors are built from typed items by a `const` builder, and
+//! the layout of the reports is derived from the same items: the size of
+//! each report (`ReportDescriptor::report_len`) and the position of each
+//! field (`ReportDescriptor::field`), looked up by usage (the element of a
+//! variable item, or the whole of an array item). Reports serialized
+//! through the fields follow the descriptor, adding a button or a wheel to
+//! the descriptor moves the fields after it, and a usage removed from the
+//! descriptor fails to build (the lookup panics in const evaluation).
+//!
+//! ```ignore
+//! const DESCRIPTOR: ReportDescriptor = ReportDescriptor::new()
+//!     .usage_page(page::GENERIC_DESKTOP)
+//!     .usage(desktop::MOUSE)
+//!     .collection(Collection::Application)
+//!     .usage(desktop::X)
+//!     .logical_minimum(-127)
+//!     .logical_maximum(127)
+//!     .report_size(8)
+//!     .report_count(1)
+//!     .input(flags::VARIABLE | flags::RELATIVE)
+//!     .end_collection();
+//! const REPORT_DESCR: &[u8] = &DESCRIPTOR.to_bytes::<{ DESCRIPTOR.len() }>();
+//! const X: Field = DESCRIPTOR.field(ReportType::Input, 0, page::GENERIC_DESKTOP, desktop::X);
+//! ```
+//!
+//! Only the items needed by `usb::mouse` are supported, no push/pop, units
+//! or physical ranges. Report IDs are limited to 1..=7.
+
+/// Usage pages, from the HID Usage Tables
+pub mod page {
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    pub const KEYBOARD: u16 = 0x07;
+    pub const BUTTON: u16 = 0x09;
+    pub const CONSUMER: u16 = 0x0c;
+    pub const VENDOR: u16 = 0xff00;
+}
+
+/// Usages of the Generic Desktop page
+pub mod desktop {
+    pub const POINTER: u16 = 0x01;
+    pub const MOUSE: u16 = 0x02;
+    pub const KEYBOARD: u16 = 0x06;
+    pub const X: u16 = 0x30;
+    pub const Y: u16 = 0x31;
+    pub const WHEEL: u16 = 0x38;
+}
+
+/// Usages of the Consumer page
+pub mod consumer {
+    pub const CONSUMER_CONTROL: u16 = 0x01;
+    pub const AC_PAN: u16 = 0x0238;
+}
+
+/// Flags of the input, output and feature items, the defaults (0) are
+/// data, array and absolute
+pub mod flags {
+    pub const CONSTANT: u8 = 0x01;
+    pub const VARIABLE: u8 = 0x02;
+    pub const RELATIVE: u8 = 0x04;
+}
+
+/// Collection types
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+}
+
+/// Report types, the main items carrying data
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReportType {
+    Input = 0,
+    Output = 1,
+    Feature = 2,
+}
+
+// item prefixes, tag and type (size in the low two bits)
+const INPUT: u8 = 0x80;
+const OUTPUT: u8 = 0x90;
+const FEATURE: u8 = 0xb0;
+const COLLECTION: u8 = 0xa0;
+const END_COLLECTION: u8 = 0xc0;
+const USAGE_PAGE: u8 = 0x04;
+const LOGICAL_MINIMUM: u8 = 0x14;
+const LOGICAL_MAXIMUM: u8 = 0x24;
+const REPORT_SIZE: u8 = 0x74;
+const REPORT_ID: u8 = 0x84;
+const REPORT_COUNT: u8 = 0x94;
+const USAGE: u8 = 0x08;
+const USAGE_MINIMUM: u8 = 0x18;
+const USAGE_MAXIMUM: u8 = 0x28;
+
+// builder capacity
+const MAX_SIZE: usize = 256;
+const MAX_ITEMS: usize = 16;
+const MAX_USAGES: usize = 4;
+const MAX_REPORT_ID: usize = 7;
+
+/// The position of a field in a report
+///
+/// `offset` in bits from the start of the report (report ID included),
+/// `count` elements of `size` bits each.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Field {
+    pub offset: u16,
+    pub size: u8,
+    pub count: u8,
+}
+
+impl Field {
+    /// Writes `value` to the first element of the field, truncated to its size
+    pub fn write(&self, report: &mut [u8], value: i32) {
+        self.write_element(report, 0, value);
+    }
+
+    /// Writes `value` to element `i` of the field, truncated to its size
+    pub fn write_element(&self, report: &mut [u8], i: usize, value: i32) {
+        assert!(i < self.count as usize);
+        let offset = self.offset as usize + i * self.size as usize;
+        for bit in 0..self.size as usize {
+            let pos = offset + bit;
+            let mask = 1 << (pos % 8);
+            if (value >> bit) & 1 != 0 {
+                report[pos / 8] |= mask;
+            } else {
+                report[pos / 8] &= !mask;
+            }
+        }
+    }
+}
+
+// a main (input, output or feature) item and its usages
+#[derive(Clone, Copy)]
+struct Item {
+    report_type: ReportType,
+    report_id: u8,
+    field: Field,
+    // a variable item, an element per usage, otherwise an array of usages
+    variable: bool,
+    // usages (page in the upper 16 bits), either a list or a range
+    usages: [u32; MAX_USAGES],
+    usages_len: usize,
+    usage_minimum: u32,
+    usage_maximum: u32,
+}
+
+const NO_ITEM: Item = Item {
+    report_type: ReportType::Input,
+    report_id: 0,
+    field: Field {
+        offset: 0,
+        size: 0,
+        count: 0,
+    },
+    variable: false,
+    usages: [0; MAX_USAGES],
+    usages_len: 0,
+    usage_minimum: 0,
+    usage_maximum: 0,
+};
+
+/// A report descriptor under construction, see the module documentation
+#[derive(Clone, Copy)]
+pub struct ReportDescriptor {
+    bytes: [u8; MAX_SIZE],
+    len: usize,
+    // open collections
+    depth: usize,
+    // global items
+    usage_page: u16,
+    report_id: u8,
+    report_size: u8,
+    report_count: u8,
+    // whether report IDs are used, unknown until the first main item
+    uses_ids: Option<bool>,
+    // local items, until the next main item
+    item: Item,
+    // main items
+    items: [Item; MAX_ITEMS],
+    items_len: usize,
+    // bits of each report, by type and report ID
+    bits: [[u16; MAX_REPORT_ID + 1]; 3],
+}
+
+impl ReportDescriptor {
+    pub const fn new() -> Self {
+        ReportDescriptor {
+            bytes: [0; MAX_SIZE],
+            len: 0,
+            depth: 0,
+            usage_page: 0,
+            report_id: 0,
+            report_size: 0,
+            report_count: 0,
+            uses_ids: None,
+            item: NO_ITEM,
+            items: [NO_ITEM; MAX_ITEMS],
+            items_len: 0,
+            bits: [[0; MAX_REPORT_ID + 1]; 3],
+        }
+    }
+
+    /// USAGE_PAGE
+    pub const fn usage_page(mut self, page: u16) -> Self {
+        self.usage_page = page;
+        self.unsigned(USAGE_PAGE, page as u32)
+    }
+
+    /// USAGE, of the current usage page
+    pub const fn usage(mut self, usage: u16) -> Self {
+        if self.item.usages_len == MAX_USAGES {
+            panic!("too many usages");
+        }
+        self.item.usages[self.item.usages_len] = self.extended(usage);
+        self.item.usages_len += 1;
+        self.unsigned(USAGE, usage as u32)
+    }
+
+    /// USAGE_MINIMUM, of the current usage page
+    pub const fn usage_minimum(mut self, usage: u16) -> Self {
+        self.item.usage_minimum = self.extended(usage);
+        self.unsigned(USAGE_MINIMUM, usage as u32)
+    }
+
+    /// USAGE_MAXIMUM, of the current usage page
+    pub const fn usage_maximum(mut self, usage: u16) -> Self {
+        self.item.usage_maximum = self.extended(usage);
+        self.unsigned(USAGE_MAXIMUM, usage as u32)
+    }
+
+    /// LOGICAL_MINIMUM
+    pub const fn logical_minimum(self, min: i32) -> Self {
+        self.signed(LOGICAL_MINIMUM, min)
+    }
+
+    /// LOGICAL_MAXIMUM
+    pub const fn logical_maximum(self, max: i32) -> Self {
+        self.signed(LOGICAL_MAXIMUM, max)
+    }
+
+    /// REPORT_SIZE, in bits
+    pub const fn report_size(mut self, bits: u8) -> Self {
+        self.report_size = bits;
+        self.unsigned(REPORT_SIZE, bits as u32)
+    }
+
+    /// REPORT_COUNT
+    pub const fn report_count(mut self, count: u8) -> Self {
+        self.report_count = count;
+        self.unsigned(REPORT_COUNT, count as u32)
+    }
+
+    /// REPORT_ID, 1..=7
+    pub const fn report_id(mut self, id: u8) -> Self {
+        if id == 0 || id as usize > MAX_REPORT_ID {
+            panic!("report ID out of range");
+        }
+        self.report_id = id;
+        self.unsigned(REPORT_ID, id as u32)
+    }
+
+    /// COLLECTION, ended by `end_collection`
+    pub const fn collection(mut self, collection: Collection) -> Self {
+        self.depth += 1;
+        self.item = NO_ITEM;
+        self.unsigned(COLLECTION, collection as u32)
+    }
+
+    /// END_COLLECTION
+    pub const fn end_collection(mut self) -> Self {
+        if self.depth == 0 {
+            panic!("end collection without collection");
+        }
+        self.depth -= 1;
+        self.item = NO_ITEM;
+        self.push(END_COLLECTION)
+    }
+
+    /// INPUT, `flags::*`
+    pub const fn input(self, flags: u8) -> Self {
+        self.main(INPUT, ReportType::Input, flags)
+    }
+
+    /// OUTPUT, `flags::*`
+    pub const fn output(self, flags: u8) -> Self {
+        self.main(OUTPUT, ReportType::Output, flags)
+    }
+
+    /// FEATURE, `flags::*`
+    pub const fn feature(self, flags: u8) -> Self {
+        self.main(FEATURE, ReportType::Feature, flags)
+    }
+
+    /// Length of the descriptor in bytes
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    /// True if no items were added
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The descriptor, `N` must be `len()`
+    pub const fn to_bytes<const N: usize>(&self) -> [u8; N] {
+        if N != self.len {
+            panic!("descriptor length mismatch");
+        }
+        if self.depth != 0 {
+            panic!("collection not ended");
+        }
+        let mut bytes = [0; N];
+        let mut i = 0;
+        while i < N {
+            bytes[i] = self.bytes[i];
+            i += 1;
+        }
+        bytes
+    }
+
+    /// Size of a report in bytes, report ID included
+    ///
+    /// Panics if the report is not a whole number of bytes (add padding).
+    pub const fn report_len(&self, report_type: ReportType, id: u8) -> usize {
+        let bits = self.bits[report_type as usize][id as usize] as usize;
+        if bits & 7 != 0 {
+            panic!("report not a whole number of bytes");
+        }
+        bits / 8 + if id != 0 { 1 } else { 0 }
+    }
+
+    /// The field of a report holding `usage` (of `page`), and the elements
+    /// after it in the same main item
+    ///
+    /// The elements of an array item hold the usages themselves (e.g., the
+    /// keys pressed), not one each, so any of its usages is the whole array.
+    /// Panics if there is no such field.
+    pub const fn field(&self, report_type: ReportType, id: u8, page: u16, usage: u16) -> Field {
+        let usage = (page as u32) << 16 | usage as u32;
+        let mut i = 0;
+        while i < self.items_len {
+            let item = &self.items[i];
+            if item.report_type as usize == report_type as usize && item.report_id == id {
+                let mut index = None;
+                let mut u = 0;
+                while u < item.usages_len {
+                    if item.usages[u] == usage {
+                        index = Some(u as u8);
+                        break;
+                    }
+                    u += 1;
+                }
+                if item.usage_minimum <= usage && usage <= item.usage_maximum {
+                    index = Some((usage - item.usage_minimum) as u8);
+                }
+                if let Some(index) = index {
+                    let field = item.field;
+                    if !item.variable {
+                        return field;
+                    }
+                    if index < field.count {
+                        return Field {
+                            offset: field.offset + index as u16 * field.size as u16,
+                            size: field.size,
+                            count: field.count - index,
+                        };
+                    }
+                }
+            }
+            i += 1;
+        }
+        panic!("no field with this usage");
+    }
+
+    // an input, output or feature item, closing the locals
+    const fn main(mut self, prefix: u8, report_type: ReportType, flags: u8) -> Self {
+        if self.depth == 0 {
+            panic!("main item outside a collection");
+        }
+        if self.report_size == 0 || self.report_count == 0 {
+            panic!("report size or count undefined");
+        }
+        let uses_ids = self.report_id != 0;
+        match self.uses_ids {
+            Some(ids) if ids != uses_ids => panic!("main item without report ID"),
+            _ => self.uses_ids = Some(uses_ids),
+        }
+        if self.items_len == MAX_ITEMS {
+            panic!("too many main items");
+        }
+
+        let bits = &mut self.bits[report_type as usize][self.report_id as usize];
+        let offset = *bits + if uses_ids { 8 } else { 0 };
+        *bits += self.report_size as u16 * self.report_count as u16;
+
+        let mut item = self.item;
+        item.report_type = report_type;
+        item.report_id = self.report_id;
+        item.variable = flags & flags::VARIABLE != 0;
+        item.field = Field {
+            offset,
+            size: self.report_size,
+            count: self.report_count,
+        };
+        // a range needs both bounds
+        if item.usage_maximum < item.usage_minimum {
+            item.usage_minimum = u32::MAX;
+        }
+        self.items[self.items_len] = item;
+        self.items_len += 1;
+        self.item = NO_ITEM;
+        self.unsigned(prefix, flags as u32)
+    }
+
+    // a usage extended with the current usage page
+    const fn extended(&self, usage: u16) -> u32 {
+        (self.usage_page as u32) << 16 | usage as u32
+    }
+
+    // an item with unsigned data, in as few bytes as possible (at least one)
+    const fn unsigned(self, prefix: u8, data: u32) -> Self {
+        let size = if data <= 0xff {
+            1
+        } else if data <= 0xffff {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data, size)
+    }
+
+    // an item with signed data, in as few bytes as possible (at least one)
+    const fn signed(self, prefix: u8, data: i32) -> Self {
+        let size = if data >= i8::MIN as i32 && data <= i8::MAX as i32 {
+            1
+        } else if data >= i16::MIN as i32 && data <= i16::MAX as i32 {
+            2
+        } else {
+            4
+        };
+        self.item(prefix, data as u32, size)
+    }
+
+    const fn item(mut self, prefix: u8, data: u32, size: usize) -> Self {
+        let code = if size == 4 { 3 } else { size as u8 };
+        self = self.push(prefix | code);
+        let mut i = 0;
+        while i < size {
+            self = self.push((data >> (8 * i)) as u8);
+            i += 1;
+        }
+        self
+    }
+
+    const fn push(mut self, byte: u8) -> Self {
+        if self.len == MAX_SIZE {
+            panic!("report descriptor too long");
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        self
+    }
+}
+
+impl Default for ReportDescriptor {
+    fn default() -> Self {
+        ReportDescriptor::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a keyboard, 8 modifier bits (variable) and 6 keys (array)
+    const KEYBOARD: ReportDescriptor = ReportDescriptor::new()
+        .usage_page(page::GENERIC_DESKTOP)
+        .usage(desktop::KEYBOARD)
+        .collection(Collection::Application)
+        .report_id(2)
+        .usage_page(page::KEYBOARD)
+        .usage_minimum(0xe0)
+        .usage_maximum(0xe7)
+        .logical_minimum(0)
+        .logical_maximum(1)
+        .report_size(1)
+        .report_count(8)
+        .input(flags::VARIABLE)
+        .usage_minimum(0x00)
+        .usage_maximum(0xff)
+        .logical_minimum(0)
+        .logical_maximum(255)
+        .report_size(8)
+        .report_count(6)
+        .input(0)
+        .end_collection();
+
+    #[test]
+    fn variable_item_fields_by_usage() {
+        let field = |usage| KEYBOARD.field(ReportType::Input, 2, page::KEYBOARD, usage);
+        assert_eq!(
+            field(0xe0),
+            Field {
+                offset: 8,
+                size: 1,
+                count: 8
+            }
+        );
+        assert_eq!(
+            field(0xe3),
+            Field {
+                offset: 11,
+                size: 1,
+                count: 5
+            }
+        );
+        assert_eq!(KEYBOARD.report_len(ReportType::Input, 2), 8);
+    }
+
+    #[test]
+    fn array_item_is_the_whole_array() {
+        let keys = Field {
+            offset: 16,
+            size: 8,
+            count: 6,
+        };
+        for usage in [0x00, 0x04, 0x05, 0xff].iter() {
+            assert_eq!(
+                KEYBOARD.field(ReportType::Input, 2, page::KEYBOARD, *usage),
+                keys,
+                "usage {:#x}",
+                usage
+            );
+        }
+    }
+}